@@ -105,6 +105,44 @@ const ANTHROPIC_MODEL_MAPPING_SRC: [(&str, &str); 22] = [
   ("haiku-3", "claude-3-haiku-20240307"),
 ];
 
+const TOGETHER_MODEL_MAPPING_SRC: [(&str, &str); 8] = [
+  // Default models
+  ("llama", "meta-llama/Llama-3.3-70B-Instruct-Turbo"),
+  ("ll", "meta-llama/Llama-3.3-70B-Instruct-Turbo"),
+  ("llama-8b", "meta-llama/Meta-Llama-3.1-8B-Instruct-Turbo"),
+  ("qwen", "Qwen/Qwen2.5-72B-Instruct-Turbo"),
+  ("qw", "Qwen/Qwen2.5-72B-Instruct-Turbo"),
+  ("deepseek", "deepseek-ai/DeepSeek-V3"),
+  ("ds", "deepseek-ai/DeepSeek-V3"),
+  ("mixtral", "mistralai/Mixtral-8x7B-Instruct-v0.1"),
+];
+
+const FIREWORKS_MODEL_MAPPING_SRC: [(&str, &str); 7] = [
+  // Default models
+  ("llama", "accounts/fireworks/models/llama-v3p3-70b-instruct"),
+  ("ll", "accounts/fireworks/models/llama-v3p3-70b-instruct"),
+  (
+    "llama-8b",
+    "accounts/fireworks/models/llama-v3p1-8b-instruct",
+  ),
+  ("qwen", "accounts/fireworks/models/qwen2p5-72b-instruct"),
+  ("qw", "accounts/fireworks/models/qwen2p5-72b-instruct"),
+  ("deepseek", "accounts/fireworks/models/deepseek-v3"),
+  ("ds", "accounts/fireworks/models/deepseek-v3"),
+];
+
+const BEDROCK_MODEL_MAPPING_SRC: [(&str, &str); 8] = [
+  // Default models
+  ("sonnet", "anthropic.claude-3-5-sonnet-20241022-v2:0"),
+  ("so", "anthropic.claude-3-5-sonnet-20241022-v2:0"),
+  ("haiku", "anthropic.claude-3-5-haiku-20241022-v1:0"),
+  ("ha", "anthropic.claude-3-5-haiku-20241022-v1:0"),
+  ("llama", "meta.llama3-1-70b-instruct-v1:0"),
+  ("ll", "meta.llama3-1-70b-instruct-v1:0"),
+  ("mistral", "mistral.mistral-large-2407-v1:0"),
+  ("mi", "mistral.mistral-large-2407-v1:0"),
+];
+
 fn pretty_print_mapping(mapping: &[(&str, &str)]) -> String {
   mapping
     .iter()
@@ -163,6 +201,39 @@ fn main() {
     .replace(
       "{anthropic_models_pretty}",
       &pretty_print_mapping(&ANTHROPIC_MODEL_MAPPING_SRC),
+    )
+    .replace(
+      "// {together_model_hashmap}",
+      &TOGETHER_MODEL_MAPPING_SRC
+        .iter()
+        .map(|(model, constant)| format!("(\"{model}\", \"{constant}\"),\n"))
+        .collect::<String>(),
+    )
+    .replace(
+      "{together_models_pretty}",
+      &pretty_print_mapping(&TOGETHER_MODEL_MAPPING_SRC),
+    )
+    .replace(
+      "// {fireworks_model_hashmap}",
+      &FIREWORKS_MODEL_MAPPING_SRC
+        .iter()
+        .map(|(model, constant)| format!("(\"{model}\", \"{constant}\"),\n"))
+        .collect::<String>(),
+    )
+    .replace(
+      "{fireworks_models_pretty}",
+      &pretty_print_mapping(&FIREWORKS_MODEL_MAPPING_SRC),
+    )
+    .replace(
+      "// {bedrock_model_hashmap}",
+      &BEDROCK_MODEL_MAPPING_SRC
+        .iter()
+        .map(|(model, constant)| format!("(\"{model}\", \"{constant}\"),\n"))
+        .collect::<String>(),
+    )
+    .replace(
+      "{bedrock_models_pretty}",
+      &pretty_print_mapping(&BEDROCK_MODEL_MAPPING_SRC),
     );
 
   fs::write(&dest_path, code).unwrap();
@@ -0,0 +1,243 @@
+/// Words which are capitalized but are no names
+const COMMON_WORDS: &[&str] = &[
+  "A",
+  "An",
+  "And",
+  "But",
+  "For",
+  "He",
+  "Her",
+  "His",
+  "How",
+  "I",
+  "If",
+  "In",
+  "It",
+  "My",
+  "No",
+  "Of",
+  "On",
+  "Or",
+  "She",
+  "So",
+  "The",
+  "Their",
+  "There",
+  "They",
+  "This",
+  "We",
+  "What",
+  "When",
+  "Where",
+  "Which",
+  "Who",
+  "Why",
+  "You",
+  "Your",
+  "Monday",
+  "Tuesday",
+  "Wednesday",
+  "Thursday",
+  "Friday",
+  "Saturday",
+  "Sunday",
+  "January",
+  "February",
+  "March",
+  "April",
+  "May",
+  "June",
+  "July",
+  "August",
+  "September",
+  "October",
+  "November",
+  "December",
+];
+
+const TITLES: &[&str] = &["Mr", "Mrs", "Ms", "Dr", "Prof"];
+
+const ORG_SUFFIXES: &[&str] = &[
+  "Inc",
+  "Ltd",
+  "LLC",
+  "GmbH",
+  "AG",
+  "Corp",
+  "Corporation",
+  "Company",
+  "University",
+  "Bank",
+  "Group",
+];
+
+const LOCATION_PREPOSITIONS: &[&str] = &[
+  "in", "at", "from", "to", "near", "In", "At", "From", "To", "Near",
+];
+
+/// Mapping of placeholders (e.g. `PERSON_1`) to the original entities
+pub type EntityMapping = Vec<(String, String)>;
+
+fn is_capitalized(word: &str) -> bool {
+  let mut chars = word.chars();
+  chars.next().is_some_and(|char| char.is_uppercase())
+    && chars.clone().count() > 0
+    && chars.all(|char| char.is_alphabetic() || char == '-' || char == '\'')
+}
+
+fn is_name_word(word: &str) -> bool {
+  is_capitalized(word)
+    && !COMMON_WORDS.contains(&word)
+    && !TITLES.contains(&word)
+}
+
+fn is_word_char(char: char) -> bool {
+  char.is_alphanumeric() || char == '-' || char == '\''
+}
+
+/// Split the text into words and the separators between them
+fn split_words(text: &str) -> Vec<&str> {
+  let mut parts = vec![];
+  let mut start = 0;
+
+  for (index, char) in text.char_indices() {
+    let is_boundary = index > start
+      && is_word_char(char) != text[start..].starts_with(is_word_char);
+    if is_boundary {
+      parts.push(&text[start..index]);
+      start = index;
+    }
+  }
+  if start < text.len() {
+    parts.push(&text[start..]);
+  }
+
+  parts
+}
+
+/// Get the kind of the entity from its words and the preceding word
+fn get_entity_kind(words: &[&str], prev_word: Option<&str>) -> &'static str {
+  if words.last().is_some_and(|word| ORG_SUFFIXES.contains(word)) {
+    "ORG"
+  } else if prev_word.is_some_and(|word| TITLES.contains(&word)) {
+    "PERSON"
+  } else if prev_word.is_some_and(|word| LOCATION_PREPOSITIONS.contains(&word))
+  {
+    "LOCATION"
+  } else {
+    "PERSON"
+  }
+}
+
+/// Replace person names, organizations, and locations with consistent
+/// placeholders (e.g. `PERSON_1`) using capitalization heuristics.
+/// Returns the anonymized text and the mapping to restore the originals.
+pub fn anonymize(text: &str) -> (String, EntityMapping) {
+  let parts = split_words(text);
+  let mut mapping: EntityMapping = vec![];
+  let mut anonymized = String::with_capacity(text.len());
+  let mut prev_word: Option<&str> = None;
+  let mut is_sentence_start = true;
+  let mut index = 0;
+
+  while index < parts.len() {
+    let part = parts[index];
+
+    if !part.starts_with(is_word_char) {
+      anonymized.push_str(part);
+      if part.contains(['.', '!', '?', '\n']) {
+        is_sentence_start = true;
+      }
+      index += 1;
+      continue;
+    }
+
+    // Collect consecutive capitalized words separated by single spaces
+    let mut words = vec![];
+    let mut end = index;
+    while parts.get(end).is_some_and(|word| is_name_word(word)) {
+      words.push(parts[end]);
+      if parts.get(end + 1) == Some(&" ")
+        && parts.get(end + 2).is_some_and(|word| is_name_word(word))
+      {
+        end += 2;
+      } else {
+        end += 1;
+        break;
+      }
+    }
+
+    // A single capitalized word at the start of a sentence is ambiguous
+    let follows_title = prev_word.is_some_and(|word| TITLES.contains(&word));
+    let is_entity = !words.is_empty()
+      && (!is_sentence_start || words.len() > 1 || follows_title);
+
+    if is_entity {
+      let entity = parts[index..end].concat();
+      let placeholder = match mapping.iter().find(|(_, orig)| *orig == entity) {
+        Some((placeholder, _)) => placeholder.clone(),
+        None => {
+          let kind = get_entity_kind(&words, prev_word);
+          let count = mapping
+            .iter()
+            .filter(|(placeholder, _)| placeholder.starts_with(kind))
+            .count();
+          let placeholder = format!("{kind}_{}", count + 1);
+          mapping.push((placeholder.clone(), entity));
+          placeholder
+        }
+      };
+      anonymized.push_str(&placeholder);
+      prev_word = words.last().copied();
+      index = end;
+    } else {
+      anonymized.push_str(part);
+      prev_word = Some(part);
+      index += 1;
+    }
+    is_sentence_start = false;
+  }
+
+  (anonymized, mapping)
+}
+
+/// Replace the placeholders in the text with the original entities
+pub fn deanonymize(text: &str, mapping: &EntityMapping) -> String {
+  let mut sorted_mapping = mapping.clone();
+  // Replace longer placeholders first, so that `PERSON_1` doesn't match
+  // the beginning of `PERSON_10`
+  sorted_mapping
+    .sort_by_key(|(placeholder, _)| std::cmp::Reverse(placeholder.len()));
+
+  sorted_mapping
+    .iter()
+    .fold(text.to_string(), |restored, (placeholder, original)| {
+      restored.replace(placeholder, original)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_anonymize() {
+    let text = "Please write an email to Dr. Jane Smith at Acme Inc \
+      about her move to New York. Jane Smith starts on Monday.";
+    let (anonymized, mapping) = anonymize(text);
+    assert_eq!(
+      anonymized,
+      "Please write an email to Dr. PERSON_1 at ORG_1 \
+      about her move to LOCATION_1. PERSON_1 starts on Monday."
+    );
+    assert_eq!(
+      mapping,
+      vec![
+        ("PERSON_1".to_string(), "Jane Smith".to_string()),
+        ("ORG_1".to_string(), "Acme Inc".to_string()),
+        ("LOCATION_1".to_string(), "New York".to_string()),
+      ]
+    );
+    assert_eq!(deanonymize(&anonymized, &mapping), text);
+  }
+}
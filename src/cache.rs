@@ -0,0 +1,120 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use xdg::BaseDirectories;
+
+/// Default time in seconds until a cached response expires
+pub const DEFAULT_TTL_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CacheEntry {
+  created_secs: u64,
+  content: String,
+  thinking: Option<String>,
+}
+
+/// 64-bit FNV-1a hash, which (unlike `DefaultHasher`)
+/// is stable across Rust versions
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+    (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+  })
+}
+
+/// Cache key of a request, derived from the URL and the complete body
+/// (which includes the model, system prompts, and all parameters)
+pub fn get_cache_key(url: &str, req_body_obj: &Value) -> String {
+  format!(
+    "{:016x}",
+    fnv1a_hash(format!("{url}\n{req_body_obj}").as_bytes())
+  )
+}
+
+fn get_cache_dir() -> Option<PathBuf> {
+  Some(
+    BaseDirectories::with_prefix("cai")
+      .ok()?
+      .get_cache_home()
+      .join("responses"),
+  )
+}
+
+fn now_secs() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |duration| duration.as_secs())
+}
+
+/// Load a cached response (content and thinking) if it hasn't expired yet
+pub fn load_response(
+  cache_key: &str,
+  ttl_secs: u64,
+) -> Option<(String, Option<String>)> {
+  let path = get_cache_dir()?.join(format!("{cache_key}.json"));
+  let entry: CacheEntry =
+    serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+
+  (now_secs().saturating_sub(entry.created_secs) < ttl_secs)
+    .then_some((entry.content, entry.thinking))
+}
+
+/// Save the response in the cache.
+/// Failures only print a warning, as the cache is an optimization.
+pub fn save_response(
+  cache_key: &str,
+  content: &str,
+  thinking: &Option<String>,
+) {
+  let entry = CacheEntry {
+    created_secs: now_secs(),
+    content: content.to_string(),
+    thinking: thinking.clone(),
+  };
+  let result = get_cache_dir()
+    .ok_or("Couldn't determine the cache directory".to_string())
+    .and_then(|dir| {
+      std::fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+      std::fs::write(
+        dir.join(format!("{cache_key}.json")),
+        serde_json::to_string(&entry).map_err(|err| err.to_string())?,
+      )
+      .map_err(|err| err.to_string())
+    });
+
+  if let Err(err) = result {
+    eprintln!("Warning: Couldn't cache the response: {err}");
+  }
+}
+
+/// Remove all cached responses and return how many were removed
+pub fn clear_responses() -> std::io::Result<usize> {
+  let Some(dir) = get_cache_dir().filter(|dir| dir.exists()) else {
+    return Ok(0);
+  };
+  let count = std::fs::read_dir(&dir)?.count();
+  std::fs::remove_dir_all(&dir)?;
+  Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_get_cache_key() {
+    let url = "https://api.openai.com/v1/chat/completions";
+    let body = json!({ "model": "gpt-4o-mini", "messages": [] });
+    let key = get_cache_key(url, &body);
+
+    assert_eq!(key.len(), 16);
+    assert_eq!(key, get_cache_key(url, &body));
+    assert_ne!(
+      key,
+      get_cache_key(url, &json!({ "model": "gpt-4o", "messages": [] }))
+    );
+    assert_eq!(fnv1a_hash(b""), 0xcbf2_9ce4_8422_2325);
+  }
+}
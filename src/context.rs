@@ -0,0 +1,235 @@
+use serde_derive::Serialize;
+
+/// Context window sizes (in tokens) of model families.
+/// The longest prefix of the model id (without any gateway prefix
+/// like `openai/`) is used, so the order doesn't matter.
+const CONTEXT_WINDOWS: &[(&str, usize)] = &[
+  ("claude-", 200_000),
+  ("deepseek-chat", 64_000),
+  ("deepseek-r1", 128_000),
+  ("deepseek-r1-distill-", 131_072),
+  ("gemma-", 8_192),
+  ("gemma2", 8_192),
+  ("gemma3", 131_072),
+  ("gpt-3.5-turbo", 16_385),
+  ("gpt-4-turbo", 128_000),
+  ("gpt-4.1", 1_047_576),
+  ("gpt-4o", 128_000),
+  ("gpt-5", 400_000),
+  ("llama-3.1-", 131_072),
+  ("llama-3.3-", 131_072),
+  ("llama-4-", 131_072),
+  ("llama-v3p1-", 131_072),
+  ("llama-v3p3-", 131_072),
+  ("llama3.1", 131_072),
+  ("llama3.3", 131_072),
+  ("mistral-large", 128_000),
+  ("mixtral-8x7b", 32_768),
+  ("o1", 200_000),
+  ("o1-mini", 128_000),
+  ("o1-preview", 128_000),
+  ("o3", 200_000),
+  ("o3-mini", 200_000),
+  ("o4-mini", 200_000),
+  ("qwen2.5", 131_072),
+  ("qwen3", 131_072),
+  ("qwq", 131_072),
+];
+
+/// Context window of unknown models (e.g. local ones)
+const DEFAULT_CONTEXT_WINDOW: usize = 8_192;
+
+/// Chunks should never be smaller than this number of tokens
+const MIN_CHUNK_TOKENS: usize = 1_000;
+
+/// Get the context window of the model in tokens (if it's known)
+pub fn get_known_context_window(model: &str) -> Option<usize> {
+  let model = model.to_lowercase();
+  let model_id = model.rsplit('/').next().unwrap_or(&model);
+  CONTEXT_WINDOWS
+    .iter()
+    .filter(|(prefix, _)| {
+      // The prefix must end at a separator
+      // (e.g. `o1` matches `o1-2024-12-17`, but not `o1x`)
+      model_id.strip_prefix(prefix).is_some_and(|rest| {
+        prefix.ends_with('-')
+          || rest.is_empty()
+          || rest.starts_with(['-', ':', '@'])
+      })
+    })
+    .max_by_key(|(prefix, _)| prefix.len())
+    .map(|(_, tokens)| *tokens)
+}
+
+/// Get the context window of the model in tokens
+pub fn get_context_window(model: &str) -> usize {
+  get_known_context_window(model).unwrap_or(DEFAULT_CONTEXT_WINDOW)
+}
+
+/// Maximum number of input tokens per chunk.
+/// Half of the context window (without the output tokens) is used
+/// to leave enough room for the prompt and estimation errors.
+pub fn get_chunk_tokens(model: &str, max_output_tokens: u32) -> usize {
+  let available_tokens =
+    get_context_window(model).saturating_sub(max_output_tokens as usize);
+  (available_tokens / 2).max(MIN_CHUNK_TOKENS)
+}
+
+/// Split the text at line boundaries into chunks with at most `max_tokens`.
+/// Lines which are too long on their own are split at character boundaries.
+pub fn split_into_chunks(
+  text: &str,
+  max_tokens: usize,
+  count_tokens: impl Fn(&str) -> usize,
+) -> Vec<String> {
+  let mut chunks = vec![];
+  let mut chunk = String::new();
+  let mut chunk_tokens = 0;
+
+  for line in text.split_inclusive('\n') {
+    if count_tokens(line) > max_tokens {
+      if !chunk.is_empty() {
+        chunks.push(std::mem::take(&mut chunk));
+        chunk_tokens = 0;
+      }
+      // Assume at least one token per 3 characters to be on the safe side
+      let chars: Vec<char> = line.chars().collect();
+      chunks.extend(
+        chars
+          .chunks(max_tokens * 3)
+          .map(|piece| piece.iter().collect::<String>()),
+      );
+      continue;
+    }
+    let line_tokens = count_tokens(line);
+    if !chunk.is_empty() && chunk_tokens + line_tokens > max_tokens {
+      chunks.push(std::mem::take(&mut chunk));
+      chunk_tokens = 0;
+    }
+    chunk.push_str(line);
+    chunk_tokens += line_tokens;
+  }
+  if !chunk.is_empty() {
+    chunks.push(chunk);
+  }
+
+  chunks
+}
+
+/// Which end of an oversized input is removed
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+pub enum Truncation {
+  Head,
+  Tail,
+}
+
+/// Remove whole lines from the head or the tail of the text
+/// until it has at most `max_tokens`.
+/// The line at the boundary is cut at a character boundary instead.
+pub fn truncate_to_tokens(
+  text: &str,
+  max_tokens: usize,
+  truncation: Truncation,
+  count_tokens: impl Fn(&str) -> usize,
+) -> String {
+  let mut lines: Vec<&str> = text.split_inclusive('\n').collect();
+  if truncation == Truncation::Head {
+    lines.reverse();
+  }
+
+  let mut kept_lines: Vec<String> = vec![];
+  let mut kept_tokens = 0;
+  for line in lines {
+    let line_tokens = count_tokens(line);
+    if kept_tokens + line_tokens <= max_tokens {
+      kept_lines.push(line.to_string());
+      kept_tokens += line_tokens;
+      continue;
+    }
+    // Start with ~3 characters per token and shrink the piece until it fits
+    let remaining_tokens = max_tokens - kept_tokens;
+    let chars: Vec<char> = line.chars().collect();
+    let mut char_count = (remaining_tokens * 3).min(chars.len());
+    while char_count > 0 {
+      let piece: String = match truncation {
+        Truncation::Head => chars[chars.len() - char_count..].iter().collect(),
+        Truncation::Tail => chars[..char_count].iter().collect(),
+      };
+      if count_tokens(&piece) <= remaining_tokens {
+        kept_lines.push(piece);
+        break;
+      }
+      char_count = char_count * 3 / 4;
+    }
+    break;
+  }
+
+  if truncation == Truncation::Head {
+    kept_lines.reverse();
+  }
+  kept_lines.concat()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_get_known_context_window() {
+    assert_eq!(get_known_context_window("some-local-model"), None);
+    assert_eq!(get_known_context_window("qwen3:8b"), Some(131_072));
+    assert_eq!(get_known_context_window("qwen-7b"), None);
+    assert_eq!(
+      get_known_context_window("deepseek-r1-distill-llama-70b"),
+      Some(131_072)
+    );
+    assert_eq!(get_known_context_window("o1-mini"), Some(128_000));
+    assert_eq!(get_known_context_window("o1-2024-12-17"), Some(200_000));
+    assert_eq!(get_known_context_window("foo1"), None);
+    assert_eq!(get_known_context_window("o1x"), None);
+    assert_eq!(
+      get_known_context_window("openai/gpt-4o-mini"),
+      Some(128_000)
+    );
+    assert_eq!(
+      get_known_context_window("accounts/fireworks/models/llama-v3p1-8b"),
+      Some(131_072)
+    );
+
+    assert_eq!(get_context_window("claude-3-5-haiku-latest"), 200_000);
+    assert_eq!(get_context_window("some-local-model"), 8_192);
+    assert_eq!(get_chunk_tokens("some-local-model", 4096), 2048);
+  }
+
+  #[test]
+  fn test_split_into_chunks() {
+    // One token per word
+    let count_words = |text: &str| text.split_whitespace().count();
+    assert_eq!(
+      split_into_chunks("a b\nc d\ne\n", 4, count_words),
+      vec!["a b\nc d\n", "e\n"]
+    );
+    assert_eq!(
+      split_into_chunks("a\nb c d e f\ng", 2, count_words),
+      vec!["a\n", "b c d ", "e f\n", "g"]
+    );
+  }
+
+  #[test]
+  fn test_truncate_to_tokens() {
+    let count_words = |text: &str| text.split_whitespace().count();
+    let text = "a b\nc d\ne\n";
+    assert_eq!(
+      truncate_to_tokens(text, 3, Truncation::Tail, count_words),
+      "a b\nc "
+    );
+    assert_eq!(
+      truncate_to_tokens(text, 3, Truncation::Head, count_words),
+      "c d\ne\n"
+    );
+    assert_eq!(
+      truncate_to_tokens("a b c d", 2, Truncation::Head, count_words),
+      " c d"
+    );
+  }
+}
@@ -1,62 +1,332 @@
 use bat::PrettyPrinter;
-use textwrap::termwidth;
 
-pub fn wrap_text(text: &String) -> String {
-  if termwidth() > 100 {
-    textwrap::wrap(&text, 80).join("\n")
-  } else {
-    text.clone()
+/// A part of a markdown response
+#[derive(Debug, PartialEq)]
+pub enum Block {
+  /// Markdown prose (including the code fence lines themselves)
+  Prose(String),
+  /// Content of a fenced code block with its declared language
+  Code { lang: String, code: String },
+}
+
+/// Maximum default wrap width to keep long lines readable
+const MAX_DEFAULT_WIDTH: usize = 100;
+
+/// Select the configured wrap width (`--width` or `wrap_width`)
+/// or the terminal width (capped at 100). A width of 0 disables wrapping.
+pub fn get_wrap_width(
+  width: Option<usize>,
+  term_width: usize,
+) -> Option<usize> {
+  let width = width.unwrap_or(term_width.min(MAX_DEFAULT_WIDTH));
+  (width > 0).then_some(width)
+}
+
+pub fn wrap_text(text: &str, width: Option<usize>) -> String {
+  match width {
+    Some(width) => textwrap::wrap(text, width).join("\n"),
+    None => text.to_string(),
   }
 }
 
-pub fn text_via_bat(text: &String) {
-  let text_wrapped = wrap_text(text);
+fn is_fence(line: &str) -> bool {
+  let trimmed = line.trim_start();
+  trimmed.starts_with("```") || trimmed.starts_with("~~~")
+}
+
+/// Split markdown text into prose and fenced code blocks.
+/// Lines are kept verbatim to preserve the original indentation.
+pub fn split_code_blocks(text: &str) -> Vec<Block> {
+  let mut blocks = vec![];
+  let mut prose = String::new();
+  let mut lines = text.lines();
+
+  while let Some(line) = lines.next() {
+    prose.push_str(line);
+    prose.push('\n');
+
+    if !is_fence(line) {
+      continue;
+    }
+
+    let fence = &line.trim_start()[..3];
+    let lang = line.trim_start()[3..]
+      .split_whitespace()
+      .next()
+      .unwrap_or("")
+      .to_string();
+    blocks.push(Block::Prose(std::mem::take(&mut prose)));
+
+    let mut code = String::new();
+    for code_line in lines.by_ref() {
+      if code_line.trim() == fence {
+        prose.push_str(code_line);
+        prose.push('\n');
+        break;
+      }
+      code.push_str(code_line);
+      code.push('\n');
+    }
+    blocks.push(Block::Code { lang, code });
+  }
+
+  if !prose.is_empty() {
+    blocks.push(Block::Prose(prose));
+  }
+
+  blocks
+}
+
+/// bat's theme for terminals with a light background
+const LIGHT_THEME: &str = "Monokai Extended Light";
+
+/// Names of all built-in themes of bat
+pub fn list_themes() -> Vec<String> {
   PrettyPrinter::new()
-    .input_from_bytes(text_wrapped.as_bytes())
-    .language("markdown")
-    .print()
-    .unwrap();
-}
-
-// TODO: This doesn't syntax highlight code blocks
-//
-// use textwrap::termwidth;
-// use syntect::easy::HighlightLines;
-// use syntect::highlighting::{Style, ThemeSet};
-// use syntect::parsing::SyntaxSet;
-// use syntect::util::as_24_bit_terminal_escaped;
-//
-// pub fn lines_via_syntect(msg: &String) {
-//   let lines = if termwidth() > 100 {
-//     textwrap::wrap(&msg, 60)
-//       .iter()
-//       .map(|s| s.to_string())
-//       .collect::<Vec<String>>()
-//   } else {
-//     msg
-//       .split("\n")
-//       .map(|s| s.to_string())
-//       .collect::<Vec<String>>()
-//   };
-//
-//   let syn_set = SyntaxSet::load_defaults_newlines();
-//   let theme_set = ThemeSet::load_defaults();
-//   let syntax = syn_set.find_syntax_by_extension("md").unwrap();
-//   let mode = dark_light::detect();
-//   let mut highlighter = HighlightLines::new(
-//     syntax,
-//     match mode {
-//       dark_light::Mode::Default => &theme_set.themes["base16-mocha.dark"],
-//       dark_light::Mode::Dark => &theme_set.themes["base16-mocha.dark"],
-//       dark_light::Mode::Light => &theme_set.themes["InspiredGitHub"],
-//     },
-//   );
-//
-//   for line in lines {
-//     let ranges: Vec<(Style, &str)> = highlighter //
-//       .highlight_line(line, &syn_set)
-//       .unwrap();
-//     let escaped = as_24_bit_terminal_escaped(&ranges[..], true);
-//     print!("{}\n", escaped);
-//   }
-// }
+    .themes()
+    .map(|theme| theme.to_string())
+    .collect()
+}
+
+/// Check if the terminal has a light background according to `COLORFGBG`
+/// (e.g. `0;15`), which is set by many terminal emulators
+fn is_light_background(colorfgbg: &str) -> Option<bool> {
+  let background: u8 = colorfgbg.rsplit(';').next()?.parse().ok()?;
+  // ANSI colors 7 (white) and 9 - 15 (bright colors except black)
+  Some(background == 7 || (9..=15).contains(&background))
+}
+
+/// Select the configured theme (`--theme` or `bat_theme`)
+/// or the one for the detected terminal background.
+/// `None` uses bat's default theme.
+pub fn get_theme(
+  theme: Option<String>,
+  colorfgbg: Option<String>,
+) -> Option<String> {
+  theme.or_else(|| {
+    colorfgbg
+      .as_deref()
+      .and_then(is_light_background)
+      .filter(|is_light| *is_light)
+      .map(|_| LIGHT_THEME.to_string())
+  })
+}
+
+fn print_via_bat(
+  text: &str,
+  language: &str,
+  colored: bool,
+  theme: Option<&str>,
+) {
+  let mut printer = PrettyPrinter::new();
+  printer
+    .input_from_bytes(text.as_bytes())
+    .language(language)
+    .colored_output(colored);
+  if let Some(theme) = theme {
+    printer.theme(theme);
+  }
+  printer.print().unwrap();
+}
+
+/// Check if bat has a syntax for the language name or file extension
+fn is_supported_lang(lang: &str) -> bool {
+  PrettyPrinter::new().syntaxes().any(|syntax| {
+    syntax.name.eq_ignore_ascii_case(lang)
+      || syntax
+        .file_extensions
+        .iter()
+        .any(|ext| ext.eq_ignore_ascii_case(lang))
+  })
+}
+
+/// Print the prose as markdown and each code block
+/// highlighted with its declared language
+pub fn text_via_bat(
+  text: &str,
+  colored: bool,
+  width: Option<usize>,
+  theme: Option<&str>,
+) {
+  for block in split_code_blocks(text) {
+    match block {
+      Block::Prose(prose) => {
+        print_via_bat(&wrap_text(&prose, width), "markdown", colored, theme);
+      }
+      Block::Code { lang, code } => {
+        // Fall back to plain text for missing or unknown languages
+        if colored && !lang.is_empty() && is_supported_lang(&lang) {
+          print_via_bat(&code, &lang, colored, theme);
+        } else {
+          print!("{code}");
+        }
+      }
+    }
+  }
+}
+
+/// Remove inline markdown syntax (emphasis, code, links) from a line
+fn strip_inline_markdown(line: &str) -> String {
+  let chars: Vec<char> = line.chars().collect();
+  let mut plain = String::with_capacity(line.len());
+  let mut i = 0;
+
+  while i < chars.len() {
+    let char = chars[i];
+    match char {
+      '`' => i += 1,
+      '!' if chars.get(i + 1) == Some(&'[') => i += 1,
+      '[' => {
+        let rest: String = chars[i..].iter().collect();
+        let link = rest.find("](").and_then(|text_end| {
+          rest[text_end..]
+            .find(')')
+            .map(|url_end| (text_end, text_end + url_end))
+        });
+        match link {
+          Some((text_end, url_end)) => {
+            let text = strip_inline_markdown(&rest[1..text_end]);
+            let url = &rest[text_end + 2..url_end];
+            plain.push_str(&format!("{text} ({url})"));
+            i += rest[..=url_end].chars().count();
+          }
+          None => {
+            plain.push(char);
+            i += 1;
+          }
+        }
+      }
+      '*' | '_' | '~' => {
+        let run_end = chars[i..]
+          .iter()
+          .position(|c| *c != char)
+          .map_or(chars.len(), |pos| i + pos);
+        let prev = if i == 0 { ' ' } else { chars[i - 1] };
+        let next = chars.get(run_end).copied().unwrap_or(' ');
+        let is_literal = match char {
+          // Underscores inside of words (e.g. snake_case)
+          '_' => prev.is_alphanumeric() && next.is_alphanumeric(),
+          // Operators surrounded by whitespace (e.g. 2 * 3)
+          _ => prev.is_whitespace() && next.is_whitespace(),
+        };
+        if is_literal || (char == '~' && run_end - i == 1) {
+          plain.extend(&chars[i..run_end]);
+        }
+        i = run_end;
+      }
+      _ => {
+        plain.push(char);
+        i += 1;
+      }
+    }
+  }
+
+  plain
+}
+
+/// Render markdown as readable plain text
+pub fn to_plain_text(text: &str) -> String {
+  let mut plain = String::new();
+
+  for block in split_code_blocks(text) {
+    match block {
+      Block::Prose(prose) => {
+        for line in prose.lines().filter(|line| !is_fence(line)) {
+          let trimmed = line.trim_start();
+          let indent = &line[..line.len() - trimmed.len()];
+          let heading = trimmed.trim_start_matches('#');
+
+          if trimmed.starts_with('#') && heading.starts_with(' ') {
+            plain
+              .push_str(&strip_inline_markdown(heading.trim()).to_uppercase());
+          } else if let Some(item) = ["* ", "+ ", "- "]
+            .iter()
+            .find_map(|bullet| trimmed.strip_prefix(bullet))
+          {
+            plain
+              .push_str(&format!("{indent}- {}", strip_inline_markdown(item)));
+          } else {
+            plain.push_str(&strip_inline_markdown(line));
+          }
+          plain.push('\n');
+        }
+      }
+      Block::Code { code, .. } => plain.push_str(&code),
+    }
+  }
+
+  plain
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_is_supported_lang() {
+    assert!(is_supported_lang("rust"));
+    assert!(is_supported_lang("py"));
+    assert!(!is_supported_lang("not-a-language"));
+  }
+
+  #[test]
+  fn test_get_theme() {
+    let light_bg = Some("0;15".to_string());
+    assert_eq!(
+      get_theme(Some("Nord".to_string()), light_bg.clone()),
+      Some("Nord".to_string())
+    );
+    assert_eq!(get_theme(None, light_bg), Some(LIGHT_THEME.to_string()));
+    assert_eq!(get_theme(None, Some("15;0".to_string())), None);
+    assert_eq!(get_theme(None, None), None);
+    assert!(list_themes().contains(&LIGHT_THEME.to_string()));
+  }
+
+  #[test]
+  fn test_get_wrap_width() {
+    assert_eq!(get_wrap_width(None, 80), Some(80));
+    assert_eq!(get_wrap_width(None, 200), Some(100));
+    assert_eq!(get_wrap_width(Some(60), 200), Some(60));
+    assert_eq!(get_wrap_width(Some(120), 80), Some(120));
+    assert_eq!(get_wrap_width(Some(0), 80), None);
+  }
+
+  #[test]
+  fn test_split_code_blocks() {
+    let text = "Some code:\n\n  ```rust\n  fn main() {}\n  ```\nDone";
+    assert_eq!(
+      split_code_blocks(text),
+      vec![
+        Block::Prose("Some code:\n\n  ```rust\n".to_string()),
+        Block::Code {
+          lang: "rust".to_string(),
+          code: "  fn main() {}\n".to_string(),
+        },
+        Block::Prose("  ```\nDone\n".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_to_plain_text() {
+    let text = "# My *Title*\n\
+      \n\
+      Some **bold** and _italic_ text with `snake_case` and 2 * 3.\n\
+      * First [link](https://example.com)\n\
+      \x20 + Nested ~~item~~\n\
+      \n\
+      ```rust\n\
+      let **x** = 1;\n\
+      ```\n";
+    assert_eq!(
+      to_plain_text(text),
+      "MY TITLE\n\
+      \n\
+      Some bold and italic text with snake_case and 2 * 3.\n\
+      - First link (https://example.com)\n\
+      \x20 - Nested item\n\
+      \n\
+      let **x** = 1;\n"
+    );
+  }
+}
@@ -1,18 +1,41 @@
+mod anonymize;
+mod cache;
+mod context;
 mod highlight;
+mod ollama;
+mod prompt_template;
+mod schema;
+mod shell;
+#[cfg(feature = "bedrock")]
+mod sigv4;
+mod tokenizer;
 
 use base64::Engine;
 use std::env;
 use std::error::Error;
+use std::fmt::Write as _;
+use std::io::IsTerminal;
 use std::str;
+use std::sync::{Mutex, OnceLock};
 use std::time::Instant;
 
-use color_print::{cformat, cprintln};
+use color_print::cformat;
 use config::Config;
-use reqwest::Response;
+use reqwest::StatusCode;
+use serde::Deserialize as _;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::{json, Map, Value};
 use xdg::BaseDirectories;
 
+pub use cache::clear_responses as clear_cache;
+pub use context::Truncation;
+pub use highlight::list_themes;
+pub use prompt_template::{
+  load_prompt_template, render_prompt_template, wrap_prompt, PromptTemplate,
+};
+pub use schema::get_unsupported_keywords;
+pub use tokenizer::estimate_tokens;
+
 // Includes `GROQ_MODEL_MAPPING` and `OLLAMA_MODEL_MAPPING` from `/build.rs`
 include!(concat!(env!("OUT_DIR"), "/models.rs"));
 
@@ -20,10 +43,54 @@ include!(concat!(env!("OUT_DIR"), "/models.rs"));
 pub struct ExecOptions {
   pub is_raw: bool, // Raw output mode (no metadata and no syntax highlighting)
   pub is_json: bool, // JSON output mode
+  pub strict_json: bool, // Reject responses without valid JSON
+  pub is_plain: bool, // Render markdown as plain text
   pub json_schema: Option<Value>, // JSON schema of expected output
+  pub output: Option<String>, // File to additionally write the response to
+  pub force: bool,  // Overwrite the output file if it already exists
+  pub system_prompts: Vec<String>, // System prompts in the order they were set
+  pub no_color: bool, // Disable ANSI colors in the output
+  pub no_emoji: bool, // Replace emoji in the output with text
+  pub params: Map<String, Value>, // Extra top-level request parameters
+  pub thinking_budget: Option<u32>, // Token budget for Anthropic's thinking
+  pub system_role: Option<String>, // Role name to use for system messages
+  pub pool_size: Option<usize>, // Max idle connections per host
+  pub exit_code_from: Option<String>, // JSON path to derive the exit code from
+  pub extract: Option<String>, // JSON path of the field to print
+  pub proxy: Option<String>, // Proxy URL for all requests
+  pub anonymize: bool, // Replace names with placeholders before sending
+  pub verbose: bool, // Log the raw request and response to stderr
+  pub measure: bool, // Print a timing breakdown to stderr
+  pub images: Vec<String>, // Paths or URLs of images to attach
+  pub print_prompt_tokens: bool, // Only print the token count of the prompt
+  pub confirm: bool, // Ask for confirmation before sending the request
+  pub yes: bool,    // Never ask for confirmation
+  pub wrap_width: Option<usize>, // Wrap width of prose (0 disables wrapping)
+  pub json_repair: bool, // Retry once if the response violates the schema
+  pub cache: bool,  // Use cached responses for identical requests
+  pub no_cache: bool, // Never use cached responses
+  pub oauth: bool,  // Use the Anthropic OAuth token instead of the API key
+  pub model: Option<Model>, // Overrides the models of the helper commands
+  pub with_date: bool, // Add the current date to the system prompt
+  pub date_format: Option<String>, // strftime format of the date
+  pub extra_headers: Vec<(String, String)>, // Overrides config headers
+  pub stop_sequences: Vec<String>, // Sequences which end the generation
+  pub seed: Option<u64>, // Seed for (best-effort) deterministic sampling
+  pub theme: Option<String>, // Syntax highlighting theme of bat
+  pub quiet: bool,  // Highlighted output without the metadata header
+  pub meta_stdout: bool, // Print the metadata header to stdout (not stderr)
+  pub ollama_native: bool, // Use Ollama's native API instead of /v1
+  pub jobs: Option<usize>, // Max number of concurrent requests
+  pub json_meta: bool, // Print the result with its metadata as JSON
+  pub show_reasoning: bool, // Print the reasoning of reasoning models
+  pub truncation: Option<Truncation>, // Shorten inputs which are too long
+  pub no_context_check: bool, // Send prompts which seem too long anyway
+  pub max_tokens: Option<u32>, // Overrides `{provider}_max_tokens`
+  pub config_path: Option<String>, // Overrides `CAI_CONFIG`
+  pub prompt_wrappers: Vec<String>, // Built-in instructions (e.g. short)
 }
 
-#[derive(Serialize, Debug, PartialEq, Default, Clone, Copy)]
+#[derive(Serialize, Debug, PartialEq, Eq, Hash, Default, Clone, Copy)]
 pub enum Provider {
   #[default]
   Anthropic,
@@ -31,8 +98,23 @@ pub enum Provider {
   OpenAI,
   Llamafile,
   Ollama,
+  Together,
+  Fireworks,
+  Bedrock,
 }
 
+/// All providers in the order of the help output
+pub const PROVIDERS: [Provider; 8] = [
+  Provider::Anthropic,
+  Provider::Groq,
+  Provider::OpenAI,
+  Provider::Llamafile,
+  Provider::Ollama,
+  Provider::Together,
+  Provider::Fireworks,
+  Provider::Bedrock,
+];
+
 impl std::fmt::Display for Provider {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
@@ -41,6 +123,31 @@ impl std::fmt::Display for Provider {
       Provider::OpenAI => write!(f, "OpenAI"),
       Provider::Llamafile => write!(f, "Llamafile"),
       Provider::Ollama => write!(f, "Ollama"),
+      Provider::Together => write!(f, "Together"),
+      Provider::Fireworks => write!(f, "Fireworks"),
+      Provider::Bedrock => write!(f, "Bedrock"),
+    }
+  }
+}
+
+impl std::str::FromStr for Provider {
+  type Err = String;
+
+  fn from_str(provider_str: &str) -> Result<Self, Self::Err> {
+    match provider_str.to_lowercase().as_str() {
+      "anthropic" => Ok(Provider::Anthropic),
+      "groq" => Ok(Provider::Groq),
+      "openai" => Ok(Provider::OpenAI),
+      "llamafile" => Ok(Provider::Llamafile),
+      "ollama" => Ok(Provider::Ollama),
+      "together" => Ok(Provider::Together),
+      "fireworks" => Ok(Provider::Fireworks),
+      "bedrock" => Ok(Provider::Bedrock),
+      _ => Err(format!(
+        "Unknown provider \"{provider_str}\". \
+        Valid providers are: anthropic, groq, openai, llamafile, ollama, \
+        together, fireworks, bedrock"
+      )),
     }
   }
 }
@@ -56,6 +163,54 @@ impl Default for Model {
   }
 }
 
+/// Randomization of the exponential backoff between retries
+#[derive(Serialize, Debug, PartialEq, Default, Clone, Copy)]
+pub enum RetryJitter {
+  /// Random delay between 0 and the backoff
+  #[default]
+  Full,
+  /// Half of the backoff plus a random delay up to the other half
+  Equal,
+  /// Exactly the backoff
+  None,
+}
+
+impl std::str::FromStr for RetryJitter {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "full" => Ok(RetryJitter::Full),
+      "equal" => Ok(RetryJitter::Equal),
+      "none" => Ok(RetryJitter::None),
+      _ => Err(format!(
+        "Invalid retry jitter \"{s}\". Valid values are: full, equal, none"
+      )),
+    }
+  }
+}
+
+/// Parse a `provider/model` string (e.g. `anthropic/claude-sonnet`).
+/// The model can be an alias and is resolved when creating the request.
+impl std::str::FromStr for Model {
+  type Err = String;
+
+  fn from_str(model_str: &str) -> Result<Self, Self::Err> {
+    let (provider_str, model_id) =
+      model_str.split_once('/').unwrap_or((model_str, ""));
+    let provider = provider_str.parse::<Provider>()?;
+
+    // Llamafile serves exactly one model and therefore needs no model id
+    if model_id.is_empty() && provider != Provider::Llamafile {
+      Err(format!(
+        "Missing model in \"{model_str}\". Use the format provider/model"
+      ))?;
+    }
+
+    Ok(Model::Model(provider, model_id.to_string()))
+  }
+}
+
 impl std::fmt::Display for Model {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
@@ -70,14 +225,38 @@ impl std::fmt::Display for Model {
   }
 }
 
+/// Credentials for signing AWS requests (used by Bedrock)
+#[derive(Serialize, Debug, Clone)]
+struct AwsCredentials {
+  access_key_id: String,
+  secret_access_key: String,
+  session_token: Option<String>,
+  region: String,
+}
+
 #[derive(Serialize, Debug, Clone)]
 struct AiRequest {
   provider: Provider,
   url: String,
   model: String,
+  model_prefix: String, // Gateway specific naming (e.g. `openai/`)
+  model_suffix: String,
   prompt: String,
   max_tokens: u32,
   api_key: String,
+  api_keys: Vec<String>, // All configured keys for failover
+  is_oauth: bool,
+  pool_size: Option<usize>,
+  proxy: Option<String>,
+  headers: Vec<(String, String)>,
+  aws_credentials: Option<AwsCredentials>,
+  openai_org: Option<String>,
+  openai_project: Option<String>,
+  anthropic_version: Option<String>,
+  anthropic_beta: Option<String>, // Comma-separated beta features
+  retries: u32,
+  retry_max_ms: u64,
+  retry_jitter: RetryJitter,
 }
 
 impl Default for AiRequest {
@@ -86,17 +265,58 @@ impl Default for AiRequest {
       provider: Default::default(),
       url: Default::default(),
       model: Default::default(),
+      model_prefix: Default::default(),
+      model_suffix: Default::default(),
       prompt: Default::default(),
       max_tokens: 4096,
       api_key: Default::default(),
+      api_keys: Default::default(),
+      is_oauth: false,
+      pool_size: Default::default(),
+      proxy: Default::default(),
+      headers: Default::default(),
+      aws_credentials: None,
+      openai_org: None,
+      openai_project: None,
+      anthropic_version: None,
+      anthropic_beta: None,
+      retries: 0,
+      retry_max_ms: 10_000,
+      retry_jitter: Default::default(),
     }
   }
 }
 
+impl AiRequest {
+  /// Model id with the gateway specific prefix and suffix
+  /// as sent to the provider
+  fn get_gateway_model(&self) -> String {
+    format!("{}{}{}", self.model_prefix, self.model, self.model_suffix)
+  }
+}
+
 #[derive(Deserialize, Debug)]
 struct AiMessage {
   // role: String,
   content: String,
+  reasoning_content: Option<String>, // DeepSeek, Together
+  reasoning: Option<String>,         // Groq
+}
+
+/// Split a leading `<think>` block (e.g. of DeepSeek-R1) from the answer
+fn split_think_block(content: &str) -> (String, Option<String>) {
+  let Some((reasoning, answer)) = content
+    .trim_start()
+    .strip_prefix("<think>")
+    .and_then(|rest| rest.split_once("</think>"))
+  else {
+    return (content.to_string(), None);
+  };
+  let reasoning = reasoning.trim();
+  (
+    answer.trim_start().to_string(),
+    (!reasoning.is_empty()).then(|| reasoning.to_string()),
+  )
 }
 
 #[derive(Deserialize, Debug)]
@@ -112,11 +332,26 @@ struct AiResponse {
   choices: Vec<AiChoice>,
 }
 
+/// Name of the tool Anthropic is forced to use to get schema conforming JSON
+const JSON_SCHEMA_TOOL: &str = "json_response";
+
 /// For Anthropic's API
 /// (https://docs.anthropic.com/claude/reference/messages_post)
 #[derive(Deserialize, Debug)]
-struct AnthropicAiContent {
-  text: String,
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicAiContent {
+  Text {
+    text: String,
+  },
+  Thinking {
+    thinking: String,
+  },
+  ToolUse {
+    name: String,
+    input: Value,
+  },
+  #[serde(other)]
+  Other,
 }
 
 #[derive(Deserialize, Debug)]
@@ -124,6 +359,69 @@ struct AnthropicAiResponse {
   content: Vec<AnthropicAiContent>,
 }
 
+impl AnthropicAiResponse {
+  /// Get the input of the JSON schema tool if it was used
+  /// (any text around it is dropped to keep the JSON valid),
+  /// otherwise concatenate all text blocks in order
+  fn text(&self) -> String {
+    let tool_input = self.content.iter().find_map(|block| match block {
+      AnthropicAiContent::ToolUse { name, input }
+        if name == JSON_SCHEMA_TOOL =>
+      {
+        Some(input.to_string())
+      }
+      _ => None,
+    });
+    if let Some(tool_input) = tool_input {
+      return tool_input;
+    }
+
+    self
+      .content
+      .iter()
+      .filter_map(|block| match block {
+        AnthropicAiContent::Text { text } => Some(text.clone()),
+        _ => None,
+      })
+      .collect()
+  }
+
+  fn thinking(&self) -> Option<String> {
+    let thinking = self
+      .content
+      .iter()
+      .filter_map(|block| match block {
+        AnthropicAiContent::Thinking { thinking } => Some(thinking.as_str()),
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+    (!thinking.is_empty()).then(|| thinking.join("\n\n"))
+  }
+}
+
+/// Region which is used if `aws_region` isn't set
+const DEFAULT_AWS_REGION: &str = "us-east-1";
+
+/// Used if `anthropic_version` isn't set
+const DEFAULT_ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Beta feature which is required for OAuth tokens
+const ANTHROPIC_OAUTH_BETA: &str = "oauth-2025-04-20";
+
+/// URL of Bedrock's Converse API for the model
+fn get_bedrock_url(region: &str, model_id: &str) -> String {
+  let mut url = reqwest::Url::parse(&format!(
+    "https://bedrock-runtime.{region}.amazonaws.com/model"
+  ))
+  .expect("Bedrock URL is valid");
+  // Encodes slashes in ARNs of inference profiles
+  url
+    .path_segments_mut()
+    .expect("URL has a path")
+    .extend([model_id, "converse"]);
+  url.to_string()
+}
+
 fn default_req_for_model(model: &Model) -> AiRequest {
   let Model::Model(provider, model_id) = model;
 
@@ -157,6 +455,24 @@ fn default_req_for_model(model: &Model) -> AiRequest {
       model: get_ollama_model(model_id).to_string(),
       ..Default::default()
     },
+    Provider::Together => AiRequest {
+      provider: Provider::Together,
+      url: "https://api.together.xyz/v1/chat/completions".to_string(),
+      model: get_together_model(model_id).to_string(),
+      ..Default::default()
+    },
+    Provider::Fireworks => AiRequest {
+      provider: Provider::Fireworks,
+      url: "https://api.fireworks.ai/inference/v1/chat/completions".to_string(),
+      model: get_fireworks_model(model_id).to_string(),
+      ..Default::default()
+    },
+    Provider::Bedrock => AiRequest {
+      provider: Provider::Bedrock,
+      url: get_bedrock_url(DEFAULT_AWS_REGION, get_bedrock_model(model_id)),
+      model: get_bedrock_model(model_id).to_string(),
+      ..Default::default()
+    },
   }
 }
 
@@ -165,11 +481,11 @@ fn get_key_setup_msg(secrets_path_str: &str) -> String {
     "An API key must be provided. Use one of the following options:\n\
         \n\
         1. Set one or more API keys in {secrets_path_str}\n\
-           (`anthropic_api_key`, `groq_api_key`, `openai_api_key`)\n\
+           (`anthropic_api_key`, `groq_api_key`, `openai_api_key`, …)\n\
         2. Set one or more cai specific env variables\n\
-            (CAI_ANTHROPIC_API_KEY, CAI_GROQ_API_KEY, CAI_OPENAI_API_KEY)\n\
+            (CAI_ANTHROPIC_API_KEY, CAI_GROQ_API_KEY, CAI_OPENAI_API_KEY, …)\n\
         3. Set one or more generic env variables\n\
-            (ANTHROPIC_API_KEY, GROQ_API_KEY, OPENAI_API_KEY)\n\
+            (ANTHROPIC_API_KEY, GROQ_API_KEY, OPENAI_API_KEY, …)\n\
         ",
   )
 }
@@ -182,27 +498,124 @@ fn get_api_request(
   let dummy_key = "DUMMY_KEY".to_string();
   let Model::Model(provider, _) = model;
 
+  let get_key = |key: &str| {
+    full_config
+      .get(key)
+      .filter(|api_key| !api_key.is_empty())
+      .map(|api_key| api_key.to_string())
+  };
+  let oauth_token = match provider {
+    Provider::Anthropic => get_key("anthropic_oauth_token"),
+    _ => None,
+  };
+
   {
     match provider {
-      Provider::Groq => full_config.get("groq_api_key"),
-      Provider::OpenAI => full_config.get("openai_api_key"),
-      Provider::Anthropic => full_config.get("anthropic_api_key"),
-      Provider::Llamafile => Some(&dummy_key),
-      Provider::Ollama => Some(&dummy_key),
+      Provider::Groq => get_key("groq_api_key"),
+      Provider::OpenAI => get_key("openai_api_key"),
+      Provider::Anthropic => get_key("anthropic_api_key"),
+      Provider::Llamafile => Some(dummy_key),
+      Provider::Ollama => Some(dummy_key),
+      Provider::Together => get_key("together_api_key"),
+      Provider::Fireworks => get_key("fireworks_api_key"),
+      Provider::Bedrock => {
+        get_key("aws_access_key_id").and(get_key("aws_secret_access_key"))
+      }
     }
   }
-  .and_then(|api_key| {
-    if api_key.is_empty() {
-      None
-    } else {
-      Some(api_key.to_string())
-    }
-  })
-  .map(|api_key| api_key.to_string())
+  .map(|api_key| (api_key, false))
+  // Claude subscriptions provide OAuth tokens instead of API keys
+  .or(oauth_token.map(|token| (token, true)))
   .ok_or(get_key_setup_msg(secrets_path_str))
-  .map(|api_key| AiRequest {
-    api_key: api_key.clone(),
-    ..(default_req_for_model(model)).clone()
+  .map(|(api_key, is_oauth)| {
+    let default_req = default_req_for_model(model);
+    // Gateway specific naming (e.g. `openai_model_prefix: openai/`)
+    let provider_name = provider.to_string().to_lowercase();
+    // (Only applied to the id sent to the provider)
+    let get_affix = |kind: &str| {
+      full_config
+        .get(&format!("{provider_name}_model_{kind}"))
+        .cloned()
+        .unwrap_or_default()
+    };
+    let (model_prefix, model_suffix) =
+      (get_affix("prefix"), get_affix("suffix"));
+    let aws_credentials = match provider {
+      Provider::Bedrock => Some(AwsCredentials {
+        access_key_id: get_key("aws_access_key_id").unwrap_or_default(),
+        secret_access_key: api_key.clone(),
+        session_token: get_key("aws_session_token"),
+        region: get_key("aws_region").unwrap_or(DEFAULT_AWS_REGION.to_string()),
+      }),
+      _ => None,
+    };
+    // Several keys can be separated by commas (e.g. of different accounts)
+    let api_keys: Vec<String> = match provider {
+      Provider::Bedrock => vec![api_key.clone()],
+      _ => api_key
+        .split(',')
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect(),
+    };
+    AiRequest {
+      api_key: api_keys.first().cloned().unwrap_or(api_key.clone()),
+      api_keys,
+      is_oauth,
+      url: match &aws_credentials {
+        Some(credentials) => get_bedrock_url(
+          &credentials.region,
+          &format!("{model_prefix}{}{model_suffix}", default_req.model),
+        ),
+        None => default_req.url.clone(),
+      },
+      model: default_req.model.clone(),
+      model_prefix,
+      model_suffix,
+      aws_credentials,
+      // For billing attribution
+      openai_org: get_key("openai_org"),
+      openai_project: get_key("openai_project"),
+      anthropic_version: get_key("anthropic_version"),
+      anthropic_beta: get_key("anthropic_beta"),
+      // Precedence: `--max-tokens` flag > `{provider}_max_tokens` > default
+      // (The flag is applied when preparing the request)
+      max_tokens: get_key(&format!("{provider_name}_max_tokens"))
+        .and_then(|max_tokens| max_tokens.parse().ok())
+        .unwrap_or(default_req.max_tokens),
+      pool_size: full_config
+        .get("pool_size")
+        .and_then(|pool_size| pool_size.parse().ok()),
+      proxy: full_config.get("proxy").cloned(),
+      retries: full_config
+        .get("retries")
+        .and_then(|retries| retries.parse().ok())
+        .unwrap_or(default_req.retries),
+      retry_max_ms: full_config
+        .get("retry_max_ms")
+        .and_then(|max_ms| max_ms.parse().ok())
+        .unwrap_or(default_req.retry_max_ms),
+      retry_jitter: full_config
+        .get("retry_jitter")
+        .and_then(|jitter| match jitter.parse() {
+          Ok(jitter) => Some(jitter),
+          Err(err) => {
+            eprintln!("Warning: Ignoring invalid retry_jitter: {err}");
+            None
+          }
+        })
+        .unwrap_or_default(),
+      // Set via `headers.<provider>` tables in the config
+      headers: full_config
+        .iter()
+        .filter_map(|(key, value)| {
+          key
+            .strip_prefix(&format!("headers.{provider_name}."))
+            .map(|name| (name.to_string(), value.clone()))
+        })
+        .collect(),
+      ..default_req
+    }
   })
 }
 
@@ -218,12 +631,38 @@ fn get_used_model(model: &Model) -> String {
       Provider::Anthropic => get_anthropic_model(model_id),
       Provider::Llamafile => model_id,
       Provider::Ollama => get_ollama_model(model_id),
+      Provider::Together => get_together_model(model_id),
+      Provider::Fireworks => get_fireworks_model(model_id),
+      Provider::Bedrock => get_bedrock_model(model_id),
     };
     cformat!("<bold>🧠 {} {}</bold>", provider, full_model_id)
   }
 }
 
-fn get_secrets_path_str() -> String {
+/// Path of a custom config file.
+/// Precedence: `--config` flag > `CAI_CONFIG` env var
+/// (`None` if the default file should be used)
+fn resolve_config_path(
+  config_flag: Option<&str>,
+  config_env: Option<String>,
+) -> Option<String> {
+  config_flag
+    .map(str::to_string)
+    .or(config_env)
+    .filter(|path| !path.is_empty())
+}
+
+/// Path of the config file.
+/// Precedence: `--config` flag > `CAI_CONFIG` env var > XDG config dir
+/// (Only the default file is created if it doesn't exist yet)
+fn get_secrets_path_str(opts: &ExecOptions) -> String {
+  if let Some(path) = resolve_config_path(
+    opts.config_path.as_deref(),
+    env::var("CAI_CONFIG").ok(),
+  ) {
+    return path;
+  }
+
   let xdg_dirs = BaseDirectories::with_prefix("cai").unwrap();
   let secrets_path = xdg_dirs
     .place_config_file("secrets.yaml")
@@ -232,284 +671,2747 @@ fn get_secrets_path_str() -> String {
   secrets_path.to_str().unwrap().to_string()
 }
 
-pub fn get_full_config(
-  secrets_path_str: &str,
-) -> Result<
-  HashMap<std::string::String, std::string::String>,
-  config::ConfigError,
-> {
-  let config = Config::builder()
-    .set_default(
-      "anthropic_api_key",
-      env::var("ANTHROPIC_API_KEY").unwrap_or_default(),
-    )?
-    .set_default(
-      "openai_api_key",
-      env::var("OPENAI_API_KEY").unwrap_or_default(),
-    )?
-    .set_default(
-      "groq_api_key", //
-      env::var("GROQ_API_KEY").unwrap_or_default(),
-    )?
-    .add_source(config::File::with_name(&secrets_path_str))
-    .add_source(config::Environment::with_prefix("CAI"))
-    .build()
-    .unwrap();
+fn get_last_model_path() -> Option<std::path::PathBuf> {
+  BaseDirectories::with_prefix("cai")
+    .ok()?
+    .place_data_file("last_model")
+    .ok()
+}
 
-  Ok(
-    config //
-      .try_deserialize::<HashMap<String, String>>()
-      .unwrap(),
-  )
+/// Record the model in the state file, so `cai again` can reuse it
+fn save_last_model(provider: &Provider, model_id: &str) {
+  let model_str = format!("{}/{model_id}", provider.to_string().to_lowercase());
+  let result = get_last_model_path()
+    .ok_or("Couldn't create data directory".to_string())
+    .and_then(|path| {
+      std::fs::write(path, model_str).map_err(|err| err.to_string())
+    });
+  if let Err(err) = result {
+    eprintln!("Warning: Couldn't save the last used model: {err}");
+  }
 }
 
-fn get_http_req(
-  optional_model: &Option<&Model>,
-  secrets_path_str: &str,
-  full_config: &HashMap<String, String>,
-) -> Result<(String, AiRequest), std::string::String> {
-  match optional_model {
-    Some(model) => {
-      let used_model = get_used_model(&model);
-      get_api_request(&full_config, &secrets_path_str, model)
-        .map(|req| (used_model, req))
-    }
-    // Use the first provider that has an API key
-    None => {
-      let req =
-        get_api_request(&full_config, &secrets_path_str, &Default::default())
-          .or(get_api_request(
-            &full_config,
-            &secrets_path_str,
-            &Model::Model(Provider::Groq, "llama-3.1-8b-instant".to_owned()),
-          ))
-          .or(get_api_request(
-            &full_config,
-            &secrets_path_str,
-            &Model::Model(Provider::OpenAI, "gpt-4o-mini".to_string()),
-          ))?;
-      let used_model = get_used_model(
-        &Model::Model(req.provider.clone(), req.model.clone()), //
-      );
-      Ok((used_model, req))
+/// Load the last used model from the state file
+pub fn load_last_model() -> Result<Model, String> {
+  get_last_model_path()
+    .and_then(|path| std::fs::read_to_string(path).ok())
+    .ok_or(
+      "No last used model was saved yet. \
+      Set `remember_last_model: true` in the config to enable it."
+        .to_string(),
+    )?
+    .trim()
+    .parse::<Model>()
+}
+
+/// URL which lists the models of the provider.
+/// Used for health checks, as it doesn't cost any tokens.
+fn get_models_url(http_req: &AiRequest) -> String {
+  match (&http_req.provider, &http_req.aws_credentials) {
+    (Provider::Anthropic, _) => http_req.url.replace("/messages", "/models"),
+    // Also available in old versions without the OpenAI compatible API
+    (Provider::Ollama, _) => {
+      ollama::get_native_url(&http_req.url).replace("/api/chat", "/api/tags")
     }
+    (Provider::Bedrock, Some(credentials)) => format!(
+      "https://bedrock.{}.amazonaws.com/foundation-models",
+      credentials.region
+    ),
+    _ => http_req.url.replace("/chat/completions", "/models"),
   }
 }
 
-fn get_req_body_obj(
+/// Result of the health check of a provider
+#[derive(Debug, PartialEq)]
+pub enum HealthStatus {
+  Ok,
+  NoApiKey,
+  Unauthorized,
+  Unreachable(String),
+  Failed(String),
+}
+
+/// Check if the provider is reachable and accepts the API key
+async fn check_provider(
   opts: &ExecOptions,
-  http_req: &AiRequest,
-  user_input: &str,
-) -> Value {
-  // Handle case where input is already a complete JSON string
-  match serde_json::from_str(user_input) {
-    Ok(json) => return json,
-    Err(_) => (),
-  }
+  full_config: &HashMap<String, String>,
+  secrets_path_str: &str,
+  provider: Provider,
+  timeout: std::time::Duration,
+) -> (HealthStatus, u128) {
+  let model = Model::Model(provider, "".to_string());
+  let Ok(http_req) = get_api_request(full_config, secrets_path_str, &model)
+  else {
+    return (HealthStatus::NoApiKey, 0);
+  };
+  let check_req = AiRequest {
+    url: get_models_url(&http_req),
+    ..http_req.clone()
+  };
 
-  let mut map = Map::new();
-  map.insert("model".to_string(), Value::String(http_req.model.clone()));
-  map.insert(
-    "max_tokens".to_string(),
-    Value::Number(http_req.max_tokens.into()),
-  );
+  let start = Instant::now();
+  let result = async {
+    let client = get_client(opts, &http_req)?;
+    let mut request = build_request(client, opts, &check_req, &Value::Null)?;
+    *request.timeout_mut() = Some(timeout);
+    Ok::<_, Box<dyn Error + Send + Sync>>(client.execute(request).await)
+  }
+  .await;
+  let elapsed_ms = start.elapsed().as_millis();
 
-  if opts.is_json {
-    match http_req.provider {
-      Provider::OpenAI | Provider::Groq | Provider::Ollama => {
-        map.insert(
-          "response_format".to_string(),
-          Value::Object(Map::from_iter([(
-            "type".to_string(),
-            Value::String("json_object".to_string()),
-          )])),
-        );
-      }
-      provider => {
-        eprintln!(
-          "{}",
-          cformat!("<red>ERROR: {provider} doesn't support a JSON mode</red>",)
-        );
-        std::process::exit(1);
-      }
+  let status = match result {
+    Err(err) => HealthStatus::Failed(err.to_string()),
+    Ok(Err(err)) if err.is_timeout() => {
+      HealthStatus::Unreachable("timed out".to_string())
     }
+    Ok(Err(err)) if err.is_connect() => {
+      HealthStatus::Unreachable("connection failed".to_string())
+    }
+    Ok(Err(err)) => HealthStatus::Unreachable(err.to_string()),
+    Ok(Ok(resp))
+      if resp.status() == StatusCode::UNAUTHORIZED
+        || resp.status() == StatusCode::FORBIDDEN =>
+    {
+      HealthStatus::Unauthorized
+    }
+    Ok(Ok(resp)) if !resp.status().is_success() => {
+      HealthStatus::Failed(format!("HTTP {}", resp.status()))
+    }
+    Ok(Ok(_)) => HealthStatus::Ok,
+  };
+  (status, elapsed_ms)
+}
+
+/// Format the results of the health checks as a table
+fn fmt_health_table(results: &[(Provider, HealthStatus, u128)]) -> String {
+  let mut table =
+    cformat!("<bold>{:<12}{:<36}Latency</bold>\n", "Provider", "Status");
+  for (provider, status, elapsed_ms) in results {
+    let status_str = match status {
+      HealthStatus::Ok => "✓ OK".to_string(),
+      HealthStatus::NoApiKey => "- No API key".to_string(),
+      HealthStatus::Unauthorized => "✗ API key was rejected".to_string(),
+      HealthStatus::Unreachable(reason) => format!("✗ Unreachable ({reason})"),
+      HealthStatus::Failed(reason) => format!("✗ {reason}"),
+    };
+    let latency = match status {
+      HealthStatus::NoApiKey => String::new(),
+      _ => format!("{elapsed_ms} ms"),
+    };
+    let row = format!("{:<12}{status_str:<36}{latency}", provider.to_string())
+      .trim_end()
+      .to_string();
+    table += &match status {
+      HealthStatus::Ok => cformat!("<green>{row}</green>\n"),
+      HealthStatus::NoApiKey => cformat!("<dim>{row}</dim>\n"),
+      _ => cformat!("<red>{row}</red>\n"),
+    };
   }
+  table
+}
 
-  if opts.json_schema.is_some() {
-    match http_req.provider {
-      Provider::OpenAI | Provider::Ollama => {
-        let mut json_schema = Map::new();
-        json_schema.insert("type".to_string(), "json_schema".into());
-        json_schema.insert(
-          "json_schema".to_string(),
-          opts.json_schema.clone().unwrap(), //
-        );
+/// Check all providers concurrently and print the results as a table.
+/// Returns false if a remote provider with an API key failed
+/// (local servers are often not running on purpose).
+pub async fn run_doctor(
+  opts: &ExecOptions,
+  timeout: std::time::Duration,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+  let secrets_path_str = get_secrets_path_str(opts);
+  let full_config = get_full_config(&secrets_path_str)?;
+  let statuses = futures::future::join_all(PROVIDERS.iter().map(|provider| {
+    check_provider(opts, &full_config, &secrets_path_str, *provider, timeout)
+  }))
+  .await;
+  let results: Vec<(Provider, HealthStatus, u128)> = PROVIDERS
+    .iter()
+    .zip(statuses)
+    .map(|(provider, (status, elapsed_ms))| (*provider, status, elapsed_ms))
+    .collect();
 
-        map.insert("response_format".to_string(), Value::Object(json_schema));
-      }
-      provider => {
-        eprintln!(
-          "{}",
-          cformat!(
-            "<red>ERROR: {provider} doesn't support a JSON schema mode</red>",
-          )
-        );
-        std::process::exit(1);
-      }
-    }
-  }
+  print!("{}", fmt_stdout(opts, &fmt_health_table(&results)));
 
-  map.insert(
-    "messages".to_string(),
-    Value::Array(vec![Value::Object(Map::from_iter([
-      ("role".to_string(), "user".into()),
-      ("content".to_string(), Value::String(user_input.to_string())),
-    ]))]),
-  );
+  Ok(results.iter().all(|(provider, status, _)| {
+    matches!(status, HealthStatus::Ok | HealthStatus::NoApiKey)
+      || matches!(provider, Provider::Llamafile | Provider::Ollama)
+  }))
+}
 
-  Value::Object(map)
+/// Check if an API key is configured for the provider
+/// (Local providers don't need one)
+pub fn has_api_key(
+  full_config: &HashMap<String, String>,
+  provider: &Provider,
+) -> bool {
+  let model = Model::Model(*provider, "".to_string());
+  get_api_request(full_config, "", &model).is_ok()
 }
 
-async fn exec_request(
-  http_req: &AiRequest,
-  req_body_obj: &Value,
-) -> Result<Response, reqwest::Error> {
-  let client = reqwest::Client::new();
-  let req_base = client.post(http_req.url.clone()).json(&req_body_obj);
-  let req = match http_req.provider {
-    Provider::Anthropic => req_base
-      .header("anthropic-version", "2023-06-01")
-      .header("x-api-key", &http_req.api_key),
-    _ => req_base.bearer_auth(&http_req.api_key),
-  };
-  req.send().await
+/// Models of all configured providers as (friendly name, model) pairs
+pub fn get_model_choices(opts: &ExecOptions) -> Vec<(String, Model)> {
+  let provider_mappings = [
+    (Provider::Anthropic, ANTHROPIC_MODEL_MAPPING),
+    (Provider::OpenAI, OPENAI_MODEL_MAPPING),
+    (Provider::Groq, GROQ_MODEL_MAPPING),
+    (Provider::Ollama, OLLAMA_MODEL_MAPPING),
+    (Provider::Together, TOGETHER_MODEL_MAPPING),
+    (Provider::Fireworks, FIREWORKS_MODEL_MAPPING),
+    (Provider::Bedrock, BEDROCK_MODEL_MAPPING),
+  ];
+
+  let full_config =
+    get_full_config(&get_secrets_path_str(opts)).unwrap_or_default();
+  let mut choices: Vec<(String, Model)> = provider_mappings
+    .iter()
+    .filter(|(provider, _)| has_api_key(&full_config, provider))
+    .flat_map(|(provider, mapping)| {
+      mapping.iter().map(move |(alias, model_id)| {
+        (
+          format!("{provider} {alias} ({model_id})"),
+          Model::Model(*provider, alias.to_string()),
+        )
+      })
+    })
+    .collect();
+  choices.push((
+    "Llamafile (local server)".to_string(),
+    Model::Model(Provider::Llamafile, "".to_string()),
+  ));
+
+  choices
 }
 
-pub async fn exec_tool(
-  optional_model: &Option<&Model>,
-  opts: &ExecOptions,
-  user_input: &str,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-  let start = Instant::now();
-  let secrets_path_str = get_secrets_path_str();
-  let full_config = get_full_config(&secrets_path_str)?;
-  let (used_model, http_req) =
-    get_http_req(optional_model, &secrets_path_str, &full_config)?;
+/// All config keys which are read by cai
+/// (besides `{provider}_default_model`, `{provider}_model_prefix`/`_suffix`,
+/// `{provider}_max_tokens`, and `headers.{provider}.*`)
+const CONFIG_KEYS: &[&str] = &[
+  "anthropic_api_key",
+  "anthropic_beta",
+  "anthropic_oauth_token",
+  "anthropic_version",
+  "aws_access_key_id",
+  "aws_region",
+  "aws_secret_access_key",
+  "aws_session_token",
+  "bat_theme",
+  "cache",
+  "cache_ttl",
+  "changelog_model",
+  "confirm_cost",
+  "date_format",
+  "default_model",
+  "fireworks_api_key",
+  "groq_api_key",
+  "ocr_model",
+  "openai_api_key",
+  "openai_org",
+  "openai_project",
+  "pool_size",
+  "proxy",
+  "remember_last_model",
+  "rename_model",
+  "retries",
+  "retry_jitter",
+  "retry_max_ms",
+  "sentiment_model",
+  "together_api_key",
+  "with_date",
+  "wrap_width",
+];
 
-  // This is checked here, so that the missing API key message comes first
-  if user_input.is_empty() {
-    Err("No prompt was provided")?;
-  }
+/// Check if cai reads the config key
+fn is_known_config_key(key: &str) -> bool {
+  CONFIG_KEYS.contains(&key)
+    || PROVIDERS.iter().any(|provider| {
+      let provider = provider.to_string().to_lowercase();
+      key == format!("{provider}_default_model")
+        || key == format!("{provider}_model_prefix")
+        || key == format!("{provider}_model_suffix")
+        || key == format!("{provider}_max_tokens")
+        || key.starts_with(&format!("headers.{provider}."))
+    })
+}
 
-  let req_body_obj =
-    get_req_body_obj(&opts, &http_req, &user_input.to_string());
+/// Check if the YAML line continues the value of the previous key
+/// (e.g. lines of a `|` block scalar, a nested map, or a list)
+fn is_yaml_continuation(line: &str) -> bool {
+  line.starts_with([' ', '\t']) || line == "-" || line.starts_with("- ")
+}
 
-  let resp = exec_request(&http_req, &req_body_obj).await?;
-  let elapsed_time: String = start.elapsed().as_millis().to_string();
+/// Set a top-level key in the YAML text while keeping all other lines
+/// (including comments) unchanged.
+/// The value is always quoted so it's never parsed as another type
+/// and the lines of the old value are removed.
+fn set_yaml_key(yaml: &str, key: &str, value: &str) -> String {
+  let new_line = format!("{key}: {}", Value::String(value.to_string()));
 
-  if !&resp.status().is_success() {
-    let resp_json = resp.json::<Value>().await?;
-    let resp_formatted = serde_json::to_string_pretty(&resp_json).unwrap();
-    Err(cformat!(
-      "<bold>⏱️ {: >5} ms</bold> | {used_model}\n\
-      \n{resp_formatted}",
-      elapsed_time,
-    ))?;
-  } else {
-    let msg = match http_req.provider {
-      Provider::Anthropic => {
-        let anth_response = resp.json::<AnthropicAiResponse>().await?;
-        anth_response.content[0].text.clone()
+  let mut is_replaced = false;
+  let mut is_in_old_value = false;
+  let mut blank_lines = vec![];
+  let mut lines = vec![];
+  for line in yaml.lines() {
+    if is_in_old_value {
+      if line.trim().is_empty() {
+        blank_lines.push(line.to_string());
+        continue;
       }
-      _ => {
-        let ai_response = resp.json::<AiResponse>().await?;
-        ai_response.choices[0].message.content.clone()
+      if is_yaml_continuation(line) {
+        blank_lines.clear();
+        continue;
       }
-    };
-
-    if opts.is_raw {
-      println!("{}", msg);
+      is_in_old_value = false;
+      lines.append(&mut blank_lines);
+    }
+    let is_key_line = line
+      .strip_prefix(key)
+      .is_some_and(|rest| rest.trim_start().starts_with(':'));
+    if is_key_line && !is_replaced {
+      is_replaced = true;
+      is_in_old_value = true;
+      lines.push(new_line.clone());
     } else {
-      cprintln!("<bold>⏱️{: >5} ms</bold> | {used_model}\n", elapsed_time,);
-      highlight::text_via_bat(&msg);
-      println!("\n");
+      lines.push(line.to_string());
     }
   }
-  Ok(())
+  lines.append(&mut blank_lines);
+  if !is_replaced {
+    lines.push(new_line);
+  }
+
+  lines.join("\n") + "\n"
 }
 
-pub async fn submit_prompt(
-  optional_model: &Option<&Model>,
-  opts: &ExecOptions,
-  user_input: &str,
-) {
-  // Necessary to wrap the execution function,
-  // because a `main` function that returns a `Result` quotes any errors.
-  match exec_tool(optional_model, &opts, user_input).await {
-    Ok(_) => (),
-    Err(err) => {
-      let model_str = optional_model
-        .as_ref()
-        .map(|x| x.to_string())
-        .unwrap_or("".to_string());
-      eprintln!(
-        "{}",
-        cformat!("<bold>🧠 {model_str}</bold><red>\nERROR:\n{}</red>\n", err)
-      );
-      std::process::exit(1);
-    }
+/// Built-in default model id of the provider
+fn get_builtin_default_model(provider: &Provider) -> &'static str {
+  match provider {
+    Provider::Anthropic => "claude-3-5-sonnet-latest",
+    Provider::Groq => "llama-3.1-8b-instant",
+    Provider::OpenAI => "gpt-4o-mini",
+    Provider::Llamafile => "",
+    Provider::Ollama => "llama3",
+    Provider::Together => "llama",
+    Provider::Fireworks => "llama",
+    Provider::Bedrock => "sonnet",
   }
 }
 
-pub async fn generate_changelog(
+/// Resolve the model of a provider subcommand.
+/// Precedence: model argument > `{provider}_default_model` config
+/// > built-in default of the provider
+pub fn get_provider_model(
   opts: &ExecOptions,
-  commit_hash: &str,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-  let output = std::process::Command::new("git")
-    .args(&[
-      "log",
-      "--date=short",
-      "--pretty=format:%cd - %s%d", // date - subject (refs)
-      &format!("{}..HEAD", commit_hash),
-    ])
-    .output()
-    .expect("Failed to execute git command");
-
-  let changelog = String::from_utf8_lossy(&output.stdout);
-
-  let prompt = format!(
-    "Summarize the following git commit log into a concise markdown changelog.\n
-    Only include user-facing changes (i.e. no code refactorings or similar).\n
-    Use the tags to group the changes, and if there are no tags use the dates.\n
-    Include the date and the tag in the header.\n
-    Don't sub-categorize the changes, just list them.\n
-    Insert a blank line after each header and sub-header.\n
-    \n\n{changelog}"
-  );
+  provider: Provider,
+  model_id: Option<String>,
+) -> Model {
+  let model_id = model_id.unwrap_or_else(|| {
+    let config_key =
+      format!("{}_default_model", provider.to_string().to_lowercase());
+    get_full_config(&get_secrets_path_str(opts))
+      .ok()
+      .and_then(|full_config| full_config.get(&config_key).cloned())
+      .filter(|model_id| !model_id.is_empty())
+      .unwrap_or(get_builtin_default_model(&provider).to_string())
+  });
 
-  let model = Model::Model(Provider::OpenAI, "gpt-4o".to_string());
+  Model::Model(provider, model_id)
+}
 
-  exec_tool(&Some(&model), &opts, &prompt).await
+/// Directory of the user defined prompt templates
+pub fn get_templates_dir() -> std::path::PathBuf {
+  BaseDirectories::with_prefix("cai")
+    .unwrap()
+    .get_config_home()
+    .join("templates")
 }
 
-#[derive(Deserialize)]
-pub struct FileAnalysis {
-  pub description: String,
-  pub timestamp: Option<String>,
+/// Path of the config file
+pub fn get_config_path(opts: &ExecOptions) -> String {
+  get_secrets_path_str(opts)
 }
 
-pub async fn analyze_file_content(
+/// Set the value of a config key in the config file.
+/// Returns a warning if cai doesn't read the key.
+pub fn set_config_value(
   opts: &ExecOptions,
-  file_path: &str,
-) -> Result<FileAnalysis, Box<dyn Error + Send + Sync>> {
-  let content = if file_path.to_lowercase().ends_with(".pdf") {
-    pdf_extract::extract_text(file_path)
-      .map_err(|e| format!("Failed to extract PDF text: {}", e))?
-  } else {
-    std::fs::read_to_string(file_path)?
-  };
+  key: &str,
+  value: &str,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+  if key.contains('.') {
+    Err("Nested keys can't be set, edit the config file instead")?;
+  }
+  let secrets_path_str = get_secrets_path_str(opts);
+  let yaml = std::fs::read_to_string(&secrets_path_str).unwrap_or_default();
+  std::fs::write(&secrets_path_str, set_yaml_key(&yaml, key, value))?;
 
-  let prompt = format!(
+  Ok((!is_known_config_key(key)).then(|| {
+    format!("\"{key}\" is not a known config key and will be ignored")
+  }))
+}
+
+/// Config keys whose defaults are read from generic environment variables
+const ENV_DEFAULT_KEYS: [&str; 9] = [
+  "anthropic_api_key",
+  "openai_api_key",
+  "groq_api_key",
+  "together_api_key",
+  "fireworks_api_key",
+  "aws_region",
+  "aws_access_key_id",
+  "aws_secret_access_key",
+  "aws_session_token",
+];
+
+/// Check if the config key holds a secret
+fn is_secret_key(key: &str) -> bool {
+  key.ends_with("_key") || key.ends_with("_token")
+}
+
+/// Mask a secret value (e.g. `sk-…abcd`) so it can be printed
+fn mask_secret(key: &str, value: &str) -> String {
+  if !is_secret_key(key) {
+    return value.to_string();
+  }
+
+  let chars: Vec<char> = value.chars().collect();
+  if chars.len() < 12 {
+    "…".to_string()
+  } else {
+    let prefix: String = chars[..3].iter().collect();
+    let suffix: String = chars[chars.len() - 4..].iter().collect();
+    format!("{prefix}…{suffix}")
+  }
+}
+
+/// Get the source which provided the value of a config key.
+/// Precedence: `CAI_` env var > config file > generic env var
+fn get_config_source(
+  key: &str,
+  file_config: &HashMap<String, String>,
+  secrets_path_str: &str,
+) -> String {
+  let cai_env_var = format!("CAI_{}", key.to_uppercase());
+  if env::var(&cai_env_var).is_ok() {
+    cai_env_var
+  } else if file_config.contains_key(key) {
+    secrets_path_str.to_string()
+  } else if ENV_DEFAULT_KEYS.contains(&key) {
+    key.to_uppercase()
+  } else {
+    "default".to_string()
+  }
+}
+
+/// All set config keys with their (masked) values and their sources
+pub fn get_config_listing(
+  opts: &ExecOptions,
+) -> Result<Vec<(String, String, String)>, config::ConfigError> {
+  let secrets_path_str = get_secrets_path_str(opts);
+  let full_config = get_full_config(&secrets_path_str)?;
+  let file_config = flatten_config(
+    Config::builder()
+      .add_source(config::File::with_name(&secrets_path_str))
+      .build()?
+      .try_deserialize::<HashMap<String, Value>>()?,
+  );
+
+  let mut listing: Vec<(String, String, String)> = full_config
+    .into_iter()
+    .filter(|(_, value)| !value.is_empty())
+    .map(|(key, value)| {
+      let source = get_config_source(&key, &file_config, &secrets_path_str);
+      (key.clone(), mask_secret(&key, &value), source)
+    })
+    .collect();
+  listing.sort();
+
+  Ok(listing)
+}
+
+/// Format the config listing as aligned groups of credentials and settings
+/// below the configuration status of the providers
+pub fn fmt_config_listing(
+  config_path: &str,
+  listing: &[(String, String, String)],
+  provider_statuses: &[(Provider, bool)],
+) -> String {
+  let mut output = format!("Config file: {config_path}\n\n");
+
+  let configured_count = provider_statuses
+    .iter()
+    .filter(|(_, is_configured)| *is_configured)
+    .count();
+  output += &cformat!(
+    "<bold>Providers</bold> ({configured_count}/{} configured)\n",
+    provider_statuses.len()
+  );
+  for (provider, is_configured) in provider_statuses {
+    output += &if *is_configured {
+      cformat!("  <green>✓ {provider}</green>\n")
+    } else {
+      cformat!("  <dim>✗ {provider} (no API key)</dim>\n")
+    };
+  }
+
+  let key_width = listing.iter().map(|(key, ..)| key.len()).max();
+  for (title, are_credentials) in [("Credentials", true), ("Settings", false)] {
+    let entries: Vec<_> = listing
+      .iter()
+      .filter(|(key, ..)| {
+        (is_secret_key(key) || key == "aws_access_key_id") == are_credentials
+      })
+      .collect();
+    if entries.is_empty() {
+      continue;
+    }
+    output += &cformat!("\n<bold>{title}</bold>\n");
+    for (key, value, source) in entries {
+      let padded_key = format!("{key:<0$}", key_width.unwrap_or_default());
+      output += &cformat!(
+        "  <blue>{padded_key}</blue>  {value}  <dim>(from {source})</dim>\n"
+      );
+    }
+  }
+
+  output
+}
+
+pub fn get_full_config(
+  secrets_path_str: &str,
+) -> Result<
+  HashMap<std::string::String, std::string::String>,
+  config::ConfigError,
+> {
+  let config = ENV_DEFAULT_KEYS
+    .iter()
+    .try_fold(Config::builder(), |builder, key| {
+      builder
+        .set_default(*key, env::var(key.to_uppercase()).unwrap_or_default())
+    })?
+    .add_source(config::File::with_name(secrets_path_str))
+    .add_source(
+      config::Environment::with_prefix("CAI")
+        .source(Some(get_config_env_vars(env::vars()))),
+    )
+    .build()
+    .map_err(|err| {
+      config::ConfigError::Message(format!(
+        "Couldn't load the config file \"{secrets_path_str}\":\n{err}"
+      ))
+    })?;
+
+  let config_map =
+    config
+      .try_deserialize::<HashMap<String, Value>>()
+      .map_err(|err| {
+        config::ConfigError::Message(format!(
+          "Invalid config file \"{secrets_path_str}\": {err}\n\
+        It must contain key-value pairs like `openai_api_key: sk-…`"
+        ))
+      })?;
+
+  flatten_config(config_map)
+    .into_iter()
+    .map(|(key, value)| {
+      let expanded = expand_env_vars(&value, |var| env::var(var).ok())
+        .map_err(|var| {
+          config::ConfigError::Message(format!(
+            "Environment variable \"{var}\" referenced by \"{key}\" \
+            is not set"
+          ))
+        })?;
+      Ok((key, expanded))
+    })
+    .collect()
+}
+
+/// Environment variables which can set config values
+/// (`CAI_CONFIG` selects the config file and isn't a value itself)
+fn get_config_env_vars(
+  vars: impl Iterator<Item = (String, String)>,
+) -> config::Map<String, String> {
+  vars.filter(|(name, _)| name != "CAI_CONFIG").collect()
+}
+
+/// Expand `${VAR}` references with the values from `get_var`.
+/// Returns the name of the variable if it can't be resolved.
+fn expand_env_vars(
+  value: &str,
+  get_var: impl Fn(&str) -> Option<String>,
+) -> Result<String, String> {
+  let mut expanded = String::with_capacity(value.len());
+  let mut rest = value;
+
+  while let Some(start) = rest.find("${") {
+    let Some(end) = rest[start..].find('}') else {
+      break;
+    };
+    let var = &rest[start + 2..start + end];
+    expanded.push_str(&rest[..start]);
+    expanded.push_str(&get_var(var).ok_or_else(|| var.to_string())?);
+    rest = &rest[start + end + 1..];
+  }
+  expanded.push_str(rest);
+
+  Ok(expanded)
+}
+
+/// Flatten nested config tables into dot separated keys
+/// (e.g. `headers.openai.X-Title`) with string values
+fn flatten_config(
+  config_map: HashMap<String, Value>,
+) -> HashMap<String, String> {
+  let mut flat_map = HashMap::new();
+
+  for (key, value) in config_map {
+    match value {
+      Value::Object(obj) => {
+        let nested_map = flatten_config(obj.into_iter().collect());
+        for (nested_key, nested_value) in nested_map {
+          flat_map.insert(format!("{key}.{nested_key}"), nested_value);
+        }
+      }
+      Value::String(text) => {
+        flat_map.insert(key, text);
+      }
+      Value::Null => {}
+      value => {
+        flat_map.insert(key, value.to_string());
+      }
+    }
+  }
+
+  flat_map
+}
+
+fn get_http_req(
+  optional_model: &Option<&Model>,
+  secrets_path_str: &str,
+  full_config: &HashMap<String, String>,
+) -> Result<(String, AiRequest), std::string::String> {
+  match optional_model {
+    Some(model) => {
+      let used_model = get_used_model(model);
+      get_api_request(full_config, secrets_path_str, model)
+        .map(|req| (used_model, req))
+    }
+    None => {
+      let default_model = full_config
+        .get("default_model")
+        .filter(|model_str| !model_str.is_empty())
+        .and_then(|model_str| match model_str.parse::<Model>() {
+          Ok(model) => Some(model),
+          Err(err) => {
+            eprintln!("Warning: Ignoring invalid default_model: {err}");
+            None
+          }
+        });
+
+      if let Some(model) = default_model {
+        return get_http_req(&Some(&model), secrets_path_str, full_config);
+      }
+
+      // Use the first provider that has an API key
+      let req =
+        get_api_request(full_config, secrets_path_str, &Default::default())
+          .or(get_api_request(
+            full_config,
+            secrets_path_str,
+            &Model::Model(Provider::Groq, "llama-3.1-8b-instant".to_owned()),
+          ))
+          .or(get_api_request(
+            full_config,
+            secrets_path_str,
+            &Model::Model(Provider::OpenAI, "gpt-4o-mini".to_string()),
+          ))?;
+      let used_model = get_used_model(
+        &Model::Model(req.provider, req.model.clone()), //
+      );
+      Ok((used_model, req))
+    }
+  }
+}
+
+/// Remove all ANSI escape sequences (e.g. colors) from the text
+fn strip_ansi_codes(text: &str) -> String {
+  let mut stripped = String::with_capacity(text.len());
+  let mut chars = text.chars().peekable();
+
+  while let Some(char) = chars.next() {
+    if char == '\x1b' && chars.peek() == Some(&'[') {
+      // Skip everything until the final byte of the escape sequence
+      for seq_char in chars.by_ref() {
+        if ('@'..='~').contains(&seq_char) && seq_char != '[' {
+          break;
+        }
+      }
+    } else {
+      stripped.push(char);
+    }
+  }
+
+  stripped
+}
+
+/// Text replacements for the emoji used in the metadata output
+const EMOJI_REPLACEMENTS: [(&str, &str); 3] =
+  [("⏱️", "Time:"), ("🧠", "Model:"), ("⏭️ ", "")];
+
+/// Apply the output options (e.g. `--no-color`) to a formatted string
+/// which is printed to stderr
+pub fn fmt_output(opts: &ExecOptions, text: &str) -> String {
+  fmt_for_stream(opts, text, std::io::stderr().is_terminal())
+}
+
+/// Apply the output options to a formatted string which is printed to stdout
+pub fn fmt_stdout(opts: &ExecOptions, text: &str) -> String {
+  fmt_for_stream(opts, text, std::io::stdout().is_terminal())
+}
+
+/// Colors are only kept if the stream is a terminal.
+/// (`cformat!` adds the colors at compile time,
+/// so they have to be removed instead of not being added.)
+fn fmt_for_stream(opts: &ExecOptions, text: &str, is_terminal: bool) -> String {
+  let mut output = if opts.no_color || !is_terminal {
+    strip_ansi_codes(text)
+  } else {
+    text.to_string()
+  };
+
+  if opts.no_emoji {
+    for (emoji, replacement) in EMOJI_REPLACEMENTS {
+      output = output.replace(emoji, replacement);
+    }
+  }
+
+  output
+}
+
+/// OpenAI's reasoning models expect `developer` instead of `system` messages
+fn get_default_system_role(http_req: &AiRequest) -> &str {
+  let is_reasoning_model = ["o1", "o3", "o4"]
+    .iter()
+    .any(|prefix| http_req.model.starts_with(prefix));
+
+  match http_req.provider {
+    Provider::OpenAI if is_reasoning_model => "developer",
+    _ => "system",
+  }
+}
+
+/// Maximum number of stop sequences the provider accepts
+/// (`None` if there is no documented limit)
+fn get_max_stop_sequences(provider: &Provider) -> Option<usize> {
+  match provider {
+    Provider::OpenAI | Provider::Groq | Provider::Bedrock => Some(4),
+    _ => None,
+  }
+}
+
+/// Check if the provider accepts a seed for deterministic sampling
+fn supports_seed(provider: &Provider) -> bool {
+  !matches!(provider, Provider::Anthropic | Provider::Bedrock)
+}
+
+/// Get warnings about options which are ignored for the request
+fn get_request_warnings(
+  opts: &ExecOptions,
+  http_req: &AiRequest,
+) -> Vec<String> {
+  let mut warnings = vec![];
+  if opts.seed.is_some() && !supports_seed(&http_req.provider) {
+    warnings.push(format!(
+      "{} doesn't support a seed, ignoring it",
+      http_req.provider
+    ));
+  }
+  warnings
+}
+
+fn get_req_body_obj(
+  opts: &ExecOptions,
+  http_req: &AiRequest,
+  user_input: &str,
+) -> Result<Value, String> {
+  // Handle case where input is already a complete JSON string
+  if let Ok(json) = serde_json::from_str(user_input) {
+    return Ok(json);
+  }
+
+  let mut map = Map::new();
+  match http_req.provider {
+    // Bedrock's Converse API gets the model from the URL
+    Provider::Bedrock => {
+      map.insert(
+        "inferenceConfig".to_string(),
+        json!({ "maxTokens": http_req.max_tokens }),
+      );
+    }
+    _ => {
+      map.insert(
+        "model".to_string(),
+        Value::String(http_req.get_gateway_model()),
+      );
+      map.insert(
+        "max_tokens".to_string(),
+        Value::Number(http_req.max_tokens.into()),
+      );
+    }
+  }
+
+  if opts.is_json {
+    match http_req.provider {
+      Provider::OpenAI
+      | Provider::Groq
+      | Provider::Ollama
+      | Provider::Together
+      | Provider::Fireworks => {
+        map.insert(
+          "response_format".to_string(),
+          Value::Object(Map::from_iter([(
+            "type".to_string(),
+            Value::String("json_object".to_string()),
+          )])),
+        );
+      }
+      provider => {
+        Err(format!("{provider} doesn't support a JSON mode"))?;
+      }
+    }
+  }
+
+  if let Some(json_schema) = &opts.json_schema {
+    match http_req.provider {
+      Provider::OpenAI | Provider::Ollama => {
+        map.insert(
+          "response_format".to_string(),
+          json!({ "type": "json_schema", "json_schema": json_schema }),
+        );
+      }
+      // Anthropic has no JSON schema mode,
+      // but forcing the use of a tool has the same effect.
+      // Forcing a tool isn't allowed with thinking,
+      // so the model is only offered the tool then.
+      Provider::Anthropic => {
+        let tool_choice = if opts.thinking_budget.is_some() {
+          json!({ "type": "auto" })
+        } else {
+          json!({ "type": "tool", "name": JSON_SCHEMA_TOOL })
+        };
+        map.insert(
+          "tools".to_string(),
+          json!([{
+            "name": JSON_SCHEMA_TOOL,
+            "description": "Respond with JSON matching the schema",
+            "input_schema": json_schema["schema"],
+          }]),
+        );
+        map.insert("tool_choice".to_string(), tool_choice);
+      }
+      provider => {
+        Err(format!("{provider} doesn't support a JSON schema mode"))?;
+      }
+    }
+  }
+
+  if let Some(budget_tokens) = opts.thinking_budget {
+    match http_req.provider {
+      Provider::Anthropic => {
+        map.insert(
+          "thinking".to_string(),
+          json!({ "type": "enabled", "budget_tokens": budget_tokens }),
+        );
+        // The thinking budget is part of the maximum number of tokens
+        if budget_tokens >= http_req.max_tokens {
+          map.insert(
+            "max_tokens".to_string(),
+            Value::Number(
+              budget_tokens.saturating_add(http_req.max_tokens).into(),
+            ),
+          );
+        }
+      }
+      provider => {
+        Err(format!("{provider} doesn't support thinking"))?;
+      }
+    }
+  }
+
+  if !opts.stop_sequences.is_empty() {
+    let provider = http_req.provider;
+    if let Some(max_count) = get_max_stop_sequences(&provider) {
+      if opts.stop_sequences.len() > max_count {
+        Err(format!(
+          "{provider} supports at most {max_count} stop sequences, \
+          but {} were given",
+          opts.stop_sequences.len()
+        ))?;
+      }
+    }
+    match provider {
+      Provider::Anthropic => {
+        map.insert("stop_sequences".to_string(), json!(opts.stop_sequences));
+      }
+      Provider::Bedrock => {
+        map["inferenceConfig"]["stopSequences"] = json!(opts.stop_sequences);
+      }
+      _ => {
+        map.insert("stop".to_string(), json!(opts.stop_sequences));
+      }
+    }
+  }
+
+  // Unsupported seeds are reported by `get_request_warnings`
+  if let Some(seed) = opts.seed.filter(|_| supports_seed(&http_req.provider)) {
+    map.insert("seed".to_string(), json!(seed));
+  }
+
+  let mut messages = vec![];
+
+  if !opts.system_prompts.is_empty() {
+    match http_req.provider {
+      // Anthropic only supports a single top-level system prompt,
+      // therefore all system prompts are concatenated in order
+      Provider::Anthropic => {
+        map.insert(
+          "system".to_string(),
+          Value::String(opts.system_prompts.join("\n\n")),
+        );
+      }
+      Provider::Bedrock => {
+        map.insert(
+          "system".to_string(),
+          opts
+            .system_prompts
+            .iter()
+            .map(|system_prompt| json!({ "text": system_prompt }))
+            .collect(),
+        );
+      }
+      _ => {
+        let system_role = opts
+          .system_role
+          .clone()
+          .unwrap_or(get_default_system_role(http_req).to_string());
+        for system_prompt in &opts.system_prompts {
+          messages.push(Value::Object(Map::from_iter([
+            ("role".to_string(), system_role.clone().into()),
+            ("content".to_string(), Value::String(system_prompt.clone())),
+          ])));
+        }
+      }
+    }
+  }
+
+  let content = match http_req.provider {
+    Provider::Bedrock => json!([{ "text": user_input }]),
+    _ => Value::String(user_input.to_string()),
+  };
+  messages.push(Value::Object(Map::from_iter([
+    ("role".to_string(), "user".into()),
+    ("content".to_string(), content),
+  ])));
+
+  map.insert("messages".to_string(), Value::Array(messages));
+
+  // Passed through as is, their validation is up to the provider
+  for (key, value) in &opts.params {
+    map.insert(key.clone(), value.clone());
+  }
+
+  Ok(Value::Object(map))
+}
+
+/// Image attached to the prompt
+#[derive(Debug, PartialEq, Clone)]
+enum ImageInput {
+  Url(String),
+  Base64 { media_type: String, data: String },
+}
+
+/// Get the media type of an image from the extension of its path
+fn get_image_media_type(path: &str) -> Option<&'static str> {
+  let ext = std::path::Path::new(path)
+    .extension()
+    .and_then(|ext| ext.to_str())
+    .unwrap_or("")
+    .to_lowercase();
+  match ext.as_str() {
+    "jpg" | "jpeg" => Some("image/jpeg"),
+    "png" => Some("image/png"),
+    "gif" => Some("image/gif"),
+    "webp" => Some("image/webp"),
+    _ => None,
+  }
+}
+
+/// Maximum size of downloaded input files (20 MB)
+const MAX_DOWNLOAD_BYTES: u64 = 20 * 1024 * 1024;
+
+/// Load the content and the media type of a local file,
+/// a `file://` URL, or an `http(s)://` URL (which is downloaded)
+async fn load_input_file(
+  client: &reqwest::Client,
+  path: &str,
+) -> Result<(Vec<u8>, String), Box<dyn Error + Send + Sync>> {
+  // Unknown formats are sent as JPEG and left to the provider to detect
+  let get_media_type = |path: &str| {
+    get_image_media_type(path)
+      .unwrap_or("image/jpeg")
+      .to_string()
+  };
+
+  if path.starts_with("http://") || path.starts_with("https://") {
+    let download_err = |err: &dyn std::fmt::Display| {
+      format!("Couldn't download \"{path}\": {err}")
+    };
+    let too_large_err =
+      download_err(&format!("File is larger than {MAX_DOWNLOAD_BYTES} bytes"));
+    let mut resp = client
+      .get(path)
+      .send()
+      .await
+      .map_err(|err| download_err(&err))?;
+    if !resp.status().is_success() {
+      Err(download_err(&resp.status()))?;
+    }
+    if resp
+      .content_length()
+      .is_some_and(|length| length > MAX_DOWNLOAD_BYTES)
+    {
+      Err(too_large_err.clone())?;
+    }
+    let media_type = resp
+      .headers()
+      .get(reqwest::header::CONTENT_TYPE)
+      .and_then(|content_type| content_type.to_str().ok())
+      .and_then(|content_type| content_type.split(';').next())
+      .map(|content_type| content_type.trim().to_string())
+      .filter(|content_type| content_type.starts_with("image/"))
+      .unwrap_or_else(|| get_media_type(resp.url().path()));
+    // The length header is optional, so the size is checked while reading
+    let mut content = vec![];
+    while let Some(chunk) =
+      resp.chunk().await.map_err(|err| download_err(&err))?
+    {
+      content.extend_from_slice(&chunk);
+      if content.len() as u64 > MAX_DOWNLOAD_BYTES {
+        Err(too_large_err.clone())?;
+      }
+    }
+    return Ok((content, media_type));
+  }
+
+  let file_path = if path.starts_with("file://") {
+    reqwest::Url::parse(path)
+      .ok()
+      .and_then(|url| url.to_file_path().ok())
+      .ok_or(format!("Invalid file URL \"{path}\""))?
+  } else {
+    std::path::PathBuf::from(path)
+  };
+  let content = std::fs::read(&file_path)
+    .map_err(|err| format!("Couldn't read \"{path}\": {err}"))?;
+  Ok((content, get_media_type(path)))
+}
+
+/// Load the images from URLs, base64 data URLs, or local files
+fn load_images(
+  images: &[String],
+) -> Result<Vec<ImageInput>, Box<dyn Error + Send + Sync>> {
+  images
+    .iter()
+    .map(|image| {
+      if image.starts_with("http://") || image.starts_with("https://") {
+        return Ok(ImageInput::Url(image.clone()));
+      }
+      if let Some((media_type, data)) = image
+        .strip_prefix("data:")
+        .and_then(|data_url| data_url.split_once(";base64,"))
+      {
+        return Ok(ImageInput::Base64 {
+          media_type: media_type.to_string(),
+          data: data.to_string(),
+        });
+      }
+      let media_type = get_image_media_type(image).ok_or(format!(
+        "Unsupported image format of \"{image}\" \
+        (Supported: jpg, jpeg, png, gif, webp)"
+      ))?;
+      let file_content = std::fs::read(image)
+        .map_err(|err| format!("Couldn't read image \"{image}\": {err}"))?;
+      Ok(ImageInput::Base64 {
+        media_type: media_type.to_string(),
+        data: base64::engine::general_purpose::STANDARD.encode(&file_content),
+      })
+    })
+    .collect()
+}
+
+/// Check if the model can process images.
+/// Local models can't be checked and are assumed to support them.
+fn is_vision_model(http_req: &AiRequest) -> bool {
+  let model = http_req.model.to_lowercase();
+  match http_req.provider {
+    Provider::OpenAI => [
+      "gpt-4o",
+      "gpt-4.1",
+      "gpt-4-turbo",
+      "gpt-5",
+      "o1",
+      "o3",
+      "o4",
+    ]
+    .iter()
+    .any(|prefix| model.starts_with(prefix)),
+    Provider::Anthropic => !model.starts_with("claude-2"),
+    Provider::Groq => model.contains("vision") || model.contains("llama-4"),
+    Provider::Together | Provider::Fireworks => {
+      model.contains("vision") || model.contains("-vl")
+    }
+    Provider::Llamafile | Provider::Ollama => true,
+    Provider::Bedrock => false,
+  }
+}
+
+/// Replace the content of the last (user) message with a multimodal
+/// content array containing the images and the text
+fn add_images(
+  req_body_obj: &mut Value,
+  provider: &Provider,
+  images: &[ImageInput],
+) {
+  let Some(message) = req_body_obj
+    .get_mut("messages")
+    .and_then(|messages| messages.as_array_mut())
+    .and_then(|messages| messages.last_mut())
+  else {
+    return;
+  };
+
+  let mut content: Vec<Value> = images
+    .iter()
+    .map(|image| match (provider, image) {
+      (Provider::Anthropic, ImageInput::Url(url)) => json!({
+        "type": "image",
+        "source": { "type": "url", "url": url },
+      }),
+      (Provider::Anthropic, ImageInput::Base64 { media_type, data }) => json!({
+        "type": "image",
+        "source": { "type": "base64", "media_type": media_type, "data": data },
+      }),
+      (_, ImageInput::Url(url)) => json!({
+        "type": "image_url",
+        "image_url": { "url": url },
+      }),
+      (_, ImageInput::Base64 { media_type, data }) => json!({
+        "type": "image_url",
+        "image_url": { "url": format!("data:{media_type};base64,{data}") },
+      }),
+    })
+    .collect();
+  content.push(json!({ "type": "text", "text": message["content"].clone() }));
+
+  message["content"] = Value::Array(content);
+}
+
+/// Log the request to stderr with the API keys redacted
+fn log_request(request: &reqwest::Request) {
+  eprintln!("> {} {}", request.method(), request.url());
+  for (name, value) in request.headers() {
+    let value_str = match name.as_str() {
+      "authorization" => "***",
+      "x-api-key" | "x-amz-security-token" => "***",
+      _ => value.to_str().unwrap_or("<binary>"),
+    };
+    eprintln!("> {name}: {value_str}");
+  }
+  if let Some(body) = request.body().and_then(|body| body.as_bytes()) {
+    let body_str = String::from_utf8_lossy(body);
+    let pretty_body = serde_json::from_str::<Value>(&body_str)
+      .map(|json| serde_json::to_string_pretty(&json).unwrap())
+      .unwrap_or(body_str.to_string());
+    eprintln!(">\n{pretty_body}\n");
+  }
+}
+
+/// Exponential backoff (starting at 500 ms) capped at `max_ms`
+/// and randomized with a value of `random` between 0 and 1
+fn get_backoff_ms(
+  attempt: u32,
+  max_ms: u64,
+  jitter: RetryJitter,
+  random: f64,
+) -> u64 {
+  let backoff_ms = 500_u64.saturating_mul(1 << attempt.min(32)).min(max_ms);
+  match jitter {
+    RetryJitter::Full => (backoff_ms as f64 * random) as u64,
+    RetryJitter::Equal => {
+      backoff_ms / 2 + (backoff_ms as f64 / 2.0 * random) as u64
+    }
+    RetryJitter::None => backoff_ms,
+  }
+}
+
+/// HTTP client shared by all requests to reuse connections
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Limits the number of concurrent requests of all tasks (`--jobs`)
+static REQUEST_LIMITER: OnceLock<Option<tokio::sync::Semaphore>> =
+  OnceLock::new();
+
+fn get_request_limiter(
+  opts: &ExecOptions,
+) -> Option<&'static tokio::sync::Semaphore> {
+  REQUEST_LIMITER
+    .get_or_init(|| {
+      opts
+        .jobs
+        .map(|jobs| tokio::sync::Semaphore::new(jobs.max(1)))
+    })
+    .as_ref()
+}
+
+/// Index of the last API key of each provider which worked
+static GOOD_KEY_INDICES: OnceLock<Mutex<HashMap<Provider, usize>>> =
+  OnceLock::new();
+
+fn get_good_key_indices() -> &'static Mutex<HashMap<Provider, usize>> {
+  GOOD_KEY_INDICES.get_or_init(Default::default)
+}
+
+/// Use the API key with the index (wrapping around)
+fn with_api_key(http_req: &AiRequest, key_index: usize) -> AiRequest {
+  let api_key = match http_req.api_keys.len() {
+    0 => http_req.api_key.clone(),
+    key_count => http_req.api_keys[key_index % key_count].clone(),
+  };
+  AiRequest {
+    api_key,
+    ..http_req.clone()
+  }
+}
+
+/// Get the shared HTTP client or build it with the settings of the request.
+/// (The settings are the same for all requests of an invocation.)
+fn get_client(
+  opts: &ExecOptions,
+  http_req: &AiRequest,
+) -> Result<&'static reqwest::Client, reqwest::Error> {
+  if let Some(client) = CLIENT.get() {
+    return Ok(client);
+  }
+
+  let mut client_builder = reqwest::Client::builder();
+  if let Some(pool_size) = opts.pool_size.or(http_req.pool_size) {
+    client_builder = client_builder.pool_max_idle_per_host(pool_size);
+  }
+  if opts.measure {
+    client_builder =
+      client_builder.dns_resolver(std::sync::Arc::new(MeasuringResolver));
+  }
+  // Precedence: --proxy flag > `proxy` config > HTTP(S)_PROXY/ALL_PROXY env
+  // (The env variables are already used by reqwest by default)
+  if let Some(proxy_url) = &opts.proxy {
+    client_builder = client_builder.proxy(reqwest::Proxy::all(proxy_url)?);
+  } else if let Some(proxy_url) = &http_req.proxy {
+    client_builder = client_builder.proxy(
+      reqwest::Proxy::all(proxy_url)?.no_proxy(reqwest::NoProxy::from_env()),
+    );
+  }
+  let client = client_builder.build()?;
+
+  Ok(CLIENT.get_or_init(|| client))
+}
+
+/// Duration of the last DNS lookup of the shared client (`--measure`)
+static LAST_DNS_MS: Mutex<Option<u128>> = Mutex::new(None);
+
+/// DNS resolver which records the duration of each lookup
+/// (Only called if a new connection is opened)
+struct MeasuringResolver;
+
+impl reqwest::dns::Resolve for MeasuringResolver {
+  fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+    Box::pin(async move {
+      let start = Instant::now();
+      // The port is replaced with the one of the URL
+      let addrs: Vec<_> =
+        tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+      if let Ok(mut last_dns_ms) = LAST_DNS_MS.lock() {
+        *last_dns_ms = Some(start.elapsed().as_millis());
+      }
+      Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+    })
+  }
+}
+
+#[cfg(feature = "bedrock")]
+fn sign_aws_request(
+  request: &mut reqwest::Request,
+  credentials: &AwsCredentials,
+) -> Result<(), String> {
+  sigv4::sign_request(request, credentials, "bedrock", chrono::Utc::now())
+}
+
+#[cfg(not(feature = "bedrock"))]
+fn sign_aws_request(
+  _request: &mut reqwest::Request,
+  _credentials: &AwsCredentials,
+) -> Result<(), String> {
+  Err(
+    "cai was built without Bedrock support. \
+    Reinstall it with `cargo install cai --features bedrock`."
+      .to_string(),
+  )
+}
+
+/// Build the request with the authentication and all custom headers
+fn build_request(
+  client: &reqwest::Client,
+  opts: &ExecOptions,
+  http_req: &AiRequest,
+  req_body_obj: &Value,
+) -> Result<reqwest::Request, Box<dyn Error + Send + Sync>> {
+  // Requests without a body (e.g. health checks) only fetch data
+  let req_base = if req_body_obj.is_null() {
+    client.get(http_req.url.clone())
+  } else {
+    client.post(http_req.url.clone()).json(&req_body_obj)
+  };
+  let req = match http_req.provider {
+    Provider::Anthropic => {
+      let version = http_req
+        .anthropic_version
+        .as_deref()
+        .unwrap_or(DEFAULT_ANTHROPIC_VERSION);
+      let betas: Vec<&str> = [
+        http_req.is_oauth.then_some(ANTHROPIC_OAUTH_BETA),
+        http_req.anthropic_beta.as_deref(),
+      ]
+      .into_iter()
+      .flatten()
+      .collect();
+      let req = req_base.header("anthropic-version", version);
+      let req = if betas.is_empty() {
+        req
+      } else {
+        req.header("anthropic-beta", betas.join(","))
+      };
+      if http_req.is_oauth {
+        req.bearer_auth(&http_req.api_key)
+      } else {
+        req.header("x-api-key", &http_req.api_key)
+      }
+    }
+    // Signed below, as the signature covers the final request
+    Provider::Bedrock => req_base,
+    // Unset values are omitted instead of being sent empty
+    Provider::OpenAI => [
+      ("OpenAI-Organization", &http_req.openai_org),
+      ("OpenAI-Project", &http_req.openai_project),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.as_ref().map(|value| (name, value)))
+    .fold(
+      req_base.bearer_auth(&http_req.api_key),
+      |req, (name, value)| req.header(name, value),
+    ),
+    _ => req_base.bearer_auth(&http_req.api_key),
+  };
+  let mut request = req.build()?;
+
+  // Headers from the CLI override the ones from the config
+  for (name, value) in http_req.headers.iter().chain(&opts.extra_headers) {
+    let header_name = reqwest::header::HeaderName::try_from(name)
+      .map_err(|err| format!("Invalid header name \"{name}\": {err}"))?;
+    let header_value = reqwest::header::HeaderValue::try_from(value)
+      .map_err(|err| format!("Invalid value for header \"{name}\": {err}"))?;
+    request.headers_mut().insert(header_name, header_value);
+  }
+  if let Some(credentials) = &http_req.aws_credentials {
+    sign_aws_request(&mut request, credentials)?;
+  }
+
+  Ok(request)
+}
+
+/// Send the request and return the status and the parsed JSON body
+async fn exec_request(
+  opts: &ExecOptions,
+  http_req: &AiRequest,
+  req_body_obj: &Value,
+) -> Result<(StatusCode, Value), Box<dyn Error + Send + Sync>> {
+  let client = get_client(opts, http_req)?;
+  let key_count = http_req.api_keys.len();
+  let mut key_index = get_good_key_indices()
+    .lock()
+    .map(|indices| indices.get(&http_req.provider).copied().unwrap_or(0))
+    .unwrap_or(0);
+  let mut request = build_request(
+    client,
+    opts,
+    &with_api_key(http_req, key_index),
+    req_body_obj,
+  )?;
+  if opts.verbose {
+    log_request(&request);
+  }
+
+  // Retries keep the permit to not exceed the limit
+  let _permit = match get_request_limiter(opts) {
+    Some(limiter) => Some(limiter.acquire().await?),
+    None => None,
+  };
+  if let Ok(mut last_dns_ms) = LAST_DNS_MS.lock() {
+    *last_dns_ms = None;
+  }
+  let start = Instant::now();
+  let mut attempt = 0;
+  let mut failed_key_count = 0;
+  let resp = loop {
+    let result = client
+      .execute(request.try_clone().ok_or("Request can't be retried")?)
+      .await;
+
+    // Other keys are tried right away instead of waiting for the backoff
+    let is_key_error = result.as_ref().is_ok_and(|resp| {
+      resp.status() == StatusCode::UNAUTHORIZED
+        || resp.status() == StatusCode::TOO_MANY_REQUESTS
+    });
+    if is_key_error && failed_key_count + 1 < key_count {
+      failed_key_count += 1;
+      key_index = (key_index + 1) % key_count;
+      if opts.verbose {
+        eprintln!("Switching to API key {} of {key_count}", key_index + 1);
+      }
+      request = build_request(
+        client,
+        opts,
+        &with_api_key(http_req, key_index),
+        req_body_obj,
+      )?;
+      continue;
+    }
+
+    let is_retryable = match &result {
+      Ok(resp) => {
+        resp.status() == StatusCode::TOO_MANY_REQUESTS
+          || resp.status().is_server_error()
+      }
+      Err(err) => err.is_connect() || err.is_timeout(),
+    };
+    if attempt >= http_req.retries || !is_retryable {
+      break result?;
+    }
+    let backoff_ms = get_backoff_ms(
+      attempt,
+      http_req.retry_max_ms,
+      http_req.retry_jitter,
+      fastrand::f64(),
+    );
+    if opts.verbose {
+      eprintln!("Retrying in {backoff_ms} ms (attempt {})", attempt + 1);
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(backoff_ms)).await;
+    attempt += 1;
+  };
+  let first_byte_ms = start.elapsed().as_millis();
+  let status = resp.status();
+  if status.is_success() {
+    if let Ok(mut indices) = get_good_key_indices().lock() {
+      indices.insert(http_req.provider, key_index % key_count.max(1));
+    }
+  }
+  let resp_text = resp.text().await?;
+  if opts.verbose {
+    eprintln!("< {status}\n{resp_text}\n");
+  }
+
+  // The first byte includes connecting (if no connection could be reused),
+  // sending the request, and the processing by the provider
+  if opts.measure {
+    let dns_str = match LAST_DNS_MS.lock().ok().and_then(|dns_ms| *dns_ms) {
+      Some(dns_ms) => format!("DNS {dns_ms} ms"),
+      None => "DNS - (no lookup needed)".to_string(),
+    };
+    eprintln!(
+      "{dns_str} | First byte {first_byte_ms} ms | Total {} ms",
+      start.elapsed().as_millis()
+    );
+  }
+
+  // Error pages (e.g. of gateways or proxies) often aren't JSON
+  let resp_json = match serde_json::from_str(&resp_text) {
+    Ok(resp_json) => resp_json,
+    Err(_) if !status.is_success() => Value::String(resp_text),
+    Err(err) => Err(format!(
+      "Response isn't valid JSON ({err}):\n\n{}",
+      truncate_body(&resp_text)
+    ))?,
+  };
+
+  Ok((status, resp_json))
+}
+
+/// Error bodies longer than this are truncated (e.g. HTML pages)
+const MAX_ERROR_BODY_CHARS: usize = 2000;
+
+fn truncate_body(body: &str) -> String {
+  let body = body.trim();
+  match body.char_indices().nth(MAX_ERROR_BODY_CHARS) {
+    Some((index, _)) => format!("{}…", &body[..index]),
+    None => body.to_string(),
+  }
+}
+
+/// Pretty-print JSON error bodies and show other ones as raw text
+fn fmt_error_body(resp_json: &Value) -> String {
+  match resp_json {
+    Value::String(text) => truncate_body(text),
+    _ => serde_json::to_string_pretty(resp_json).unwrap(),
+  }
+}
+
+/// Switch the request to Ollama's native `/api/chat` endpoint
+fn to_ollama_native(
+  http_req: &AiRequest,
+  req_body_obj: &Value,
+) -> Result<(AiRequest, Value), Box<dyn Error + Send + Sync>> {
+  let native_req = AiRequest {
+    url: ollama::get_native_url(&http_req.url),
+    ..http_req.clone()
+  };
+  Ok((native_req, ollama::to_native_body(req_body_obj)?))
+}
+
+/// Extract the content and the thinking from a successful response
+fn parse_ai_response(
+  provider: &Provider,
+  resp_json: Value,
+) -> Result<(String, Option<String>), Box<dyn Error + Send + Sync>> {
+  let get_empty_msg = |kind: &str| {
+    format!(
+      "Provider returned no {kind} (possibly content-filtered)\n\n{}",
+      serde_json::to_string_pretty(&resp_json).unwrap()
+    )
+  };
+
+  match provider {
+    Provider::Anthropic => {
+      let anth_response = AnthropicAiResponse::deserialize(&resp_json)?;
+      if anth_response.content.is_empty() {
+        Err(get_empty_msg("content"))?;
+      }
+      Ok((anth_response.text(), anth_response.thinking()))
+    }
+    Provider::Bedrock => {
+      let text: String = resp_json["output"]["message"]["content"]
+        .as_array()
+        .map(|blocks| {
+          blocks
+            .iter()
+            .filter_map(|block| block["text"].as_str())
+            .collect()
+        })
+        .unwrap_or_default();
+      if text.is_empty() {
+        Err(get_empty_msg("content"))?;
+      }
+      Ok((text, None))
+    }
+    Provider::Ollama if resp_json.get("choices").is_none() => {
+      let (content, thinking) = ollama::parse_native_response(&resp_json);
+      if content.is_empty() {
+        Err(get_empty_msg("content"))?;
+      }
+      Ok((content, thinking))
+    }
+    _ => {
+      let ai_response = AiResponse::deserialize(&resp_json)?;
+      let Some(choice) = ai_response.choices.first() else {
+        Err(get_empty_msg("choices"))?
+      };
+      let message = &choice.message;
+      let (content, think_block) = split_think_block(&message.content);
+      let reasoning = message
+        .reasoning_content
+        .clone()
+        .or(message.reasoning.clone())
+        .filter(|reasoning| !reasoning.is_empty())
+        .or(think_block);
+      Ok((content, reasoning))
+    }
+  }
+}
+
+/// Error response returned by the API of a provider
+#[derive(Debug)]
+pub struct ApiError {
+  pub used_model: String,
+  pub elapsed_ms: u128,
+  pub body: String,
+}
+
+impl std::fmt::Display for ApiError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{}",
+      cformat!(
+        "<bold>⏱️ {: >5} ms</bold> | {}\n\n{}",
+        self.elapsed_ms,
+        self.used_model,
+        self.body,
+      )
+    )
+  }
+}
+
+impl Error for ApiError {}
+
+/// Number of tokens used by the request
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+pub struct Usage {
+  pub input_tokens: u64,
+  pub output_tokens: u64,
+}
+
+impl std::ops::Add for Usage {
+  type Output = Usage;
+
+  fn add(self, other: Usage) -> Usage {
+    Usage {
+      input_tokens: self.input_tokens + other.input_tokens,
+      output_tokens: self.output_tokens + other.output_tokens,
+    }
+  }
+}
+
+/// Extract the token usage from the (provider specific) response
+fn get_usage(resp_json: &Value) -> Option<Usage> {
+  let usage = &resp_json["usage"];
+  let get_count =
+    |names: &[&str]| names.iter().find_map(|name| usage[name].as_u64());
+
+  if usage.is_object() {
+    Some(Usage {
+      input_tokens: get_count(&[
+        "prompt_tokens",
+        "input_tokens",
+        "inputTokens",
+      ])?,
+      output_tokens: get_count(&[
+        "completion_tokens",
+        "output_tokens",
+        "outputTokens",
+      ])?,
+    })
+  } else {
+    // Ollama's native API
+    Some(Usage {
+      input_tokens: resp_json["prompt_eval_count"].as_u64()?,
+      output_tokens: resp_json["eval_count"].as_u64()?,
+    })
+  }
+}
+
+/// Metadata of a response besides its content
+#[derive(Debug, Default)]
+struct ResponseMeta {
+  system_fingerprint: Option<String>,
+  usage: Option<Usage>,
+}
+
+/// Successful response of a model
+#[derive(Serialize, Debug, Clone)]
+pub struct AiResult {
+  pub provider: Provider,
+  pub model: String,
+  pub content: String,
+  pub thinking: Option<String>,
+  pub elapsed_ms: u128,
+  pub is_cached: bool,
+  pub system_fingerprint: Option<String>, // Identifies the backend config
+  pub usage: Option<Usage>,               // Not available for cached responses
+  pub warnings: Vec<String>,              // Ignored options of the request
+}
+
+/// Fully assembled request which is ready to be sent
+struct PreparedRequest {
+  used_model: String,
+  http_req: AiRequest,
+  req_body_obj: Value,
+  opts: ExecOptions,
+  entity_mapping: anonymize::EntityMapping,
+  full_config: HashMap<String, String>,
+  warnings: Vec<String>,
+}
+
+/// Assemble the request body with all system prompts and attachments
+fn prepare_request(
+  optional_model: &Option<&Model>,
+  opts: &ExecOptions,
+  user_input: &str,
+) -> Result<PreparedRequest, Box<dyn Error + Send + Sync>> {
+  let secrets_path_str = get_secrets_path_str(opts);
+  let full_config = get_full_config(&secrets_path_str)?;
+  let (used_model, mut http_req) =
+    get_http_req(optional_model, &secrets_path_str, &full_config)?;
+  // Invalid values are reported instead of silently using the default
+  let max_tokens_key = format!(
+    "{}_max_tokens",
+    http_req.provider.to_string().to_lowercase()
+  );
+  if let Some(max_tokens) = full_config
+    .get(&max_tokens_key)
+    .filter(|max_tokens| max_tokens.parse::<u32>().is_err())
+  {
+    Err(format!(
+      "Invalid {max_tokens_key} \"{max_tokens}\" in {secrets_path_str} \
+      (must be a number)"
+    ))?;
+  }
+  if let Some(max_tokens) = opts.max_tokens {
+    http_req.max_tokens = max_tokens;
+  }
+
+  if opts.oauth && http_req.provider == Provider::Anthropic {
+    http_req.api_key = full_config
+      .get("anthropic_oauth_token")
+      .filter(|token| !token.is_empty())
+      .cloned()
+      .ok_or("--oauth requires `anthropic_oauth_token` in the config")?;
+    // The API keys must not be rotated in instead of the token
+    http_req.api_keys = vec![http_req.api_key.clone()];
+    http_req.is_oauth = true;
+  }
+
+  // This is checked here, so that the missing API key message comes first
+  if user_input.is_empty() {
+    Err("No prompt was provided")?;
+  }
+
+  // Names are replaced locally and restored in the response
+  let (user_input, entity_mapping) = if opts.anonymize {
+    anonymize::anonymize(user_input)
+  } else {
+    (user_input.to_string(), vec![])
+  };
+  let mut opts = opts.clone();
+
+  // Models don't know the current date otherwise
+  let with_date = opts.with_date
+    || full_config
+      .get("with_date")
+      .is_some_and(|with_date| with_date == "true");
+  if with_date {
+    let date_format = opts
+      .date_format
+      .clone()
+      .or(full_config.get("date_format").cloned())
+      .unwrap_or("%A, %Y-%m-%d %H:%M (UTC%:z)".to_string());
+    let mut date_str = String::new();
+    write!(date_str, "{}", chrono::Local::now().format(&date_format))
+      .map_err(|_| format!("Invalid date format \"{date_format}\""))?;
+    opts
+      .system_prompts
+      .insert(0, format!("The current date and time is {date_str}."));
+  }
+
+  if !entity_mapping.is_empty() {
+    opts.system_prompts.push(
+      "Some names were replaced with placeholders like PERSON_1, ORG_1, \
+      or LOCATION_1. Keep the placeholders unchanged in your answer."
+        .to_string(),
+    );
+  }
+
+  if !opts.images.is_empty() && !is_vision_model(&http_req) {
+    Err(format!(
+      "{} {} doesn't support images",
+      http_req.provider, http_req.model
+    ))?;
+  }
+  let images = load_images(&opts.images)?;
+  let get_body = |input: &str| -> Result<Value, Box<dyn Error + Send + Sync>> {
+    let mut req_body_obj = get_req_body_obj(&opts, &http_req, input)?;
+    if !images.is_empty() {
+      add_images(&mut req_body_obj, &http_req.provider, &images);
+    }
+    Ok(req_body_obj)
+  };
+  let mut req_body_obj = get_body(&user_input)?;
+
+  // Oversized prompts can still be counted
+  if !opts.print_prompt_tokens {
+    let fitted_input =
+      fit_into_context_window(&opts, &http_req, &req_body_obj, &user_input)?;
+    if let Some(input) = fitted_input {
+      req_body_obj = get_body(&input)?;
+    }
+  }
+
+  if opts.ollama_native && http_req.provider == Provider::Ollama {
+    (http_req, req_body_obj) = to_ollama_native(&http_req, &req_body_obj)?;
+  }
+
+  let warnings = get_request_warnings(&opts, &http_req);
+  Ok(PreparedRequest {
+    used_model,
+    http_req,
+    req_body_obj,
+    opts,
+    entity_mapping,
+    full_config,
+    warnings,
+  })
+}
+
+pub async fn get_ai_result(
+  optional_model: &Option<&Model>,
+  opts: &ExecOptions,
+  user_input: &str,
+) -> Result<AiResult, Box<dyn Error + Send + Sync>> {
+  let start = Instant::now();
+  let PreparedRequest {
+    used_model,
+    http_req,
+    req_body_obj,
+    opts,
+    entity_mapping,
+    full_config,
+    warnings,
+  } = prepare_request(optional_model, opts, user_input)?;
+
+  // Fail before sending the request to not waste any tokens
+  if let Some(output) = &opts.output {
+    if !opts.force && std::path::Path::new(output).exists() {
+      Err(format!(
+        "Output file \"{output}\" already exists. Use --force to overwrite it."
+      ))?;
+    }
+  }
+
+  let use_cache = !opts.no_cache
+    && (opts.cache
+      || full_config
+        .get("cache")
+        .is_some_and(|cache| cache == "true"));
+  let cache_ttl = full_config
+    .get("cache_ttl")
+    .and_then(|ttl| ttl.parse().ok())
+    .unwrap_or(cache::DEFAULT_TTL_SECS);
+  let cache_key = cache::get_cache_key(&http_req.url, &req_body_obj);
+  let cached_response = use_cache
+    .then(|| cache::load_response(&cache_key, cache_ttl))
+    .flatten();
+  let is_cached = cached_response.is_some();
+
+  let (content, thinking, response_meta) = match cached_response {
+    Some((content, thinking)) => (content, thinking, Default::default()),
+    None => {
+      let (content, thinking, response_meta) = request_content(
+        &opts,
+        &full_config,
+        &used_model,
+        &http_req,
+        &req_body_obj,
+      )
+      .await?;
+      if use_cache {
+        cache::save_response(&cache_key, &content, &thinking);
+      }
+      (content, thinking, response_meta)
+    }
+  };
+  let elapsed_ms = start.elapsed().as_millis();
+
+  let remember_last_model = full_config
+    .get("remember_last_model")
+    .is_some_and(|remember| remember == "true");
+  if remember_last_model {
+    save_last_model(&http_req.provider, &http_req.model);
+  }
+  let content = if opts.strict_json {
+    extract_json(&content).ok_or(format!(
+      "Response does not contain valid JSON:\n\n{content}"
+    ))?
+  } else {
+    content
+  };
+
+  Ok(AiResult {
+    provider: http_req.provider,
+    model: http_req.model,
+    content: anonymize::deanonymize(&content, &entity_mapping),
+    thinking: thinking
+      .map(|thinking| anonymize::deanonymize(&thinking, &entity_mapping)),
+    elapsed_ms,
+    is_cached,
+    system_fingerprint: response_meta.system_fingerprint,
+    usage: response_meta.usage,
+    warnings,
+  })
+}
+
+/// Send the request and return the content, the thinking,
+/// and the metadata of the response
+async fn request_content(
+  opts: &ExecOptions,
+  full_config: &HashMap<String, String>,
+  used_model: &str,
+  http_req: &AiRequest,
+  req_body_obj: &Value,
+) -> Result<(String, Option<String>, ResponseMeta), Box<dyn Error + Send + Sync>>
+{
+  let start = Instant::now();
+  confirm_request(opts, full_config, used_model, http_req, req_body_obj)?;
+
+  let (mut status, mut resp_json) =
+    exec_request(opts, http_req, req_body_obj).await?;
+
+  // Older Ollama versions don't provide the OpenAI compatible endpoint
+  // and respond with a plain text 404 page
+  let mut native_request = None;
+  if http_req.provider == Provider::Ollama
+    && status == StatusCode::NOT_FOUND
+    && resp_json.is_string()
+  {
+    if opts.verbose {
+      eprintln!("Falling back to Ollama's native API");
+    }
+    let (native_req, native_body) = to_ollama_native(http_req, req_body_obj)?;
+    (status, resp_json) = exec_request(opts, &native_req, &native_body).await?;
+    native_request = Some((native_req, native_body));
+  }
+  let (http_req, req_body_obj) = native_request
+    .as_ref()
+    .map_or((http_req, req_body_obj), |(native_req, native_body)| {
+      (native_req, native_body)
+    });
+  let elapsed_ms = start.elapsed().as_millis();
+
+  if !status.is_success() {
+    return Err(Box::new(ApiError {
+      used_model: used_model.to_string(),
+      elapsed_ms,
+      body: fmt_error_body(&resp_json),
+    }));
+  }
+
+  let mut response_meta = ResponseMeta {
+    system_fingerprint: resp_json["system_fingerprint"]
+      .as_str()
+      .map(|fingerprint| fingerprint.to_string()),
+    usage: get_usage(&resp_json),
+  };
+  let (mut content, mut thinking) =
+    parse_ai_response(&http_req.provider, resp_json)?;
+
+  if let Some(json_schema) = &opts.json_schema {
+    let schema = &json_schema["schema"];
+    let errors = schema::validate_json(&content, schema);
+    if !errors.is_empty() && opts.json_repair {
+      let repair_body = get_repair_req_body(req_body_obj, &content, &errors);
+      let (status, resp_json) =
+        exec_request(opts, http_req, &repair_body).await?;
+      if !status.is_success() {
+        return Err(Box::new(ApiError {
+          used_model: used_model.to_string(),
+          elapsed_ms: start.elapsed().as_millis(),
+          body: fmt_error_body(&resp_json),
+        }));
+      }
+      // Both requests are billed
+      response_meta.usage = match (response_meta.usage, get_usage(&resp_json)) {
+        (Some(usage), Some(repair_usage)) => Some(usage + repair_usage),
+        (usage, repair_usage) => usage.or(repair_usage),
+      };
+      (content, thinking) = parse_ai_response(&http_req.provider, resp_json)?;
+    }
+    check_json_schema(&content, schema)?;
+  }
+
+  Ok((content, thinking, response_meta))
+}
+
+/// Validate the response against the JSON schema
+fn check_json_schema(content: &str, schema: &Value) -> Result<(), String> {
+  let errors = schema::validate_json(content, schema);
+  if errors.is_empty() {
+    Ok(())
+  } else {
+    Err(format!(
+      "Response doesn't match the JSON schema:\n- {}\n\n{content}",
+      errors.join("\n- ")
+    ))
+  }
+}
+
+/// Continue the conversation with a request to fix the schema violations
+fn get_repair_req_body(
+  req_body_obj: &Value,
+  content: &str,
+  errors: &[String],
+) -> Value {
+  let mut repair_body = req_body_obj.clone();
+  if let Some(messages) = repair_body["messages"].as_array_mut() {
+    messages.push(json!({ "role": "assistant", "content": content }));
+    messages.push(json!({
+      "role": "user",
+      "content": format!(
+        "The JSON doesn't match the schema:\n- {}\n\n\
+        Respond only with the corrected JSON.",
+        errors.join("\n- ")
+      ),
+    }));
+  }
+  repair_body
+}
+
+/// Rough number of tokens an image needs
+/// (Depends on the resolution, which isn't decoded here)
+const IMAGE_TOKEN_ESTIMATE: usize = 1000;
+
+/// Estimate the number of tokens of the fully assembled request
+/// (including system prompts and images) without sending it
+fn estimate_request_tokens(provider: &Provider, req_body_obj: &Value) -> usize {
+  let count_content = |content: &Value| match content {
+    Value::String(text) => estimate_tokens(provider, text),
+    Value::Array(parts) => parts
+      .iter()
+      .map(|part| match part["text"].as_str() {
+        Some(text) => estimate_tokens(provider, text),
+        None => IMAGE_TOKEN_ESTIMATE,
+      })
+      .sum(),
+    _ => 0,
+  };
+
+  let system_tokens = count_content(&req_body_obj["system"]);
+  let message_tokens: usize = req_body_obj["messages"]
+    .as_array()
+    .map(|messages| {
+      messages
+        .iter()
+        .map(|message| count_content(&message["content"]))
+        .sum()
+    })
+    .unwrap_or_default();
+
+  system_tokens + message_tokens
+}
+
+/// Print the estimated number of tokens (and the rough cost)
+/// of the assembled prompt without sending it
+pub fn print_prompt_tokens(
+  optional_model: &Option<&Model>,
+  opts: &ExecOptions,
+  user_input: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let PreparedRequest {
+    used_model,
+    http_req,
+    req_body_obj,
+    ..
+  } = prepare_request(optional_model, opts, user_input)?;
+  let tokens = estimate_request_tokens(&http_req.provider, &req_body_obj);
+
+  if opts.is_raw {
+    println!("{tokens}");
+    return Ok(());
+  }
+
+  let cost = estimate_cost(&http_req, tokens)
+    .map(|cost| format!(" (≈ ${cost:.6})"))
+    .unwrap_or_default();
+  println!(
+    "{}",
+    fmt_stdout(
+      opts,
+      &cformat!("<bold>{used_model}</bold>\n{tokens} tokens{cost}")
+    )
+  );
+  Ok(())
+}
+
+/// Format a number of tokens for messages (e.g. 12k)
+fn fmt_tokens(tokens: usize) -> String {
+  if tokens >= 1000 {
+    format!("{}k", tokens / 1000)
+  } else {
+    tokens.to_string()
+  }
+}
+
+/// Check that the prompt fits into the context window of the model
+/// to fail before the provider rejects it after a round trip.
+/// Returns the truncated input if it doesn't fit and truncation is enabled.
+fn fit_into_context_window(
+  opts: &ExecOptions,
+  http_req: &AiRequest,
+  req_body_obj: &Value,
+  user_input: &str,
+) -> Result<Option<String>, String> {
+  if opts.no_context_check {
+    return Ok(None);
+  }
+  // Context windows of unknown (e.g. local) models can't be checked
+  let Some(context_window) = context::get_known_context_window(&http_req.model)
+  else {
+    return Ok(None);
+  };
+  let max_tokens = http_req.max_tokens as usize;
+  let max_prompt_tokens = context_window.saturating_sub(max_tokens);
+  let prompt_tokens = estimate_request_tokens(&http_req.provider, req_body_obj);
+  if prompt_tokens <= max_prompt_tokens {
+    return Ok(None);
+  }
+
+  let Some(truncation) = opts.truncation else {
+    return Err(format!(
+      "Prompt is ~{} tokens, but {} allows {} tokens \
+      minus {} for the completion.\n\
+      Use --truncate-head or --truncate-tail to shorten the input \
+      or --no-context-check to send it anyway.",
+      fmt_tokens(prompt_tokens),
+      http_req.model,
+      fmt_tokens(context_window),
+      fmt_tokens(max_tokens),
+    ));
+  };
+  // Removing lines from JSON would silently send invalid JSON
+  if user_input.trim_start().starts_with(['{', '[']) {
+    return Err(format!(
+      "Prompt is ~{} tokens, but {} allows {} tokens \
+      minus {} for the completion.\n\
+      The input looks like JSON, which can't be truncated without breaking it.",
+      fmt_tokens(prompt_tokens),
+      http_req.model,
+      fmt_tokens(context_window),
+      fmt_tokens(max_tokens),
+    ));
+  }
+  let count_tokens = |text: &str| estimate_tokens(&http_req.provider, text);
+  let input_tokens = count_tokens(user_input);
+  // System prompts and images are kept
+  let other_tokens = prompt_tokens.saturating_sub(input_tokens);
+  let input = context::truncate_to_tokens(
+    user_input,
+    max_prompt_tokens.saturating_sub(other_tokens),
+    truncation,
+    count_tokens,
+  );
+  eprintln!(
+    "Warning: Truncated the input from ~{} to ~{} tokens \
+    to fit into the context window of {}",
+    fmt_tokens(input_tokens),
+    fmt_tokens(count_tokens(&input)),
+    http_req.model,
+  );
+
+  Ok(Some(input))
+}
+
+/// Rough input cost in USD of the given number of tokens
+fn estimate_cost(http_req: &AiRequest, tokens: usize) -> Option<f64> {
+  tokenizer::get_input_price(&http_req.provider, &http_req.model)
+    .map(|price| tokens as f64 * price / 1_000_000.0)
+}
+
+/// Estimated cost in USD above which interactive requests must be confirmed
+const DEFAULT_CONFIRM_COST: f64 = 0.1;
+
+/// Ask the user to confirm the request if `--confirm` is set
+/// or if the estimated cost is above the threshold
+fn confirm_request(
+  opts: &ExecOptions,
+  full_config: &HashMap<String, String>,
+  used_model: &str,
+  http_req: &AiRequest,
+  req_body_obj: &Value,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+  if opts.yes {
+    return Ok(());
+  }
+
+  let tokens = estimate_request_tokens(&http_req.provider, req_body_obj);
+  let cost = estimate_cost(http_req, tokens);
+  let threshold = full_config
+    .get("confirm_cost")
+    .and_then(|cost| cost.parse::<f64>().ok())
+    .unwrap_or(DEFAULT_CONFIRM_COST);
+  let is_interactive = std::io::stdin().is_terminal();
+  let is_expensive =
+    is_interactive && cost.is_some_and(|cost| cost > threshold);
+
+  if !opts.confirm && !is_expensive {
+    return Ok(());
+  }
+  if !is_interactive {
+    Err("--confirm requires an interactive terminal. Use --yes to skip it.")?;
+  }
+
+  let cost_str = cost
+    .map(|cost| format!(" (≈ ${cost:.4})"))
+    .unwrap_or_default();
+  eprint!(
+    "{}",
+    fmt_output(
+      opts,
+      &cformat!(
+        "<bold>{used_model}</bold>\n\
+        {tokens} tokens{cost_str}\n\
+        Send the request? [y/N] "
+      )
+    )
+  );
+  let mut answer = String::new();
+  std::io::stdin().read_line(&mut answer)?;
+
+  if ["y", "yes"].contains(&answer.trim().to_lowercase().as_str()) {
+    Ok(())
+  } else {
+    Err("Request was cancelled")?
+  }
+}
+
+/// Print the result and write it to the output file
+pub fn output_ai_result(
+  opts: &ExecOptions,
+  result: &AiResult,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+  for warning in &result.warnings {
+    eprintln!("Warning: {warning}");
+  }
+  let content = match &opts.extract {
+    Some(json_path) => extract_json_field(&result.content, json_path)?,
+    None => result.content.clone(),
+  };
+  let msg = if opts.is_plain {
+    highlight::to_plain_text(&content)
+  } else {
+    content
+  };
+
+  if let Some(output) = &opts.output {
+    std::fs::write(output, &msg)?;
+  }
+
+  if opts.json_meta {
+    let envelope = json!({
+      "provider": result.provider,
+      "model": result.model,
+      "elapsed_ms": result.elapsed_ms,
+      "usage": result.usage,
+      "content": msg,
+    });
+    println!("{}", serde_json::to_string_pretty(&envelope)?);
+  } else if opts.is_raw {
+    println!("{}", msg);
+  } else {
+    let used_model =
+      get_used_model(&Model::Model(result.provider, result.model.clone()));
+    let cached_marker = if result.is_cached {
+      cformat!(" <dim>(cached)</dim>")
+    } else {
+      String::new()
+    };
+    // Changes when the backend of the provider changes
+    let fingerprint = result
+      .system_fingerprint
+      .as_ref()
+      .map(|fingerprint| cformat!(" | <dim>{fingerprint}</dim>"))
+      .unwrap_or_default();
+    if !opts.quiet {
+      let header = cformat!(
+        "<bold>⏱️{: >5} ms</bold> | {used_model}{cached_marker}{fingerprint}\n",
+        result.elapsed_ms,
+      );
+      // Keeps piped output clean while still showing it interactively
+      if opts.meta_stdout {
+        println!("{}", fmt_stdout(opts, &header));
+      } else {
+        eprintln!("{}", fmt_output(opts, &header));
+      }
+    }
+    // Anthropic only returns the thinking if a budget was given
+    let show_thinking = opts.show_reasoning || opts.thinking_budget.is_some();
+    if let Some(thinking) = result.thinking.as_ref().filter(|_| show_thinking) {
+      println!(
+        "{}",
+        fmt_stdout(opts, &cformat!("<dim>{}</dim>\n", thinking.trim()))
+      );
+    }
+    if opts.is_plain {
+      print!("{msg}");
+    } else {
+      let width =
+        highlight::get_wrap_width(opts.wrap_width, textwrap::termwidth());
+      let theme =
+        highlight::get_theme(opts.theme.clone(), env::var("COLORFGBG").ok());
+      let use_color = !opts.no_color && std::io::stdout().is_terminal();
+      highlight::text_via_bat(&msg, use_color, width, theme.as_deref());
+    }
+    if !opts.quiet {
+      println!("\n");
+    } else if !msg.ends_with('\n') {
+      println!();
+    }
+  }
+  Ok(())
+}
+
+/// Get the wrap width and the theme from the flags or else the config.
+/// Invalid values are reported instead of being ignored.
+pub fn get_display_settings(
+  opts: &ExecOptions,
+) -> Result<(Option<usize>, Option<String>), Box<dyn Error + Send + Sync>> {
+  let secrets_path_str = get_secrets_path_str(opts);
+  let full_config = get_full_config(&secrets_path_str)?;
+
+  let get_value =
+    |key: &str| full_config.get(key).filter(|value| !value.is_empty());
+  let config_width = get_value("wrap_width")
+    .map(|width| {
+      width.parse().map_err(|_| {
+        format!(
+          "Invalid wrap_width \"{width}\" in {secrets_path_str} \
+          (must be a number)"
+        )
+      })
+    })
+    .transpose()?;
+
+  let theme = match (&opts.theme, get_value("bat_theme")) {
+    (Some(theme), _) => Some((theme, "--theme".to_string())),
+    (None, Some(theme)) => {
+      Some((theme, format!("bat_theme in {secrets_path_str}")))
+    }
+    (None, None) => None,
+  };
+  if let Some((theme, source)) = &theme {
+    if !list_themes().contains(theme) {
+      Err(format!(
+        "Unknown theme \"{theme}\" ({source}). \
+        Use --list-themes to show all themes"
+      ))?;
+    }
+  }
+
+  Ok((
+    opts.wrap_width.or(config_width),
+    theme.map(|(theme, _)| theme.clone()),
+  ))
+}
+
+/// Send the prompt, print the response, and return the assistant message
+/// (`None` if only the prompt tokens were printed)
+pub async fn exec_tool(
+  optional_model: &Option<&Model>,
+  opts: &ExecOptions,
+  user_input: &str,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+  if opts.print_prompt_tokens {
+    print_prompt_tokens(optional_model, opts, user_input)?;
+    return Ok(None);
+  }
+  let result = get_ai_result(optional_model, opts, user_input).await?;
+  output_ai_result(opts, &result)?;
+  Ok(Some(result.content))
+}
+
+/// Extract a JSON value from a response which might wrap it
+/// in markdown code fences or surround it with prose
+pub fn extract_json(text: &str) -> Option<String> {
+  let is_valid = |candidate: &str| {
+    serde_json::from_str::<Value>(candidate)
+      .is_ok_and(|json| json.is_object() || json.is_array())
+  };
+
+  let trimmed = text.trim();
+  if is_valid(trimmed) {
+    return Some(trimmed.to_string());
+  }
+
+  // Find the outermost balanced object or array
+  let chars: Vec<(usize, char)> = text.char_indices().collect();
+  for (pos, &(start, open_char)) in chars.iter().enumerate() {
+    if open_char != '{' && open_char != '[' {
+      continue;
+    }
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut is_escaped = false;
+    for &(index, char) in &chars[pos..] {
+      match char {
+        _ if is_escaped => is_escaped = false,
+        '\\' if in_string => is_escaped = true,
+        '"' => in_string = !in_string,
+        '{' | '[' if !in_string => depth += 1,
+        '}' | ']' if !in_string => {
+          depth -= 1;
+          if depth == 0 {
+            let candidate = &text[start..=index];
+            if is_valid(candidate) {
+              return Some(candidate.to_string());
+            }
+            break;
+          }
+        }
+        _ => {}
+      }
+    }
+  }
+
+  None
+}
+
+/// Get a field of a JSON response.
+/// The field is specified as a JSON path (`$.a.b[0]`) or a JSON pointer.
+fn get_json_field(content: &str, json_path: &str) -> Result<Value, String> {
+  let mut json: Value = serde_json::from_str(content.trim())
+    .map_err(|err| format!("Response is not valid JSON: {err}"))?;
+  let pointer = if json_path.starts_with('/') {
+    json_path.to_string()
+  } else {
+    json_path
+      .trim_start_matches('$')
+      .replace('[', ".")
+      .replace(']', "")
+      .split('.')
+      .filter(|segment| !segment.is_empty())
+      .map(|segment| format!("/{segment}"))
+      .collect()
+  };
+
+  json
+    .pointer_mut(&pointer)
+    .map(Value::take)
+    .ok_or(format!("Field \"{json_path}\" not found in the response"))
+}
+
+/// Derive an exit code from a boolean (true → 0, false → 1)
+/// or integer field of a JSON response.
+pub fn get_exit_code(content: &str, json_path: &str) -> Result<i32, String> {
+  match get_json_field(content, json_path)? {
+    Value::Bool(is_ok) => Ok(if is_ok { 0 } else { 1 }),
+    Value::Number(number) => number
+      .as_i64()
+      .map(|code| code as i32)
+      .ok_or(format!("Field \"{json_path}\" is not an integer: {number}")),
+    value => Err(format!(
+      "Field \"{json_path}\" must be a boolean or an integer, but is {value}"
+    )),
+  }
+}
+
+/// Extract a single field of a JSON response.
+/// Strings are returned without quotes, all other values as JSON.
+pub fn extract_json_field(
+  content: &str,
+  json_path: &str,
+) -> Result<String, String> {
+  match get_json_field(content, json_path)? {
+    Value::String(text) => Ok(text),
+    value => Ok(serde_json::to_string_pretty(&value).unwrap()),
+  }
+}
+
+pub async fn submit_prompt(
+  optional_model: &Option<&Model>,
+  opts: &ExecOptions,
+  user_input: &str,
+) {
+  let user_input = &wrap_prompt(&opts.prompt_wrappers, user_input);
+
+  // Necessary to wrap the execution function,
+  // because a `main` function that returns a `Result` quotes any errors.
+  let exec_result = if opts.print_prompt_tokens {
+    print_prompt_tokens(optional_model, opts, user_input).map(|_| None)
+  } else {
+    match get_ai_result(optional_model, opts, user_input).await {
+      Ok(result) => output_ai_result(opts, &result).map(|_| Some(result)),
+      Err(err) => Err(err),
+    }
+  };
+
+  match exec_result {
+    Ok(None) => {}
+    Ok(Some(result)) => {
+      if let Some(json_path) = &opts.exit_code_from {
+        match get_exit_code(&result.content, json_path) {
+          Ok(code) => std::process::exit(code),
+          Err(err) => {
+            eprintln!(
+              "{}",
+              fmt_output(opts, &cformat!("<red>ERROR: {err}</red>"))
+            );
+            std::process::exit(2);
+          }
+        }
+      }
+    }
+    Err(err) => {
+      let model_str = optional_model
+        .as_ref()
+        .map(|x| x.to_string())
+        .unwrap_or("".to_string());
+      eprintln!(
+        "{}",
+        fmt_output(
+          opts,
+          &cformat!(
+            "<bold>🧠 {model_str}</bold><red>\nERROR:\n{}</red>\n",
+            err
+          )
+        )
+      );
+      std::process::exit(1);
+    }
+  }
+}
+
+/// Prompt to summarize one chunk of an oversized input
+const CHUNK_SUMMARY_PROMPT: &str = "Summarize the following part of a larger \
+  text. Keep all facts, names, numbers, and code which might be relevant. \
+  Only output the summary.";
+
+/// Maximum number of summarization passes over an oversized input
+const MAX_CHUNK_ROUNDS: usize = 3;
+
+/// Summarize the input in chunks (and the summaries again if necessary)
+/// until it fits into the context window of the model.
+/// Inputs which already fit are returned unchanged.
+pub async fn condense_input(
+  optional_model: &Option<&Model>,
+  opts: &ExecOptions,
+  input: &str,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+  let secrets_path_str = get_secrets_path_str(opts);
+  let full_config = get_full_config(&secrets_path_str)?;
+  let (_, http_req) =
+    get_http_req(optional_model, &secrets_path_str, &full_config)?;
+  let max_tokens =
+    context::get_chunk_tokens(&http_req.model, http_req.max_tokens);
+  let count_tokens = |text: &str| estimate_tokens(&http_req.provider, text);
+  // Only the summaries are requested, not the final output format.
+  // They are neither confirmed nor cached one by one.
+  let chunk_opts = ExecOptions {
+    is_json: false,
+    strict_json: false,
+    json_schema: None,
+    json_repair: false,
+    json_meta: false,
+    output: None,
+    extract: None,
+    exit_code_from: None,
+    images: vec![],
+    stop_sequences: vec![],
+    prompt_wrappers: vec![],
+    system_prompts: vec![CHUNK_SUMMARY_PROMPT.to_string()],
+    confirm: false,
+    yes: true,
+    cache: false,
+    no_cache: true,
+    print_prompt_tokens: false,
+    ..opts.clone()
+  };
+
+  let mut text = input.to_string();
+  for _ in 0..MAX_CHUNK_ROUNDS {
+    if count_tokens(&text) <= max_tokens {
+      return Ok(text);
+    }
+    let chunks = context::split_into_chunks(&text, max_tokens, count_tokens);
+    let mut summaries = vec![];
+    for (index, chunk) in chunks.iter().enumerate() {
+      if !opts.is_raw {
+        eprintln!("Summarizing chunk {} of {} …", index + 1, chunks.len());
+      }
+      let result = get_ai_result(optional_model, &chunk_opts, chunk).await?;
+      summaries.push(result.content.trim().to_string());
+    }
+    text = summaries.join("\n\n") + "\n";
+  }
+
+  if count_tokens(&text) <= max_tokens {
+    Ok(text)
+  } else {
+    Err(format!(
+      "Input is still too large after {MAX_CHUNK_ROUNDS} summarization passes"
+    ))?
+  }
+}
+
+/// Get the model for a helper command from the `--model` flag,
+/// the config key (e.g. `rename_model`), or use the default model
+fn get_helper_model(
+  opts: &ExecOptions,
+  config_key: &str,
+  default_model: Model,
+) -> Model {
+  if let Some(model) = &opts.model {
+    return model.clone();
+  }
+
+  get_full_config(&get_secrets_path_str(opts))
+    .ok()
+    .and_then(|full_config| full_config.get(config_key).cloned())
+    .filter(|model_str| !model_str.is_empty())
+    .and_then(|model_str| match model_str.parse::<Model>() {
+      Ok(model) => Some(model),
+      Err(err) => {
+        eprintln!("Warning: Ignoring invalid {config_key}: {err}");
+        None
+      }
+    })
+    .unwrap_or(default_model)
+}
+
+pub async fn generate_changelog(
+  opts: &ExecOptions,
+  commit_hash: &str,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+  let output = std::process::Command::new("git")
+    .args([
+      "log",
+      "--date=short",
+      "--pretty=format:%cd - %s%d", // date - subject (refs)
+      &format!("{}..HEAD", commit_hash),
+    ])
+    .output()
+    .expect("Failed to execute git command");
+
+  let changelog = String::from_utf8_lossy(&output.stdout);
+
+  let prompt = format!(
+    "Summarize the following git commit log into a concise markdown changelog.\n
+    Only include user-facing changes (i.e. no code refactorings or similar).\n
+    Use the tags to group the changes, and if there are no tags use the dates.\n
+    Include the date and the tag in the header.\n
+    Don't sub-categorize the changes, just list them.\n
+    Insert a blank line after each header and sub-header.\n
+    \n\n{changelog}"
+  );
+
+  let model = get_helper_model(
+    opts,
+    "changelog_model",
+    Model::Model(Provider::OpenAI, "gpt-4o".to_string()),
+  );
+
+  exec_tool(&Some(&model), opts, &prompt).await
+}
+
+#[derive(Deserialize)]
+pub struct FileAnalysis {
+  pub description: String,
+  pub timestamp: Option<String>,
+}
+
+/// Range of PDF pages (e.g. `3`, `1-3`, or `2-` for all pages from 2 on)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PageRange {
+  pub start: u32,
+  pub end: Option<u32>,
+}
+
+impl std::str::FromStr for PageRange {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let parse_page = |page_str: &str| {
+      page_str
+        .trim()
+        .parse::<u32>()
+        .ok()
+        .filter(|page| *page > 0)
+        .ok_or(format!(
+          "Invalid page range \"{s}\". Use e.g. 3, 1-3, or 2- (pages start at 1)"
+        ))
+    };
+
+    let range = match s.split_once('-') {
+      Some((start, "")) => PageRange {
+        start: parse_page(start)?,
+        end: None,
+      },
+      Some((start, end)) => PageRange {
+        start: parse_page(start)?,
+        end: Some(parse_page(end)?),
+      },
+      None => PageRange {
+        start: parse_page(s)?,
+        end: Some(parse_page(s)?),
+      },
+    };
+
+    if range.end.is_some_and(|end| end < range.start) {
+      return Err(format!("Invalid page range \"{s}\". End is before start"));
+    }
+
+    Ok(range)
+  }
+}
+
+/// Extract the text of the PDF (or only of the pages in the range)
+fn extract_pdf_text(
+  file_path: &str,
+  pages: Option<PageRange>,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+  let Some(range) = pages else {
+    return Ok(
+      pdf_extract::extract_text(file_path)
+        .map_err(|e| format!("Failed to extract PDF text: {}", e))?,
+    );
+  };
+
+  let mut doc = pdf_extract::Document::load(file_path)
+    .map_err(|e| format!("Failed to load PDF: {}", e))?;
+  if doc.is_encrypted() {
+    doc
+      .decrypt("")
+      .map_err(|e| format!("Failed to decrypt PDF: {}", e))?;
+  }
+
+  let page_count = doc.get_pages().len() as u32;
+  if range.start > page_count {
+    return Err(
+      format!(
+        "Page {} is out of range, \"{file_path}\" only has {page_count} page(s)",
+        range.start
+      )
+      .into(),
+    );
+  }
+  let end = range.end.unwrap_or(page_count).min(page_count);
+  let other_pages: Vec<u32> = (1..=page_count)
+    .filter(|page| *page < range.start || *page > end)
+    .collect();
+  doc.delete_pages(&other_pages);
+
+  let mut text = String::new();
+  pdf_extract::output_doc(
+    &doc,
+    &mut pdf_extract::PlainTextOutput::new(&mut text),
+  )
+  .map_err(|e| format!("Failed to extract PDF text: {}", e))?;
+  Ok(text)
+}
+
+pub async fn analyze_file_content(
+  opts: &ExecOptions,
+  file_path: &str,
+  pages: Option<PageRange>,
+) -> Result<FileAnalysis, Box<dyn Error + Send + Sync>> {
+  let content = if file_path.to_lowercase().ends_with(".pdf") {
+    extract_pdf_text(file_path, pages)?
+  } else {
+    std::fs::read_to_string(file_path)?
+  };
+
+  let prompt = format!(
     "Analyze following file content and return a file analysis JSON object:\n\
     \n\
     {content}\n",
@@ -541,19 +3443,28 @@ pub async fn analyze_file_content(
       "additionalProperties": false,
     },
   }));
-  let secrets_path_str = get_secrets_path_str();
-  let full_config = get_full_config(&secrets_path_str)?;
-  let (_used_model, http_req) = get_http_req(
-    &Some(&Model::Model(Provider::OpenAI, "gpt-4o-mini".to_string())),
-    &secrets_path_str,
-    &full_config,
-  )?;
-  let req_body_obj = get_req_body_obj(&opts, &http_req, &prompt);
-  let resp = exec_request(&http_req, &req_body_obj).await?;
+  let model = get_helper_model(
+    &opts,
+    "rename_model",
+    Model::Model(Provider::OpenAI, "gpt-4o-mini".to_string()),
+  );
+  // Applies the same options as prompts (e.g. `--oauth` or `--max-tokens`)
+  let PreparedRequest {
+    http_req,
+    req_body_obj,
+    opts,
+    entity_mapping,
+    ..
+  } = prepare_request(&Some(&model), &opts, &prompt)?;
+  let (status, resp_json) =
+    exec_request(&opts, &http_req, &req_body_obj).await?;
 
-  if resp.status().is_success() {
-    let ai_response = resp.json::<AiResponse>().await?;
-    let content = ai_response.choices[0].message.content.clone();
+  if status.is_success() {
+    let (content, _) = parse_ai_response(&http_req.provider, resp_json)?;
+    let content = anonymize::deanonymize(&content, &entity_mapping);
+    if let Some(json_schema) = &opts.json_schema {
+      check_json_schema(&content, &json_schema["schema"])?;
+    }
     let analysis: FileAnalysis =
       serde_json::from_str(&content).map_err(|e| {
         format!(
@@ -564,67 +3475,320 @@ pub async fn analyze_file_content(
       })?;
     Ok(analysis)
   } else {
-    let json_val = resp.json::<Value>().await?;
-    let json_str = serde_json::to_string_pretty(&json_val).unwrap();
-    Err(json_str.into())
+    Err(fmt_error_body(&resp_json).into())
   }
 }
 
 pub async fn extract_text_from_file(
   opts: &ExecOptions,
   file_path: &str,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-  let file_content = std::fs::read(file_path)?;
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+  let model = &get_helper_model(
+    opts,
+    "ocr_model",
+    Model::Model(Provider::OpenAI, "gpt-4o".to_string()),
+  );
+  // Downloads use the same client (e.g. with the proxy) as the requests
+  let secrets_path_str = get_secrets_path_str(opts);
+  let full_config = get_full_config(&secrets_path_str)?;
+  let (_used_model, http_req) =
+    get_http_req(&Some(model), &secrets_path_str, &full_config)?;
+  let client = get_client(opts, &http_req)?;
+  let (file_content, media_type) = load_input_file(client, file_path).await?;
   let base64_content =
     base64::engine::general_purpose::STANDARD.encode(&file_content);
-  let model_id = "gpt-4o";
-  let model = &Model::Model(Provider::OpenAI, model_id.to_string());
-  let prompt = json!({
-    "model": format!("{model_id}"),
-    "max_tokens": default_req_for_model(model).max_tokens,
-    "messages": [{
-      "role": "user",
-      "content": [
-        {
-          "type": "text",
-          "text": "Extract and return all text from this image."
-        },
-        {
-          "type": "image_url",
-          "image_url": {
-            "url": format!("data:image/jpeg;base64,{base64_content}")
-          }
-        }
-      ]
-    }]
-  })
-  .to_string();
+  // Attached like an `--image` to get the native format of each provider
+  let opts = ExecOptions {
+    images: vec![format!("data:{media_type};base64,{base64_content}")],
+    ..opts.clone()
+  };
 
-  exec_tool(&Some(model), &opts, &prompt).await
+  exec_tool(
+    &Some(model),
+    &opts,
+    "Extract and return all text from this image.",
+  )
+  .await
 }
 
 pub async fn prompt_with_lang_cntxt(
   opts: &ExecOptions,
   prog_lang: &str,
   prompt: Vec<String>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
   let system_prompt = format!(
     "You're a professional {prog_lang} developer.\n
     Answer the following question in the context of {prog_lang}.\n
     Keep your answer concise and to the point.\n"
   );
 
-  let model = Model::Model(
-    Provider::Anthropic,
-    "claude-3-5-sonnet-latest".to_string(), //
+  let model = Model::Model(
+    Provider::Anthropic,
+    "claude-3-5-sonnet-latest".to_string(), //
+  );
+
+  exec_tool(
+    &Some(&model),
+    opts,
+    &(system_prompt.to_owned() + &prompt.join(" ")), //
+  )
+  .await
+}
+
+pub async fn brainstorm(
+  opts: &ExecOptions,
+  count: u32,
+  prompt: Vec<String>,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+  let format_instruction = if opts.is_json {
+    format!(
+      "Return only a JSON object with a single key `ideas` \
+      whose value is an array of exactly {count} strings.\n"
+    )
+  } else {
+    format!(
+      "Return only a numbered list of exactly {count} items \
+      with one idea per line (e.g. `1. Idea`).\n"
+    )
+  };
+  let system_prompt = format!(
+    "Brainstorm {count} distinct ideas for the following request.\n\
+    {format_instruction}\
+    Don't add an introduction, explanations, or closing remarks.\n\n"
+  );
+  let user_input = system_prompt + &prompt.join(" ");
+
+  if !opts.is_json || opts.print_prompt_tokens {
+    return exec_tool(&None, opts, &user_input).await;
+  }
+
+  // JSON mode requires an object, but the ideas are printed as a bare array
+  let mut result = get_ai_result(&None, opts, &user_input).await?;
+  result.content = get_ideas_array(&result.content)?;
+  output_ai_result(opts, &result)?;
+  Ok(Some(result.content))
+}
+
+/// Get the array of ideas from a `{"ideas": […]}` object
+fn get_ideas_array(content: &str) -> Result<String, String> {
+  let json: Value = serde_json::from_str(content)
+    .map_err(|err| format!("Response isn't valid JSON: {err}"))?;
+  match &json["ideas"] {
+    ideas @ Value::Array(_) => {
+      serde_json::to_string_pretty(ideas).map_err(|err| err.to_string())
+    }
+    _ => Err(format!("Response has no array of ideas:\n\n{content}")),
+  }
+}
+
+pub async fn outline(
+  opts: &ExecOptions,
+  depth: Option<u32>,
+  text: &str,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+  if text.trim().is_empty() {
+    Err("No text to outline was provided")?;
+  }
+
+  let depth_instruction = match depth {
+    Some(depth) => format!("Use at most {depth} levels of nesting.\n"),
+    None => "".to_string(),
+  };
+  let system_prompt = format!(
+    "Create a hierarchical outline of the following content.\n
+    Return only a nested markdown list with `-` bullets \
+    and 2 spaces of indentation per level.\n
+    {depth_instruction}
+    Capture the structure of the content instead of summarizing it.\n
+    Don't add an introduction, explanations, or closing remarks.\n\n"
+  );
+
+  exec_tool(
+    &opts.model.as_ref(),
+    opts,
+    &(system_prompt.to_owned() + text), //
+  )
+  .await
+}
+
+/// Explain what the shell command does and warn about destructive effects
+pub async fn explain_command(
+  opts: &ExecOptions,
+  command: &str,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+  if command.trim().is_empty() {
+    Err("No command to explain was provided")?;
+  }
+
+  let system_prompt =
+    "Explain concisely what the following shell command does.\n
+    Go through its programs, flags, and arguments in a short markdown list.\n
+    If it can delete, overwrite, or irreversibly change any data \
+    or the system, end with a `**Warning:**` line \
+    which describes the destructive effects.\n
+    Don't add an introduction or closing remarks.\n\n";
+
+  exec_tool(
+    &opts.model.as_ref(),
+    opts,
+    &format!("{system_prompt}```sh\n{}\n```", command.trim()),
+  )
+  .await
+}
+
+/// Name of the user's shell (e.g. `zsh`)
+fn get_shell_name() -> String {
+  env::var("SHELL")
+    .ok()
+    .and_then(|shell| shell.rsplit('/').next().map(str::to_string))
+    .filter(|name| !name.is_empty())
+    .unwrap_or("sh".to_string())
+}
+
+/// Generate a single shell command which does what the description says
+pub async fn generate_command(
+  opts: &ExecOptions,
+  description: &str,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+  if description.trim().is_empty() {
+    Err("No description of the command was provided")?;
+  }
+
+  let prompt = format!(
+    "Return only a single {} command which does the following.\n
+    Don't add any explanations or markdown code fences.\n\n{description}",
+    get_shell_name()
+  );
+  let result = get_ai_result(&opts.model.as_ref(), opts, &prompt).await?;
+  let command = shell::strip_code_fence(&result.content);
+  if command.is_empty() {
+    Err("The model didn't return a command")?;
+  }
+
+  Ok(command)
+}
+
+/// Run the command with the user's `$SHELL` after asking for confirmation
+/// and return its exit code.
+/// Generated commands are always confirmed (even with `--yes`).
+pub fn run_command(
+  opts: &ExecOptions,
+  command: &str,
+) -> Result<i32, Box<dyn Error + Send + Sync>> {
+  let destructive_reason = shell::get_destructive_reason(command);
+
+  if !std::io::stdin().is_terminal() {
+    Err("Running the command requires an interactive terminal")?;
+  }
+  if let Some(reason) = &destructive_reason {
+    eprintln!(
+      "{}",
+      fmt_output(
+        opts,
+        &cformat!("<red>⚠️ The command might be destructive ({reason})</red>")
+      )
+    );
+  }
+  eprint!("Run the command? [y/N] ");
+  let mut answer = String::new();
+  std::io::stdin().read_line(&mut answer)?;
+  if !["y", "yes"].contains(&answer.trim().to_lowercase().as_str()) {
+    Err("Command was not run")?;
+  }
+
+  let shell = env::var("SHELL").unwrap_or("sh".to_string());
+  let status = std::process::Command::new(shell)
+    .arg("-c")
+    .arg(command)
+    .status()?;
+
+  Ok(status.code().unwrap_or(1))
+}
+
+/// Send two prompt variants to the same model concurrently
+/// and print both answers with labels
+pub async fn compare_prompts(
+  optional_model: &Option<&Model>,
+  opts: &ExecOptions,
+  prompt_a: &str,
+  prompt_b: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+  // Both answers would be written to the same file
+  let opts = ExecOptions {
+    output: None,
+    ..opts.clone()
+  };
+  let results = futures::future::join_all([
+    get_ai_result(optional_model, &opts, prompt_a),
+    get_ai_result(optional_model, &opts, prompt_b),
+  ])
+  .await;
+
+  for ((label, prompt), result) in
+    [("A", prompt_a), ("B", prompt_b)].iter().zip(results)
+  {
+    if !opts.is_raw {
+      println!(
+        "{}",
+        fmt_stdout(&opts, &cformat!("<bold>Prompt {label}:</bold> {prompt}\n"))
+      );
+    }
+    match result {
+      Ok(result) => output_ai_result(&opts, &result)?,
+      Err(err) => eprintln!(
+        "{}",
+        fmt_output(&opts, &cformat!("<red>ERROR:\n{}</red>\n", err))
+      ),
+    }
+  }
+
+  Ok(())
+}
+
+pub async fn analyze_sentiment(
+  opts: &ExecOptions,
+  text: &str,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+  if text.trim().is_empty() {
+    Err("No text to analyze was provided")?;
+  }
+
+  let prompt = format!(
+    "Analyze the sentiment of following text \
+    and return a sentiment analysis JSON object:\n\
+    \n\
+    {text}\n",
   );
+  let mut opts = opts.clone();
 
-  exec_tool(
-    &Some(&model),
+  opts.json_schema = Some(json!({
+    "name": "sentiment_analysis",
+    "strict": true,
+    "schema": {
+      "type": "object",
+      "properties": {
+        "label": {
+          "type": "string",
+          "enum": [ "positive", "neutral", "negative" ],
+        },
+        "score": {
+          "type": "number",
+          "description":
+            "Confidence of the label between 0 and 1 (e.g. 0.95)",
+        }
+      },
+      "required": [ "label", "score" ],
+      "additionalProperties": false,
+    },
+  }));
+
+  let model = get_helper_model(
     &opts,
-    &(system_prompt.to_owned() + &prompt.join(" ")), //
-  )
-  .await
+    "sentiment_model",
+    Model::Model(Provider::OpenAI, "gpt-4o-mini".to_string()),
+  );
+
+  exec_tool(&Some(&model), &opts, &prompt).await
 }
 
 #[cfg(test)]
@@ -640,10 +3804,1254 @@ mod tests {
         is_raw: false,
         is_json: false,
         json_schema: None,
+        ..Default::default()
       },
-      &prompt,
+      prompt,
     )
     .await;
     assert!(result.is_err());
   }
+
+  #[test]
+  fn test_system_role() {
+    let opts = ExecOptions {
+      system_prompts: vec!["Be brief".to_string()],
+      ..Default::default()
+    };
+    let reasoning_req =
+      default_req_for_model(&Model::Model(Provider::OpenAI, "o1".to_string()));
+    let body = get_req_body_obj(&opts, &reasoning_req, "Hello").unwrap();
+    assert_eq!(body["messages"][0]["role"], "developer");
+
+    let opts = ExecOptions {
+      system_role: Some("instructions".to_string()),
+      ..opts
+    };
+    let groq_req = default_req_for_model(&Model::default());
+    let body = get_req_body_obj(&opts, &groq_req, "Hello").unwrap();
+    assert_eq!(body["messages"][0]["role"], "instructions");
+  }
+
+  #[test]
+  fn test_params_passthrough() {
+    let opts = ExecOptions {
+      params: Map::from_iter([("seed".to_string(), json!(42))]),
+      ..Default::default()
+    };
+    let req = default_req_for_model(&Model::Model(
+      Provider::OpenAI,
+      "gpt-4o-mini".to_string(),
+    ));
+    let body = get_req_body_obj(&opts, &req, "Hello").unwrap();
+    assert_eq!(body["seed"], json!(42));
+    assert!(body["seed"].is_number());
+  }
+
+  #[test]
+  fn test_anthropic_multi_block_response() {
+    let response: AnthropicAiResponse = serde_json::from_value(json!({
+      "content": [
+        { "type": "thinking", "thinking": "Hmm", "signature": "abc" },
+        { "type": "text", "text": "The Titanic sank " },
+        { "type": "tool_use", "id": "1", "name": "search", "input": {} },
+        { "type": "text", "text": "in 1912." },
+      ]
+    }))
+    .unwrap();
+    assert_eq!(response.text(), "The Titanic sank in 1912.");
+    assert_eq!(response.thinking(), Some("Hmm".to_string()));
+  }
+
+  #[test]
+  fn test_anthropic_json_schema() {
+    let opts = ExecOptions {
+      json_schema: Some(json!({
+        "name": "requested_json_schema",
+        "strict": true,
+        "schema": { "type": "object", "properties": {} },
+      })),
+      ..Default::default()
+    };
+    let req = default_req_for_model(&Model::Model(
+      Provider::Anthropic,
+      "claude-3-5-haiku-latest".to_string(),
+    ));
+    let body = get_req_body_obj(&opts, &req, "Hello").unwrap();
+    assert_eq!(body["tools"][0]["name"], JSON_SCHEMA_TOOL);
+    assert_eq!(body["tools"][0]["input_schema"]["type"], "object");
+    assert_eq!(body["tool_choice"]["name"], JSON_SCHEMA_TOOL);
+
+    let response: AnthropicAiResponse = serde_json::from_value(json!({
+      "content": [{
+        "type": "tool_use",
+        "id": "1",
+        "name": JSON_SCHEMA_TOOL,
+        "input": { "age": 42 },
+      }]
+    }))
+    .unwrap();
+    assert_eq!(response.text(), r#"{"age":42}"#);
+
+    // Thinking doesn't allow forcing the tool
+    let thinking_opts = ExecOptions {
+      thinking_budget: Some(1024),
+      ..opts.clone()
+    };
+    let body = get_req_body_obj(&thinking_opts, &req, "Hello").unwrap();
+    assert_eq!(body["tool_choice"], json!({ "type": "auto" }));
+    assert_eq!(body["thinking"]["budget_tokens"], 1024);
+
+    let response: AnthropicAiResponse = serde_json::from_value(json!({
+      "content": [
+        { "type": "thinking", "thinking": "Hmm", "signature": "x" },
+        { "type": "text", "text": "Here you go:" },
+        {
+          "type": "tool_use",
+          "id": "1",
+          "name": JSON_SCHEMA_TOOL,
+          "input": { "age": 42 },
+        },
+      ]
+    }))
+    .unwrap();
+    assert_eq!(response.text(), r#"{"age":42}"#);
+
+    let groq_req = default_req_for_model(&Model::default());
+    assert_eq!(
+      get_req_body_obj(&opts, &groq_req, "Hello"),
+      Err("Groq doesn't support a JSON schema mode".to_string())
+    );
+  }
+
+  #[test]
+  fn test_unsupported_modes() {
+    let anthropic_req = default_req_for_model(&Model::Model(
+      Provider::Anthropic,
+      "claude-3-5-haiku-latest".to_string(),
+    ));
+    let opts = ExecOptions {
+      is_json: true,
+      ..Default::default()
+    };
+    assert_eq!(
+      get_req_body_obj(&opts, &anthropic_req, "Hello"),
+      Err("Anthropic doesn't support a JSON mode".to_string())
+    );
+
+    let opts = ExecOptions {
+      thinking_budget: Some(1024),
+      ..Default::default()
+    };
+    let groq_req = default_req_for_model(&Model::default());
+    assert_eq!(
+      get_req_body_obj(&opts, &groq_req, "Hello"),
+      Err("Groq doesn't support thinking".to_string())
+    );
+
+    let opts = ExecOptions {
+      thinking_budget: Some(u32::MAX),
+      ..Default::default()
+    };
+    let body = get_req_body_obj(&opts, &anthropic_req, "Hello").unwrap();
+    assert_eq!(body["max_tokens"], u32::MAX);
+  }
+
+  #[test]
+  fn test_empty_choices_response() {
+    let result = parse_ai_response(
+      &Provider::OpenAI,
+      json!({ "id": "chatcmpl-123", "choices": [] }),
+    );
+    let err_msg = result.unwrap_err().to_string();
+    assert!(err_msg.contains("Provider returned no choices"));
+    assert!(err_msg.contains("chatcmpl-123"));
+
+    let result = parse_ai_response(
+      &Provider::Anthropic,
+      json!({ "id": "msg_123", "content": [] }),
+    );
+    let err_msg = result.unwrap_err().to_string();
+    assert!(err_msg.contains("Provider returned no content"));
+    assert!(err_msg.contains("msg_123"));
+  }
+
+  #[test]
+  fn test_get_exit_code() {
+    let content = r#"{"ok": false, "result": {"codes": [0, 3]}}"#;
+    assert_eq!(get_exit_code(content, "$.ok"), Ok(1));
+    assert_eq!(get_exit_code(content, "$.result.codes[1]"), Ok(3));
+    assert_eq!(get_exit_code(content, "/result/codes/0"), Ok(0));
+    assert!(get_exit_code(content, "$.missing").is_err());
+    assert!(get_exit_code(content, "$.result").is_err());
+  }
+
+  #[test]
+  fn test_max_tokens_config() {
+    let full_config = HashMap::from([
+      ("openai_api_key".to_string(), "KEY".to_string()),
+      ("groq_api_key".to_string(), "KEY".to_string()),
+      ("openai_max_tokens".to_string(), "16384".to_string()),
+    ]);
+    let get_max_tokens = |provider| {
+      let model = Model::Model(provider, "".to_string());
+      get_api_request(&full_config, "", &model)
+        .unwrap()
+        .max_tokens
+    };
+    assert_eq!(get_max_tokens(Provider::OpenAI), 16384);
+    assert_eq!(get_max_tokens(Provider::Groq), 4096);
+  }
+
+  #[test]
+  fn test_get_ideas_array() {
+    assert_eq!(
+      get_ideas_array(r#"{"ideas": ["A", "B"]}"#),
+      Ok("[\n  \"A\",\n  \"B\"\n]".to_string())
+    );
+    assert!(get_ideas_array(r#"{"ideas": "A"}"#).is_err());
+    assert!(get_ideas_array("1. A").is_err());
+  }
+
+  #[test]
+  fn test_model_prefix_suffix() {
+    let full_config = HashMap::from([
+      ("openai_api_key".to_string(), "KEY".to_string()),
+      ("openai_model_prefix".to_string(), "openai/".to_string()),
+      ("openai_model_suffix".to_string(), ":free".to_string()),
+    ]);
+    let model = Model::Model(Provider::OpenAI, "gpt-4o".to_string());
+    let http_req = get_api_request(&full_config, "", &model).unwrap();
+    assert_eq!(http_req.get_gateway_model(), "openai/gpt-4o:free");
+    let body = get_req_body_obj(&Default::default(), &http_req, "Hi").unwrap();
+    assert_eq!(body["model"], "openai/gpt-4o:free");
+    // The plain id is used for everything else (e.g. saving the last model)
+    assert_eq!(http_req.model, "gpt-4o");
+
+    let model = Model::Model(Provider::OpenAI, "o1-mini".to_string());
+    let http_req = get_api_request(&full_config, "", &model).unwrap();
+    assert_eq!(get_default_system_role(&http_req), "developer");
+
+    let model = Model::Model(Provider::Ollama, "phi4".to_string());
+    let http_req = get_api_request(&full_config, "", &model).unwrap();
+    assert_eq!(http_req.model, "phi4");
+  }
+
+  #[test]
+  fn test_parse_api_keys() {
+    let full_config = HashMap::from([(
+      "openai_api_key".to_string(),
+      "KEY_1, KEY_2,".to_string(),
+    )]);
+    let model = Model::Model(Provider::OpenAI, "gpt-4o".to_string());
+    let http_req = get_api_request(&full_config, "", &model).unwrap();
+    assert_eq!(http_req.api_key, "KEY_1");
+    assert_eq!(http_req.api_keys, vec!["KEY_1", "KEY_2"]);
+    assert_eq!(with_api_key(&http_req, 1).api_key, "KEY_2");
+    assert_eq!(with_api_key(&http_req, 2).api_key, "KEY_1");
+  }
+
+  /// Respond with 401 to all requests which don't use the valid API key
+  async fn serve_key_check(
+    listener: tokio::net::TcpListener,
+    valid_key: &'static str,
+  ) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    loop {
+      let Ok((mut stream, _)) = listener.accept().await else {
+        return;
+      };
+      let mut request = vec![];
+      let mut buffer = [0; 4096];
+      // Read until the end of the JSON body
+      while !request.ends_with(b"}") {
+        match stream.read(&mut buffer).await {
+          Ok(0) | Err(_) => break,
+          Ok(count) => request.extend_from_slice(&buffer[..count]),
+        }
+      }
+      let is_valid =
+        String::from_utf8_lossy(&request)
+          .to_lowercase()
+          .contains(&format!(
+            "authorization: bearer {}",
+            valid_key.to_lowercase()
+          ));
+      let (status, body) = if is_valid {
+        ("200 OK", r#"{"choices":[{"message":{"content":"Hi"}}]}"#)
+      } else {
+        ("401 Unauthorized", r#"{"error":"Invalid API key"}"#)
+      };
+      let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\n\
+        Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+      );
+      let _ = stream.write_all(response.as_bytes()).await;
+    }
+  }
+
+  #[tokio::test]
+  async fn test_api_key_failover() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!(
+      "http://{}/v1/chat/completions",
+      listener.local_addr().unwrap()
+    );
+    tokio::spawn(serve_key_check(listener, "KEY_2"));
+
+    let full_config = HashMap::from([(
+      "together_api_key".to_string(),
+      "KEY_1,KEY_2".to_string(),
+    )]);
+    let model = Model::Model(Provider::Together, "llama".to_string());
+    let http_req = AiRequest {
+      url,
+      ..get_api_request(&full_config, "", &model).unwrap()
+    };
+    let opts = ExecOptions::default();
+    let req_body_obj = get_req_body_obj(&opts, &http_req, "Hello").unwrap();
+
+    let (status, resp_json) =
+      exec_request(&opts, &http_req, &req_body_obj).await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(resp_json["choices"][0]["message"]["content"], "Hi");
+    // The working key is used first for the next requests
+    assert_eq!(
+      get_good_key_indices()
+        .lock()
+        .unwrap()
+        .get(&Provider::Together),
+      Some(&1)
+    );
+  }
+
+  #[test]
+  fn test_fmt_error_body() {
+    assert_eq!(
+      fmt_error_body(&json!({ "error": "Invalid key" })),
+      "{\n  \"error\": \"Invalid key\"\n}"
+    );
+    assert_eq!(
+      fmt_error_body(&json!("<html>502 Bad Gateway</html>\n")),
+      "<html>502 Bad Gateway</html>"
+    );
+    let long_body = "x".repeat(MAX_ERROR_BODY_CHARS + 1);
+    assert_eq!(
+      fmt_error_body(&Value::String(long_body)),
+      format!("{}…", "x".repeat(MAX_ERROR_BODY_CHARS))
+    );
+  }
+
+  #[test]
+  fn test_custom_config_file() {
+    let config_path = env::temp_dir().join("cai_test_custom_config.yaml");
+    std::fs::write(&config_path, "default_model: groq/llama3\n").unwrap();
+    let full_config = get_full_config(config_path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&config_path).unwrap();
+    assert_eq!(
+      full_config.get("default_model"),
+      Some(&"groq/llama3".to_string())
+    );
+
+    let missing_path = env::temp_dir().join("cai_test_missing_config.yaml");
+    assert!(get_full_config(missing_path.to_str().unwrap()).is_err());
+
+    let env_path = Some("env.yaml".to_string());
+    assert_eq!(
+      resolve_config_path(Some("flag.yaml"), env_path.clone()),
+      Some("flag.yaml".to_string())
+    );
+    assert_eq!(
+      resolve_config_path(None, env_path),
+      Some("env.yaml".to_string())
+    );
+    assert_eq!(resolve_config_path(None, Some("".to_string())), None);
+    assert_eq!(resolve_config_path(None, None), None);
+
+    let opts = ExecOptions {
+      config_path: Some(config_path.to_string_lossy().to_string()),
+      ..Default::default()
+    };
+    assert_eq!(get_config_path(&opts), opts.config_path.unwrap());
+
+    let env_vars = get_config_env_vars(
+      [
+        ("CAI_CONFIG".to_string(), "env.yaml".to_string()),
+        ("CAI_OPENAI_API_KEY".to_string(), "sk-1".to_string()),
+      ]
+      .into_iter(),
+    );
+    assert_eq!(env_vars.keys().collect::<Vec<_>>(), ["CAI_OPENAI_API_KEY"]);
+  }
+
+  #[test]
+  fn test_get_usage() {
+    let openai_usage = get_usage(&json!({
+      "usage": { "prompt_tokens": 12, "completion_tokens": 3 },
+    }));
+    let ollama_usage =
+      get_usage(&json!({ "prompt_eval_count": 5, "eval_count": 2 }));
+    assert_eq!(
+      openai_usage,
+      Some(Usage {
+        input_tokens: 12,
+        output_tokens: 3
+      })
+    );
+    assert_eq!(
+      openai_usage.zip(ollama_usage).map(|(a, b)| a + b),
+      Some(Usage {
+        input_tokens: 17,
+        output_tokens: 5
+      })
+    );
+    assert_eq!(
+      get_usage(&json!({ "usage": { "inputTokens": 1, "outputTokens": 4 } })),
+      Some(Usage {
+        input_tokens: 1,
+        output_tokens: 4
+      })
+    );
+    assert_eq!(get_usage(&json!({ "choices": [] })), None);
+  }
+
+  #[test]
+  fn test_reasoning_content() {
+    let parse = |message: Value| {
+      parse_ai_response(
+        &Provider::Groq,
+        json!({ "choices": [{ "message": message }] }),
+      )
+      .unwrap()
+    };
+    assert_eq!(
+      parse(json!({ "content": "42", "reasoning_content": "6 * 7" })),
+      ("42".to_string(), Some("6 * 7".to_string()))
+    );
+    assert_eq!(
+      parse(json!({ "content": "<think>\n6 * 7\n</think>\n\n42" })),
+      ("42".to_string(), Some("6 * 7".to_string()))
+    );
+    assert_eq!(
+      parse(json!({ "content": "Use <think> tags" })),
+      ("Use <think> tags".to_string(), None)
+    );
+  }
+
+  #[test]
+  fn test_anthropic_version_headers() {
+    let model = Model::Model(Provider::Anthropic, "claude-sonnet".to_string());
+    let get_headers = |full_config: HashMap<String, String>| {
+      let http_req = get_api_request(&full_config, "", &model).unwrap();
+      build_request(
+        &reqwest::Client::new(),
+        &Default::default(),
+        &http_req,
+        &json!({}),
+      )
+      .unwrap()
+      .headers()
+      .clone()
+    };
+
+    let headers = get_headers(HashMap::from([(
+      "anthropic_api_key".to_string(),
+      "KEY".to_string(),
+    )]));
+    assert_eq!(headers["anthropic-version"], "2023-06-01");
+    assert!(headers.get("anthropic-beta").is_none());
+
+    let headers = get_headers(HashMap::from([
+      ("anthropic_oauth_token".to_string(), "TOKEN".to_string()),
+      ("anthropic_version".to_string(), "2025-01-01".to_string()),
+      ("anthropic_beta".to_string(), "some-beta".to_string()),
+    ]));
+    assert_eq!(headers["anthropic-version"], "2025-01-01");
+    assert_eq!(headers["anthropic-beta"], "oauth-2025-04-20,some-beta");
+  }
+
+  #[tokio::test]
+  async fn test_load_input_file() {
+    let file_path = env::temp_dir().join("cai_test_input_file.png");
+    std::fs::write(&file_path, b"PNG").unwrap();
+    let file_url = reqwest::Url::from_file_path(&file_path).unwrap();
+    let client = reqwest::Client::new();
+    let result = load_input_file(&client, file_url.as_str()).await;
+    std::fs::remove_file(&file_path).unwrap();
+    assert_eq!(result.unwrap(), (b"PNG".to_vec(), "image/png".to_string()));
+
+    let missing_path = env::temp_dir().join("cai_test_missing_file.jpg");
+    assert!(load_input_file(&client, missing_path.to_str().unwrap())
+      .await
+      .is_err());
+  }
+
+  /// Serve the raw HTTP response to all requests
+  async fn serve_response(
+    listener: tokio::net::TcpListener,
+    response: &'static str,
+  ) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    while let Ok((mut stream, _)) = listener.accept().await {
+      let _ = stream.read(&mut [0; 4096]).await;
+      let _ = stream.write_all(response.as_bytes()).await;
+    }
+  }
+
+  #[tokio::test]
+  async fn test_download_input_file() {
+    let client = reqwest::Client::new();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/receipt", listener.local_addr().unwrap());
+    tokio::spawn(serve_response(
+      listener,
+      "HTTP/1.1 200 OK\r\nContent-Type: image/webp\r\n\
+      Content-Length: 4\r\nConnection: close\r\n\r\nWEBP",
+    ));
+    assert_eq!(
+      load_input_file(&client, &url).await.unwrap(),
+      (b"WEBP".to_vec(), "image/webp".to_string())
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/huge.png", listener.local_addr().unwrap());
+    tokio::spawn(serve_response(
+      listener,
+      "HTTP/1.1 200 OK\r\nContent-Length: 999999999\r\n\
+      Connection: close\r\n\r\n",
+    ));
+    let err = load_input_file(&client, &url).await.unwrap_err();
+    assert!(err.to_string().contains("larger than"));
+  }
+
+  #[test]
+  fn test_fit_into_context_window() {
+    let http_req = default_req_for_model(&Model::Model(
+      Provider::Groq,
+      "llama-3.1-8b-instant".to_string(),
+    ));
+    let long_input = "Hello world\n".repeat(60_000);
+    let req_body_obj =
+      get_req_body_obj(&Default::default(), &http_req, &long_input).unwrap();
+
+    let err = fit_into_context_window(
+      &Default::default(),
+      &http_req,
+      &req_body_obj,
+      &long_input,
+    )
+    .unwrap_err();
+    assert!(err.starts_with("Prompt is ~"), "{err}");
+    assert!(err.contains("allows 131k tokens"), "{err}");
+
+    let opts = ExecOptions {
+      truncation: Some(Truncation::Head),
+      ..Default::default()
+    };
+    let input =
+      fit_into_context_window(&opts, &http_req, &req_body_obj, &long_input)
+        .unwrap()
+        .unwrap();
+    assert!(input.len() < long_input.len() && long_input.ends_with(&input));
+
+    let json_input = format!("[{}1]", "1,".repeat(300_000));
+    let json_body_obj = json!({ "messages": [{ "content": json_input }] });
+    let err =
+      fit_into_context_window(&opts, &http_req, &json_body_obj, &json_input)
+        .unwrap_err();
+    assert!(err.contains("looks like JSON"), "{err}");
+
+    let opts = ExecOptions {
+      no_context_check: true,
+      ..Default::default()
+    };
+    assert_eq!(
+      fit_into_context_window(&opts, &http_req, &req_body_obj, &long_input),
+      Ok(None)
+    );
+
+    let req_body_obj =
+      get_req_body_obj(&Default::default(), &http_req, "Hello").unwrap();
+    assert_eq!(
+      fit_into_context_window(
+        &Default::default(),
+        &http_req,
+        &req_body_obj,
+        "Hello"
+      ),
+      Ok(None)
+    );
+  }
+
+  #[test]
+  fn test_get_models_url() {
+    let get_url = |provider| {
+      get_models_url(&default_req_for_model(&Model::Model(
+        provider,
+        "".to_string(),
+      )))
+    };
+    assert_eq!(
+      get_url(Provider::OpenAI),
+      "https://api.openai.com/v1/models"
+    );
+    assert_eq!(
+      get_url(Provider::Anthropic),
+      "https://api.anthropic.com/v1/models"
+    );
+    assert_eq!(get_url(Provider::Ollama), "http://localhost:11434/api/tags");
+
+    let table = fmt_health_table(&[
+      (Provider::Groq, HealthStatus::Ok, 120),
+      (Provider::OpenAI, HealthStatus::NoApiKey, 0),
+    ]);
+    assert_eq!(
+      strip_ansi_codes(&table).lines().collect::<Vec<_>>(),
+      [
+        "Provider    Status                              Latency",
+        "Groq        ✓ OK                                120 ms",
+        "OpenAI      - No API key",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_extract_json_field() {
+    let content = r#"{"name": "Universe", "age": {"value": 13.8}}"#;
+    assert_eq!(
+      extract_json_field(content, "$.name"),
+      Ok("Universe".to_string())
+    );
+    assert_eq!(
+      extract_json_field(content, "$.age"),
+      Ok("{\n  \"value\": 13.8\n}".to_string())
+    );
+    assert!(extract_json_field(content, "$.missing").is_err());
+    assert!(extract_json_field("No JSON", "$.name").is_err());
+  }
+
+  #[test]
+  fn test_provider_headers() {
+    let config_map = HashMap::from([
+      ("openai_api_key".to_string(), json!("KEY")),
+      ("pool_size".to_string(), json!(4)),
+      (
+        "headers".to_string(),
+        json!({ "openai": { "X-Title": "cai" }, "groq": { "X-Other": "1" } }),
+      ),
+    ]);
+    let full_config = flatten_config(config_map);
+    assert_eq!(full_config.get("pool_size"), Some(&"4".to_string()));
+
+    let model = Model::Model(Provider::OpenAI, "gpt-4o".to_string());
+    let http_req = get_api_request(&full_config, "", &model).unwrap();
+    assert_eq!(
+      http_req.headers,
+      vec![("X-Title".to_string(), "cai".to_string())]
+    );
+  }
+
+  #[test]
+  fn test_openai_org_headers() {
+    let full_config = HashMap::from([
+      ("openai_api_key".to_string(), "KEY".to_string()),
+      ("openai_org".to_string(), "org-123".to_string()),
+      ("openai_project".to_string(), "".to_string()),
+    ]);
+    let model = Model::Model(Provider::OpenAI, "gpt-4o".to_string());
+    let http_req = get_api_request(&full_config, "", &model).unwrap();
+    let request = build_request(
+      &reqwest::Client::new(),
+      &Default::default(),
+      &http_req,
+      &json!({}),
+    )
+    .unwrap();
+
+    assert_eq!(request.headers()["OpenAI-Organization"], "org-123");
+    assert!(!request.headers().contains_key("OpenAI-Project"));
+  }
+
+  #[test]
+  fn test_extract_json() {
+    let json_str = r#"{"a": [1, "}"]}"#;
+    assert_eq!(extract_json(json_str), Some(json_str.to_string()));
+    assert_eq!(
+      extract_json(&format!("```json\n{json_str}\n```")),
+      Some(json_str.to_string())
+    );
+    assert_eq!(
+      extract_json(&format!("Sure, here it is: {json_str}. Anything else?")),
+      Some(json_str.to_string())
+    );
+    assert_eq!(extract_json("No JSON {here}"), None);
+  }
+
+  #[test]
+  fn test_get_backoff_ms() {
+    let backoffs: Vec<u64> = (0..6)
+      .map(|attempt| get_backoff_ms(attempt, 5_000, RetryJitter::None, 0.5))
+      .collect();
+    assert_eq!(backoffs, vec![500, 1000, 2000, 4000, 5000, 5000]);
+
+    for attempt in 0..40 {
+      let max = get_backoff_ms(attempt, 5_000, RetryJitter::None, 0.0);
+      for random in [0.0, 0.3, 0.999] {
+        let full = get_backoff_ms(attempt, 5_000, RetryJitter::Full, random);
+        let equal = get_backoff_ms(attempt, 5_000, RetryJitter::Equal, random);
+        assert!(full <= max);
+        assert!(equal >= max / 2 && equal <= max);
+      }
+    }
+  }
+
+  #[test]
+  fn test_parse_page_range() {
+    assert_eq!(
+      "1-3".parse::<PageRange>(),
+      Ok(PageRange {
+        start: 1,
+        end: Some(3)
+      })
+    );
+    assert_eq!(
+      "2".parse::<PageRange>(),
+      Ok(PageRange {
+        start: 2,
+        end: Some(2)
+      })
+    );
+    assert_eq!(
+      "2-".parse::<PageRange>(),
+      Ok(PageRange {
+        start: 2,
+        end: None
+      })
+    );
+    assert!("0-2".parse::<PageRange>().is_err());
+    assert!("3-1".parse::<PageRange>().is_err());
+    assert!("a-b".parse::<PageRange>().is_err());
+  }
+
+  #[test]
+  fn test_max_tokens_override() {
+    let config_path = env::temp_dir()
+      .join(format!("cai_test_max_tokens_{}.yaml", std::process::id()));
+    let config_path_str = config_path.to_string_lossy().to_string();
+    let model = Model::Model(Provider::Groq, "llama3".to_string());
+    let get_max_tokens = |config: &str, max_tokens: Option<u32>| {
+      std::fs::write(&config_path, config).unwrap();
+      let opts = ExecOptions {
+        config_path: Some(config_path_str.clone()),
+        max_tokens,
+        ..Default::default()
+      };
+      prepare_request(&Some(&model), &opts, "Hello")
+        .map(|prepared| {
+          assert_eq!(
+            prepared.req_body_obj["max_tokens"],
+            prepared.http_req.max_tokens
+          );
+          prepared.http_req.max_tokens
+        })
+        .map_err(|err| err.to_string())
+    };
+
+    let config = "groq_api_key: KEY\ngroq_max_tokens: 1000\n";
+    assert_eq!(get_max_tokens(config, None), Ok(1000));
+    assert_eq!(get_max_tokens(config, Some(50)), Ok(50));
+    let invalid_config = "groq_api_key: KEY\ngroq_max_tokens: lots\n";
+    assert_eq!(
+      get_max_tokens(invalid_config, Some(50)),
+      Err(format!(
+        "Invalid groq_max_tokens \"lots\" in {config_path_str} \
+        (must be a number)"
+      ))
+    );
+
+    std::fs::remove_file(&config_path).unwrap();
+  }
+
+  #[test]
+  fn test_get_display_settings() {
+    let config_path = env::temp_dir()
+      .join(format!("cai_test_display_{}.yaml", std::process::id()));
+    let config_path_str = config_path.to_string_lossy().to_string();
+    let get_settings = |config: &str, theme: Option<&str>| {
+      std::fs::write(&config_path, config).unwrap();
+      let opts = ExecOptions {
+        config_path: Some(config_path_str.clone()),
+        theme: theme.map(str::to_string),
+        ..Default::default()
+      };
+      get_display_settings(&opts).map_err(|err| err.to_string())
+    };
+
+    let config = "wrap_width: 60\nbat_theme: Nord\n";
+    assert_eq!(
+      get_settings(config, None),
+      Ok((Some(60), Some("Nord".to_string())))
+    );
+    assert_eq!(
+      get_settings(config, Some("GitHub")),
+      Ok((Some(60), Some("GitHub".to_string())))
+    );
+    assert_eq!(get_settings("", None), Ok((None, None)));
+    assert_eq!(
+      get_settings("wrap_width: abc\n", None),
+      Err(format!(
+        "Invalid wrap_width \"abc\" in {config_path_str} (must be a number)"
+      ))
+    );
+    assert_eq!(
+      get_settings("bat_theme: Nope\n", None),
+      Err(format!(
+        "Unknown theme \"Nope\" (bat_theme in {config_path_str}). \
+        Use --list-themes to show all themes"
+      ))
+    );
+    assert_eq!(
+      get_settings(config, Some("Nope")),
+      Err(
+        "Unknown theme \"Nope\" (--theme). \
+        Use --list-themes to show all themes"
+          .to_string()
+      )
+    );
+
+    std::fs::remove_file(&config_path).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_exec_tool_prompt_tokens() {
+    let config_path = env::temp_dir().join(format!(
+      "cai_test_prompt_tokens_{}.yaml",
+      std::process::id()
+    ));
+    std::fs::write(&config_path, "groq_api_key: KEY\n").unwrap();
+    let model = Model::Model(Provider::Groq, "llama3".to_string());
+    let opts = ExecOptions {
+      config_path: Some(config_path.to_string_lossy().to_string()),
+      print_prompt_tokens: true,
+      is_raw: true,
+      ..Default::default()
+    };
+
+    // Only the tokens are printed, but there is no reply
+    let result = exec_tool(&Some(&model), &opts, "Hello").await;
+    std::fs::remove_file(&config_path).unwrap();
+    assert_eq!(result.unwrap(), None);
+  }
+
+  #[test]
+  fn test_has_api_key() {
+    let full_config =
+      HashMap::from([("groq_api_key".to_string(), "KEY".to_string())]);
+    assert!(has_api_key(&full_config, &Provider::Groq));
+    assert!(has_api_key(&full_config, &Provider::Ollama));
+    assert!(!has_api_key(&full_config, &Provider::OpenAI));
+  }
+
+  #[test]
+  fn test_anthropic_oauth_token() {
+    let full_config = HashMap::from([(
+      "anthropic_oauth_token".to_string(),
+      "TOKEN".to_string(),
+    )]);
+    let model = Model::Model(Provider::Anthropic, "sonnet".to_string());
+    let http_req = get_api_request(&full_config, "", &model).unwrap();
+    assert_eq!(http_req.api_key, "TOKEN");
+    assert!(http_req.is_oauth);
+
+    let model = Model::Model(Provider::OpenAI, "gpt-4o".to_string());
+    assert!(get_api_request(&full_config, "", &model).is_err());
+
+    // `--oauth` prefers the token over a configured API key
+    let config_path = env::temp_dir()
+      .join(format!("cai_test_oauth_{}.yaml", std::process::id()));
+    std::fs::write(
+      &config_path,
+      "anthropic_api_key: KEY\nanthropic_oauth_token: TOKEN\n",
+    )
+    .unwrap();
+    let opts = ExecOptions {
+      config_path: Some(config_path.to_string_lossy().to_string()),
+      oauth: true,
+      ..Default::default()
+    };
+    let model = Model::Model(Provider::Anthropic, "sonnet".to_string());
+    let prepared = prepare_request(&Some(&model), &opts, "Hello");
+    std::fs::remove_file(&config_path).unwrap();
+    let PreparedRequest {
+      http_req,
+      req_body_obj,
+      ..
+    } = prepared.unwrap();
+    let request = build_request(
+      &reqwest::Client::new(),
+      &opts,
+      &with_api_key(&http_req, 0),
+      &req_body_obj,
+    )
+    .unwrap();
+    assert_eq!(request.headers()["authorization"], "Bearer TOKEN");
+    assert!(request.headers().get("x-api-key").is_none());
+  }
+
+  #[test]
+  fn test_set_yaml_key() {
+    let yaml = "# My config\nopenai_api_key: old\ndefault_model: groq/llama3\n";
+    assert_eq!(
+      set_yaml_key(yaml, "openai_api_key", "sk-new"),
+      "# My config\nopenai_api_key: \"sk-new\"\ndefault_model: groq/llama3\n"
+    );
+    assert_eq!(
+      set_yaml_key(yaml, "date_format", "%Y-%m-%d"),
+      format!("{yaml}date_format: \"%Y-%m-%d\"\n")
+    );
+    assert_eq!(set_yaml_key("", "cache", "true"), "cache: \"true\"\n");
+    assert_eq!(
+      set_yaml_key("a: |\n  x\n\n  y\n\nb: 1\n", "a", "#no"),
+      "a: \"#no\"\n\nb: 1\n"
+    );
+    assert_eq!(
+      set_yaml_key(
+        "headers:\n  openai:\n    X: 1\n- z\nb: 1\n",
+        "headers",
+        "1"
+      ),
+      "headers: \"1\"\nb: 1\n"
+    );
+    assert!(is_known_config_key("cache_ttl"));
+    assert!(is_known_config_key("ollama_model_suffix"));
+    assert!(is_known_config_key("anthropic_max_tokens"));
+    assert!(!is_known_config_key("opneai_api_key"));
+  }
+
+  #[test]
+  fn test_fmt_config_listing() {
+    let listing = [
+      ("default_model", "groq/llama3", "/cai.yaml"),
+      ("groq_api_key", "gsk…abcd", "GROQ_API_KEY"),
+    ]
+    .map(|(key, value, source)| {
+      (key.to_string(), value.to_string(), source.to_string())
+    });
+    let output = fmt_config_listing(
+      "/cai.yaml",
+      &listing,
+      &[(Provider::Groq, true), (Provider::OpenAI, false)],
+    );
+    assert_eq!(
+      strip_ansi_codes(&output),
+      "Config file: /cai.yaml\n\n\
+      Providers (1/2 configured)\n  \
+      ✓ Groq\n  \
+      ✗ OpenAI (no API key)\n\n\
+      Credentials\n  \
+      groq_api_key   gsk…abcd  (from GROQ_API_KEY)\n\n\
+      Settings\n  \
+      default_model  groq/llama3  (from /cai.yaml)\n"
+    );
+  }
+
+  #[test]
+  fn test_mask_secret() {
+    assert_eq!(
+      mask_secret("openai_api_key", "sk-proj-1234567890abcd"),
+      "sk-…abcd"
+    );
+    assert_eq!(mask_secret("anthropic_oauth_token", "short"), "…");
+    assert_eq!(
+      mask_secret("default_model", "openai/gpt-4o"),
+      "openai/gpt-4o"
+    );
+  }
+
+  #[test]
+  fn test_invalid_config_file() {
+    let config_path = env::temp_dir().join("cai_test_invalid_config.yaml");
+    let config_path_str = config_path.to_str().unwrap();
+
+    std::fs::write(&config_path, "openai_api_key: [unclosed\n").unwrap();
+    let err = get_full_config(config_path_str).unwrap_err().to_string();
+    assert!(err.contains("Couldn't load the config file"));
+    assert!(err.contains(config_path_str));
+
+    std::fs::write(&config_path, "- just\n- a list\n").unwrap();
+    let err = get_full_config(config_path_str).unwrap_err().to_string();
+    assert!(err.contains(config_path_str));
+
+    std::fs::write(&config_path, "headers:\n  openai:\n    x-id: 1\n").unwrap();
+    let full_config = get_full_config(config_path_str).unwrap();
+    assert_eq!(full_config["headers.openai.x-id"], "1");
+  }
+
+  #[test]
+  fn test_expand_env_vars() {
+    let get_var = |var: &str| (var == "MY_SECRET").then(|| "abc".to_string());
+    assert_eq!(
+      expand_env_vars("${MY_SECRET}", get_var),
+      Ok("abc".to_string())
+    );
+    assert_eq!(
+      expand_env_vars("key-${MY_SECRET}-${MY_SECRET}", get_var),
+      Ok("key-abc-abc".to_string())
+    );
+    assert_eq!(
+      expand_env_vars("no vars", get_var),
+      Ok("no vars".to_string())
+    );
+    assert_eq!(
+      expand_env_vars("${MISSING}", get_var),
+      Err("MISSING".to_string())
+    );
+  }
+
+  #[test]
+  fn test_estimate_request_tokens() {
+    let req_body_obj = json!({
+      "system": "Be brief",
+      "messages": [
+        { "role": "system", "content": "Answer in German" },
+        {
+          "role": "user",
+          "content": [
+            { "type": "image_url", "image_url": { "url": "https://x.y" } },
+            { "type": "text", "text": "What is this?" },
+          ],
+        },
+      ],
+    });
+    assert_eq!(
+      estimate_request_tokens(&Provider::Anthropic, &req_body_obj),
+      2 + 4 + IMAGE_TOKEN_ESTIMATE + 4
+    );
+  }
+
+  #[test]
+  fn test_add_images() {
+    let images = vec![
+      ImageInput::Url("https://example.com/cat.jpg".to_string()),
+      ImageInput::Base64 {
+        media_type: "image/png".to_string(),
+        data: "iVBOR".to_string(),
+      },
+    ];
+    assert_eq!(
+      load_images(&["data:image/png;base64,iVBOR".to_string()]).unwrap(),
+      images[1..]
+    );
+    let body = json!({ "messages": [{ "role": "user", "content": "What?" }] });
+
+    let mut openai_body = body.clone();
+    add_images(&mut openai_body, &Provider::OpenAI, &images);
+    assert_eq!(
+      openai_body["messages"][0]["content"],
+      json!([
+        {
+          "type": "image_url",
+          "image_url": { "url": "https://example.com/cat.jpg" },
+        },
+        {
+          "type": "image_url",
+          "image_url": { "url": "data:image/png;base64,iVBOR" },
+        },
+        { "type": "text", "text": "What?" },
+      ])
+    );
+
+    let mut anthropic_body = body.clone();
+    add_images(&mut anthropic_body, &Provider::Anthropic, &images[1..]);
+    assert_eq!(
+      anthropic_body["messages"][0]["content"],
+      json!([
+        {
+          "type": "image",
+          "source": {
+            "type": "base64",
+            "media_type": "image/png",
+            "data": "iVBOR",
+          },
+        },
+        { "type": "text", "text": "What?" },
+      ])
+    );
+  }
+
+  #[test]
+  fn test_strip_ansi_codes() {
+    let text = cformat!("<bold>⏱️ 42 ms</bold> | <red>ERROR</red>");
+    assert_eq!(strip_ansi_codes(&text), "⏱️ 42 ms | ERROR");
+  }
+
+  #[test]
+  fn test_fmt_output_no_emoji() {
+    let opts = ExecOptions {
+      no_emoji: true,
+      ..Default::default()
+    };
+    let text = cformat!("<bold>⏱️  42 ms</bold> | <bold>🧠 Groq</bold>");
+    assert_eq!(
+      strip_ansi_codes(&fmt_for_stream(&opts, &text, true)),
+      "Time:  42 ms | Model: Groq"
+    );
+    assert!(fmt_for_stream(&opts, &text, true).contains("\x1b["));
+    // Colors are only kept for terminals
+    assert!(!fmt_for_stream(&opts, &text, false).contains("\x1b["));
+  }
+
+  #[test]
+  fn test_parse_model() {
+    assert_eq!(
+      "anthropic/claude-sonnet".parse::<Model>(),
+      Ok(Model::Model(
+        Provider::Anthropic,
+        "claude-sonnet".to_string()
+      ))
+    );
+    assert_eq!(
+      "llamafile".parse::<Model>(),
+      Ok(Model::Model(Provider::Llamafile, "".to_string()))
+    );
+    assert!("openai".parse::<Model>().is_err());
+    assert!("mistral/large"
+      .parse::<Model>()
+      .unwrap_err()
+      .contains("Valid providers are"));
+  }
+
+  #[test]
+  fn test_system_prompts_order() {
+    let opts = ExecOptions {
+      system_prompts: vec!["Be a pirate".to_string(), "Be brief".to_string()],
+      ..Default::default()
+    };
+
+    let openai_req = default_req_for_model(&Model::Model(
+      Provider::OpenAI,
+      "gpt-4o-mini".to_string(),
+    ));
+    let openai_body = get_req_body_obj(&opts, &openai_req, "Hello").unwrap();
+    assert_eq!(
+      openai_body["messages"],
+      json!([
+        { "role": "system", "content": "Be a pirate" },
+        { "role": "system", "content": "Be brief" },
+        { "role": "user", "content": "Hello" },
+      ])
+    );
+
+    let anthropic_req = default_req_for_model(&Model::Model(
+      Provider::Anthropic,
+      "claude-3-5-haiku-latest".to_string(),
+    ));
+    let anthropic_body =
+      get_req_body_obj(&opts, &anthropic_req, "Hello").unwrap();
+    assert_eq!(anthropic_body["system"], "Be a pirate\n\nBe brief");
+    assert_eq!(
+      anthropic_body["messages"],
+      json!([{ "role": "user", "content": "Hello" }])
+    );
+  }
+
+  #[test]
+  fn test_stop_sequences() {
+    let opts = ExecOptions {
+      stop_sequences: vec!["END".to_string(), "\n\n".to_string()],
+      ..Default::default()
+    };
+    let openai_req = default_req_for_model(&Model::Model(
+      Provider::OpenAI,
+      "gpt-4o-mini".to_string(),
+    ));
+    let openai_body = get_req_body_obj(&opts, &openai_req, "Hello").unwrap();
+    assert_eq!(openai_body["stop"], json!(["END", "\n\n"]));
+
+    let anthropic_req =
+      default_req_for_model(&Model::Model(Provider::Anthropic, "".to_string()));
+    let anthropic_body =
+      get_req_body_obj(&opts, &anthropic_req, "Hello").unwrap();
+    assert_eq!(anthropic_body["stop_sequences"], json!(["END", "\n\n"]));
+
+    let too_many_opts = ExecOptions {
+      stop_sequences: vec!["a", "b", "c", "d", "e"]
+        .into_iter()
+        .map(String::from)
+        .collect(),
+      ..Default::default()
+    };
+    assert_eq!(
+      get_req_body_obj(&too_many_opts, &openai_req, "Hello").unwrap_err(),
+      "OpenAI supports at most 4 stop sequences, but 5 were given"
+    );
+  }
+
+  #[test]
+  fn test_seed() {
+    let opts = ExecOptions {
+      seed: Some(42),
+      ..Default::default()
+    };
+    let openai_req = default_req_for_model(&Model::Model(
+      Provider::OpenAI,
+      "gpt-4o-mini".to_string(),
+    ));
+    let openai_body = get_req_body_obj(&opts, &openai_req, "Hello").unwrap();
+    assert_eq!(openai_body["seed"], json!(42));
+    assert!(get_request_warnings(&opts, &openai_req).is_empty());
+
+    // Unsupported providers only get a warning
+    let anthropic_req =
+      default_req_for_model(&Model::Model(Provider::Anthropic, "".to_string()));
+    let anthropic_body =
+      get_req_body_obj(&opts, &anthropic_req, "Hello").unwrap();
+    assert_eq!(anthropic_body.get("seed"), None);
+    assert_eq!(
+      get_request_warnings(&opts, &anthropic_req),
+      vec!["Anthropic doesn't support a seed, ignoring it"]
+    );
+  }
+
+  #[test]
+  fn test_bedrock_request() {
+    let opts = ExecOptions {
+      system_prompts: vec!["Be brief".to_string()],
+      ..Default::default()
+    };
+    let bedrock_req =
+      default_req_for_model(&Model::Model(Provider::Bedrock, "ha".to_string()));
+    assert_eq!(
+      bedrock_req.url,
+      "https://bedrock-runtime.us-east-1.amazonaws.com\
+      /model/anthropic.claude-3-5-haiku-20241022-v1:0/converse"
+    );
+    assert_eq!(
+      get_req_body_obj(&opts, &bedrock_req, "Hello").unwrap(),
+      json!({
+        "inferenceConfig": { "maxTokens": 4096 },
+        "system": [{ "text": "Be brief" }],
+        "messages": [{ "role": "user", "content": [{ "text": "Hello" }] }],
+      })
+    );
+    assert_eq!(
+      parse_ai_response(
+        &Provider::Bedrock,
+        json!({
+          "output": {
+            "message": { "role": "assistant", "content": [{ "text": "Hi" }] }
+          }
+        })
+      )
+      .unwrap(),
+      ("Hi".to_string(), None)
+    );
+
+    // Slashes of inference profile ARNs must be encoded
+    assert_eq!(
+      get_bedrock_url("eu-west-1", "arn:aws:bedrock:eu-west-1:1:profile/x"),
+      "https://bedrock-runtime.eu-west-1.amazonaws.com\
+      /model/arn:aws:bedrock:eu-west-1:1:profile%2Fx/converse"
+    );
+  }
 }
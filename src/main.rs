@@ -1,18 +1,63 @@
+use std::collections::HashSet;
+use std::env;
 use std::io::stdin;
 use std::io::{read_to_string, IsTerminal};
 
 use cai::{
-  analyze_file_content, exec_tool, extract_text_from_file, generate_changelog,
-  groq_models_pretty, ollama_models_pretty, openai_models_pretty,
-  prompt_with_lang_cntxt, submit_prompt, ExecOptions, Model, Provider,
+  analyze_file_content, analyze_sentiment, bedrock_models_pretty, brainstorm,
+  clear_cache, compare_prompts, condense_input, estimate_tokens,
+  explain_command, extract_text_from_file, fireworks_models_pretty,
+  fmt_config_listing, fmt_output, fmt_stdout, generate_changelog,
+  generate_command, get_ai_result, get_config_listing, get_config_path,
+  get_display_settings, get_full_config, get_model_choices, get_provider_model,
+  get_templates_dir, get_unsupported_keywords, groq_models_pretty, has_api_key,
+  list_themes, load_last_model, load_prompt_template, ollama_models_pretty,
+  openai_models_pretty, outline, output_ai_result, prompt_with_lang_cntxt,
+  render_prompt_template, run_command, run_doctor, set_config_value,
+  submit_prompt, together_models_pretty, wrap_prompt, ApiError, ExecOptions,
+  FileAnalysis, Model, PageRange, Provider, Truncation, PROVIDERS,
+};
+use clap::{
+  builder::styling, crate_version, ArgMatches, CommandFactory, FromArgMatches,
+  Parser, Subcommand, ValueEnum,
 };
-use clap::{builder::styling, crate_version, Parser, Subcommand};
 use color_print::cformat;
+use console::{Key, Term};
 use futures::future::join_all;
+use futures::StreamExt;
 use serde_json::{json, Value};
 
 const CRATE_VERSION: &str = crate_version!();
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum AllFormat {
+  /// Formatted and syntax highlighted blocks
+  Pretty,
+  /// One JSON object per model and line
+  Jsonl,
+  /// A single JSON array with one object per model
+  Json,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum ConfigCommand {
+  /// Set a config value in the config file
+  Set {
+    /// Name of the config key (e.g. openai_api_key)
+    key: String,
+    /// New value of the config key
+    value: String,
+  },
+  /// Print the path of the config file
+  Path,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum CacheCommand {
+  /// Remove all cached responses
+  Clear,
+}
+
 #[derive(Subcommand, Debug, PartialEq)]
 #[clap(args_conflicts_with_subcommands = false, arg_required_else_help(true))]
 enum Commands {
@@ -20,9 +65,8 @@ enum Commands {
   #[clap(visible_alias = "gr")]
   Groq {
     #[clap(help = groq_models_pretty!("Following aliases are available:"))]
-    model: String,
+    model: Option<String>,
     /// The prompt to send to the AI model
-    #[clap(required(true))]
     prompt: Vec<String>,
   },
   /// - Llama 3 shortcut (🏆 Default)
@@ -44,9 +88,8 @@ enum Commands {
       "Following aliases are available
 (Check out https://platform.openai.com/docs/models for all supported model ids):"
     ))]
-    model: String,
+    model: Option<String>,
     /// The prompt to send to the AI model
-    #[clap(required(true))]
     prompt: Vec<String>,
   },
   /// - GPT-4o shortcut
@@ -70,9 +113,8 @@ enum Commands {
 for all supported model ids):"
     ))]
     #[clap(verbatim_doc_comment)] // Include linebreaks
-    model: String,
+    model: Option<String>,
     /// The prompt to send to the AI model
-    #[clap(required(true))]
     prompt: Vec<String>,
   },
   /// - Claude Opus
@@ -107,11 +149,49 @@ for all supported model ids):"
       Get new ones from https://ollama.com/library.\n\
       Following aliases are available:"
     ))]
-    model: String,
+    model: Option<String>,
+    /// The prompt to send to the AI model
+    prompt: Vec<String>,
+  },
+  /// Together AI
+  #[clap(visible_alias = "to")]
+  Together {
+    #[clap(help = together_models_pretty!(
+      "Following aliases are available
+(Check out https://docs.together.ai/docs/serverless-models \
+for all supported model ids):"
+    ))]
+    #[clap(verbatim_doc_comment)] // Include linebreaks
+    model: Option<String>,
+    /// The prompt to send to the AI model
+    prompt: Vec<String>,
+  },
+  /// Fireworks AI
+  #[clap(visible_alias = "fw")]
+  Fireworks {
+    #[clap(help = fireworks_models_pretty!(
+      "Following aliases are available
+(Check out https://fireworks.ai/models for all supported model ids):"
+    ))]
+    #[clap(verbatim_doc_comment)] // Include linebreaks
+    model: Option<String>,
+    /// The prompt to send to the AI model
+    prompt: Vec<String>,
+  },
+  /// AWS Bedrock (needs the `bedrock` feature)
+  #[clap(visible_alias = "br")]
+  Bedrock {
+    #[clap(help = bedrock_models_pretty!(
+      "Following aliases are available
+(Check out https://docs.aws.amazon.com/bedrock/latest/userguide/models-supported.html \
+for all supported model ids):"
+    ))]
+    #[clap(verbatim_doc_comment)] // Include linebreaks
+    model: Option<String>,
     /// The prompt to send to the AI model
     prompt: Vec<String>,
   },
-  /// Simultaneously send prompt to each provider's default model:
+  /// Simultaneously send prompt to each configured provider's default model:
   /// - Groq Llama 3.1
   /// - Antropic Claude Sonnet 3.5
   /// - OpenAI GPT-4o mini
@@ -119,6 +199,15 @@ for all supported model ids):"
   /// - Llamafile
   #[clap(verbatim_doc_comment)] // Include linebreaks
   All {
+    /// Output format of the responses
+    #[clap(long, value_enum, default_value_t = AllFormat::Pretty)]
+    format: AllFormat,
+    /// Only use these providers (e.g. anthropic,openai)
+    #[clap(long, value_delimiter = ',')]
+    only: Vec<Provider>,
+    /// Don't use these providers (e.g. ollama,llamafile)
+    #[clap(long, value_delimiter = ',')]
+    exclude: Vec<Provider>,
     /// The prompt to send to the AI models simultaneously
     prompt: Vec<String>,
   },
@@ -130,20 +219,164 @@ for all supported model ids):"
     commit_hash: String,
   },
 
-  /// Analyze and rename a file with timestamp and description
+  /// Analyze and rename files with timestamp and description
   #[clap()]
   Rename {
-    /// The file to analyze and rename
-    file: String,
+    /// Rename all files in directories and their subdirectories
+    #[clap(long, short)]
+    recursive: bool,
+    /// Only print the new names without renaming the files
+    #[clap(long)]
+    dry_run: bool,
+    /// Only analyze these pages of PDFs (e.g. 1-3)
+    #[clap(long, conflicts_with = "first_page")]
+    pages: Option<PageRange>,
+    /// Only analyze the first page of PDFs (Same as --pages 1)
+    #[clap(long)]
+    first_page: bool,
+    /// Number of files to analyze concurrently [default: --jobs or 4]
+    #[clap(long, short)]
+    jobs: Option<usize>,
+    /// Template for the new file name. Available placeholders:
+    /// {timestamp}, {date}, {time}, {desc}, {ext}, {orig}
+    #[clap(
+      long,
+      value_parser = parse_template,
+      default_value = "{timestamp}_{desc}.{ext}",
+      verbatim_doc_comment
+    )]
+    template: String,
+    /// The files, directories, or glob patterns to analyze and rename
+    #[clap(required(true))]
+    files: Vec<String>,
   },
 
   /// Extract text from an image
   #[clap()]
   Ocr {
-    /// The file to extract text from
+    /// The file (path, file:// or http(s):// URL) to extract text from
     file: String,
   },
 
+  /// Analyze the sentiment of a text and return a label and a score
+  #[clap()]
+  Sentiment {
+    /// The text to analyze
+    prompt: Vec<String>,
+  },
+
+  /// Brainstorm ideas and return them as a numbered list
+  #[clap()]
+  Brainstorm {
+    /// Number of ideas to generate
+    #[clap(long, short, default_value_t = 10)]
+    count: u32,
+    /// The topic to brainstorm about
+    #[clap(required(true))]
+    prompt: Vec<String>,
+  },
+
+  /// Create a hierarchical markdown outline of a text (e.g. from stdin)
+  #[clap()]
+  Outline {
+    /// Maximum nesting depth of the outline
+    #[clap(long, short)]
+    depth: Option<u32>,
+    /// The text to outline
+    prompt: Vec<String>,
+  },
+
+  /// Explain what a shell command does and warn about destructive effects
+  #[clap()]
+  Explain {
+    /// The shell command to explain
+    command: Vec<String>,
+  },
+
+  /// Generate a shell command from a description
+  #[clap()]
+  Cmd {
+    /// Run the command with $SHELL after confirming it (even with --yes)
+    #[clap(long)]
+    run: bool,
+    /// What the command should do
+    #[clap(required(true))]
+    description: Vec<String>,
+  },
+
+  /// Send two prompt variants to the same model and compare the answers
+  /// (Uses the model of the `--model` flag or the default model)
+  #[clap(verbatim_doc_comment)]
+  ComparePrompts {
+    /// The first prompt variant
+    prompt_a: String,
+    /// The second prompt variant
+    prompt_b: String,
+  },
+
+  /// Replace names, organizations, and locations with placeholders
+  /// before sending the prompt and restore them in the response
+  #[clap(verbatim_doc_comment)]
+  Anon {
+    /// The prompt to send to the AI model
+    prompt: Vec<String>,
+  },
+
+  /// Use the last used model again
+  /// (Requires `remember_last_model: true` in the config)
+  #[clap(verbatim_doc_comment)]
+  Again {
+    /// The prompt to send to the AI model
+    prompt: Vec<String>,
+  },
+
+  /// Run a prompt template from ~/.config/cai/templates/
+  /// (Built-in: value, short, rewrite, svg, reply)
+  #[clap(verbatim_doc_comment)]
+  Run {
+    /// Name of the template (file name without .yaml, .yml, or .toml)
+    name: String,
+    /// Value of a placeholder as key=value (can be repeated)
+    #[clap(long, value_parser = parse_var)]
+    var: Vec<(String, String)>,
+    /// Values of the remaining placeholders in order
+    args: Vec<String>,
+  },
+
+  /// Print the config values and where they were set
+  #[clap()]
+  Config {
+    #[command(subcommand)]
+    command: Option<ConfigCommand>,
+  },
+
+  /// Manage the response cache
+  #[clap()]
+  Cache {
+    #[command(subcommand)]
+    command: CacheCommand,
+  },
+
+  /// Interactively pick one of the configured models and enter a prompt
+  #[clap()]
+  Pick,
+
+  /// Check which providers are reachable and accept their API keys
+  #[clap()]
+  Doctor {
+    /// Timeout of each check in seconds
+    #[clap(long, default_value_t = 10)]
+    timeout: u64,
+  },
+
+  /// Estimate the number of tokens of a prompt without sending it
+  /// (Uses the provider of the `--model` flag, default: openai)
+  #[clap(verbatim_doc_comment)]
+  Tokens {
+    /// The text to count the tokens of
+    prompt: Vec<String>,
+  },
+
   /////////////////////////////////////////
   //========== LANGUAGE CONTEXTS ==========
   /////////////////////////////////////////
@@ -335,13 +568,45 @@ for all supported model ids):"
   <b>cai cl</b> Which year did the Titanic sink
   <b>cai anthropic claude-3-opus-latest</b> Which year did the Titanic sink
 
+  <dim># Send a prompt to a model in the format provider/model</dim>
+  <b>cai -m openai/gpt-4o</b> Which year did the Titanic sink
+  <b>cai -m groq/ll</b> Which year did the Titanic sink
+
   <dim># Send a prompt to locally running Ollama server</dim>
   <b>cai ollama llama3</b> Which year did the Titanic sink
   <b>cai ol ll</b> Which year did the Titanic sink
 
+  <dim># Ask about an image</dim>
+  <b>cai --image photo.jpg gp</b> What is in this picture?
+
+  <dim># Explain a shell command or generate one and run it after confirming</dim>
+  <b>cai explain</b> 'tar -xzvf archive.tar.gz -C /tmp'
+  <b>cai cmd --run</b> Find all files larger than 100 MB
+
   <dim># Add data via stdin</dim>
   cat main.rs | <b>cai</b> Explain this code
 
+  <dim># Let the model reference exact lines of the piped code</dim>
+  cat main.rs | <b>cai --line-numbers</b> Review this code
+
+  <dim># Put the instruction before the piped data</dim>
+  cat server.log | <b>cai --stdin-after</b> Summarize the following log:
+
+  <dim># Summarize piped data which exceeds the context window in chunks</dim>
+  cat big.log | <b>cai --chunk</b> Summarize this log
+
+  <dim># Only send the end of a log which exceeds the context window</dim>
+  cat big.log | <b>cai --truncate-head</b> What caused the crash?
+
+  <dim># Use stdin as a prompt template</dim>
+  echo 'Translate to French: {{{{args}}}}' | <b>cai</b> Hello world
+
+  <dim># Layer several system prompts (Anthropic gets them concatenated)</dim>
+  <b>cai --system-file persona.txt --system 'Answer in German' gp</b> Hi
+
+  <dim># Set provider specific request parameters (not validated by cai)</dim>
+  <b>cai --param top_p=0.5 --param seed=42 gp</b> Tell me a joke
+
   <dim># Use a JSON schema to specify the output format</dim>
   <b>cai \
     --json-schema='{}' \
@@ -355,15 +620,282 @@ for all supported model ids):"
     .placeholder(styling::AnsiColor::Yellow.on_default())
 )]
 struct Args {
+  #[arg(long, short, help = "Model to use in the format provider/model")]
+  model: Option<Model>,
+
+  #[arg(
+    long,
+    value_name = "PATH",
+    help = "Config file to use instead of the default one (env: CAI_CONFIG)"
+  )]
+  config: Option<String>,
+
   #[arg(long, short, action, help = "Print raw response without any metadata")]
   raw: bool,
 
+  #[arg(
+    long,
+    short,
+    action,
+    help = "Render the markdown response as plain text"
+  )]
+  plain: bool,
+
   #[arg(long, short, action, help = "Prompt LLM in JSON output mode")]
   json: bool,
 
+  #[arg(long, help = "Like --json, but strip any prose and fail without JSON")]
+  strict_json: bool,
+
+  #[arg(
+    long,
+    help = "Print the response with provider, model, timing, and usage as JSON"
+  )]
+  json_meta: bool,
+
   #[arg(long, action, help = "JSON schema to validate the output against")]
   json_schema: Option<String>,
 
+  #[arg(
+    long,
+    value_name = "PATH",
+    conflicts_with = "json_schema",
+    help = "Load the JSON schema from a file"
+  )]
+  json_schema_file: Option<String>,
+
+  #[arg(long, help = "Retry once if the response doesn't match the schema")]
+  json_repair: bool,
+
+  #[arg(long, help = "Reuse cached responses of identical requests")]
+  cache: bool,
+
+  #[arg(long, conflicts_with = "cache", help = "Disable the response cache")]
+  no_cache: bool,
+
+  #[arg(
+    long,
+    value_name = "PATH",
+    help = "Derive exit code from a JSON field (e.g. $.ok)"
+  )]
+  exit_code_from: Option<String>,
+
+  #[arg(
+    long,
+    value_name = "PATH",
+    help = "Only print a field of the JSON response (e.g. $.age)"
+  )]
+  extract: Option<String>,
+
+  #[arg(
+    long,
+    short,
+    help = "Also write the response to the given file \
+      (`cai all` adds the model to the name)"
+  )]
+  output: Option<String>,
+
+  #[arg(long, short, action, help = "Overwrite the output file if it exists")]
+  force: bool,
+
+  #[arg(long, action, help = "Disable colored output (also via NO_COLOR)")]
+  no_color: bool,
+
+  #[arg(
+    long,
+    action,
+    help = "Replace emoji with text (also via CAI_NO_EMOJI)"
+  )]
+  no_emoji: bool,
+
+  #[arg(long, help = "Token budget for Anthropic's extended thinking")]
+  thinking: Option<u32>,
+
+  #[arg(long, help = "Print the reasoning of reasoning models (dimmed)")]
+  show_reasoning: bool,
+
+  #[arg(
+    long,
+    conflicts_with = "truncate_tail",
+    help = "Remove the start of inputs which exceed the context window"
+  )]
+  truncate_head: bool,
+
+  #[arg(
+    long,
+    help = "Remove the end of inputs which exceed the context window"
+  )]
+  truncate_tail: bool,
+
+  #[arg(long, help = "Don't check if the prompt fits into the context window")]
+  no_context_check: bool,
+
+  #[arg(
+    long,
+    value_parser = parse_param,
+    help = "Extra request parameter as key=value (can be repeated)"
+  )]
+  param: Vec<(String, Value)>,
+
+  #[arg(
+    long,
+    value_name = "SEQ",
+    help = "Stop generating at this sequence (can be repeated)"
+  )]
+  stop: Vec<String>,
+
+  #[arg(long, help = "Seed for reproducible outputs (if supported)")]
+  seed: Option<u64>,
+
+  #[arg(
+    long,
+    help = "Max number of output tokens (config: {provider}_max_tokens)"
+  )]
+  max_tokens: Option<u32>,
+
+  #[arg(
+    long,
+    value_parser = parse_header,
+    help = "Extra HTTP header as \"Name: value\" (can be repeated)"
+  )]
+  extra_header: Vec<(String, String)>,
+
+  #[arg(long, help = "Role name for system messages (e.g. developer)")]
+  system_role: Option<String>,
+
+  #[arg(long, help = "Max idle HTTP connections per host (config: pool_size)")]
+  pool_size: Option<usize>,
+
+  #[arg(
+    short,
+    long,
+    help = "Log the raw request and response to stderr (API keys redacted)"
+  )]
+  verbose: bool,
+
+  #[arg(
+    long,
+    help = "Use the Claude OAuth token (config: anthropic_oauth_token)"
+  )]
+  oauth: bool,
+
+  #[arg(long, help = "Use Ollama's native /api/chat endpoint")]
+  ollama_native: bool,
+
+  #[arg(
+    long = "value",
+    global = true,
+    help = "Only output the requested value"
+  )]
+  wrap_value: bool,
+
+  #[arg(long = "short", global = true, help = "Answer as briefly as possible")]
+  wrap_short: bool,
+
+  #[arg(
+    long = "rewrite",
+    global = true,
+    help = "Rewrite the prompt to improve its clarity and style"
+  )]
+  wrap_rewrite: bool,
+
+  #[arg(
+    long = "svg",
+    global = true,
+    help = "Create an SVG image of the prompt"
+  )]
+  wrap_svg: bool,
+
+  #[arg(long = "reply", global = true, help = "Write a reply to the prompt")]
+  wrap_reply: bool,
+
+  #[arg(
+    long,
+    value_name = "N",
+    help = "Max concurrent requests (default: all at once, 1: sequential)"
+  )]
+  jobs: Option<usize>,
+
+  #[arg(
+    long,
+    help = "Wrap width of the response (0: off, config: wrap_width)"
+  )]
+  width: Option<usize>,
+
+  #[arg(
+    short,
+    long,
+    help = "Only print the highlighted response without the metadata header"
+  )]
+  quiet: bool,
+
+  #[arg(long, help = "Print the timing and model header to stdout")]
+  meta_stdout: bool,
+
+  #[arg(long, help = "Syntax highlighting theme (config: bat_theme)")]
+  theme: Option<String>,
+
+  #[arg(long, help = "List all available syntax highlighting themes")]
+  list_themes: bool,
+
+  #[arg(long, help = "Show the estimated cost and ask before sending")]
+  confirm: bool,
+
+  #[arg(short, long, help = "Never ask for confirmation")]
+  yes: bool,
+
+  #[arg(long, help = "Only print the token count of the full prompt")]
+  print_prompt_tokens: bool,
+
+  #[arg(
+    long,
+    help = "Attach an image (path or URL) to the prompt (repeatable)"
+  )]
+  image: Vec<String>,
+
+  #[arg(long, help = "Print a timing breakdown of the request to stderr")]
+  measure: bool,
+
+  #[arg(
+    long,
+    help = "Add the current date to the system prompt (config: with_date)"
+  )]
+  with_date: bool,
+
+  #[arg(long, help = "strftime format for --with-date (config: date_format)")]
+  date_format: Option<String>,
+
+  #[arg(long, help = "Remove common leading whitespace from piped stdin")]
+  dedent: bool,
+
+  #[arg(long, help = "Prefix each line of piped stdin with its line number")]
+  line_numbers: bool,
+
+  #[arg(long, help = "Put piped stdin after the prompt instead of before it")]
+  stdin_after: bool,
+
+  #[arg(long, help = "Summarize oversized stdin in chunks before sending")]
+  chunk: bool,
+
+  #[arg(long, help = "Replace names with placeholders before sending")]
+  anonymize: bool,
+
+  #[arg(
+    long,
+    help = "Proxy URL for all requests (config: proxy, env: HTTPS_PROXY)"
+  )]
+  proxy: Option<String>,
+
+  #[arg(long, help = "System prompt to use (can be repeated)")]
+  system: Vec<String>,
+
+  #[arg(long, help = "File to load a system prompt from (can be repeated)")]
+  system_file: Vec<String>,
+
+  /// All `--system` and `--system-file` prompts in command line order
+  #[arg(skip)]
+  system_prompts: Vec<String>,
+
   #[command(subcommand)]
   command: Option<Commands>,
 
@@ -372,6 +904,44 @@ struct Args {
   prompt: Vec<String>,
 }
 
+/// Parse a `key=value` parameter.
+/// The value is parsed as JSON if possible and used as a string otherwise.
+fn parse_param(param: &str) -> Result<(String, Value), String> {
+  let (key, value) = param
+    .split_once('=')
+    .ok_or(format!("Invalid parameter \"{param}\", expected key=value"))?;
+  let json_value = serde_json::from_str(value)
+    .unwrap_or_else(|_| Value::String(value.to_string()));
+  Ok((key.trim().to_string(), json_value))
+}
+
+/// Parse a `key=value` template variable
+fn parse_var(var: &str) -> Result<(String, String), String> {
+  let (key, value) = var
+    .split_once('=')
+    .ok_or(format!("Invalid variable \"{var}\", expected key=value"))?;
+  Ok((key.trim().to_string(), value.to_string()))
+}
+
+/// Parse a `Name: value` HTTP header
+fn parse_header(header: &str) -> Result<(String, String), String> {
+  let (name, value) = header
+    .split_once(':')
+    .ok_or(format!("Invalid header \"{header}\", expected Name: value"))?;
+  Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+/// Prefix each line with its right aligned line number (e.g. ` 9 | code`)
+fn number_lines(text: &str) -> String {
+  let width = text.lines().count().to_string().len();
+  text
+    .lines()
+    .enumerate()
+    .map(|(index, line)| format!("{:>width$} | {line}", index + 1))
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
 fn capitalize_str(str: &str) -> String {
   let mut chars = str.chars();
   match chars.next() {
@@ -380,316 +950,956 @@ fn capitalize_str(str: &str) -> String {
   }
 }
 
+/// Prompt the model and return the result as a single line JSON object
+async fn get_json_line(
+  model: &Model,
+  opts: &ExecOptions,
+  prompt: &str,
+) -> Value {
+  let start = std::time::Instant::now();
+  let Model::Model(provider, model_id) = model;
+
+  // `ok` and `ms` are short forms for scripts,
+  // `elapsed_ms` matches the metadata of `--json-meta`
+  match get_ai_result(&Some(model), opts, prompt).await {
+    Ok(result) => {
+      for warning in &result.warnings {
+        eprintln!("Warning: {model}: {warning}");
+      }
+      json!({
+        "provider": result.provider,
+        "model": result.model,
+        "ok": true,
+        "content": result.content,
+        "ms": result.elapsed_ms,
+        "elapsed_ms": result.elapsed_ms,
+        "usage": result.usage,
+        "error": null,
+      })
+    }
+    Err(err) => json!({
+      "provider": provider,
+      "model": model_id,
+      "ok": false,
+      "content": null,
+      "ms": start.elapsed().as_millis(),
+      "elapsed_ms": start.elapsed().as_millis(),
+      "usage": null,
+      "error": err
+        .downcast_ref::<ApiError>()
+        .map(|api_err| api_err.body.clone())
+        .unwrap_or(err.to_string()),
+    }),
+  }
+}
+
+/// Add the model to the output file name to keep the results of `cai all`
+/// apart (e.g. `answer.md` -> `answer.openai-gpt-4o-mini.md`)
+fn get_model_output_path(output: &str, model: &Model) -> String {
+  let Model::Model(provider, model_id) = model;
+  let model_name = [provider.to_string().to_lowercase(), model_id.clone()]
+    .into_iter()
+    .filter(|part| !part.is_empty())
+    .collect::<Vec<_>>()
+    .join("-")
+    .replace(['/', ':'], "-");
+  let path = std::path::Path::new(output);
+  match path.extension() {
+    Some(extension) => path
+      .with_extension(format!("{model_name}.{}", extension.to_string_lossy()))
+      .to_string_lossy()
+      .to_string(),
+    None => format!("{output}.{model_name}"),
+  }
+}
+
+/// Combine stdin and the prompt arguments.
+/// If stdin contains a `{{args}}` marker, it's used as a template
+/// and the marker is replaced by the prompt.
+/// Otherwise stdin is prepended to the prompt
+/// or appended to it if `stdin_after` is set.
+fn combine_prompt(stdin: &str, prompt: &[String], stdin_after: bool) -> String {
+  let prompt_str = prompt.join(" ");
+
+  if stdin.contains("{{args}}") {
+    stdin.trim_end().replace("{{args}}", &prompt_str)
+  } else if stdin_after && !stdin.is_empty() {
+    format!("{prompt_str}\n{stdin}")
+  } else {
+    format!("{stdin}{prompt_str}")
+  }
+}
+
+/// Parse a JSON schema and wrap it in the API object for structured outputs
+fn parse_json_schema(schema_str: &str) -> Result<Value, String> {
+  let schema: Value = serde_json::from_str(schema_str)
+    .map_err(|err| format!("Invalid JSON schema: {err}"))?;
+  let mut schema_obj = schema
+    .as_object()
+    .ok_or("Invalid JSON schema: Must be a JSON object")?
+    .clone();
+  schema_obj.insert("additionalProperties".to_string(), false.into());
+  if !schema_obj.contains_key("type") {
+    schema_obj.insert("type".to_string(), "object".into());
+  }
+  let unsupported_keywords =
+    get_unsupported_keywords(&Value::Object(schema_obj.clone()));
+  if !unsupported_keywords.is_empty() {
+    return Err(format!(
+      "Invalid JSON schema: Unsupported keywords {}",
+      unsupported_keywords.join(", ")
+    ));
+  }
+
+  Ok(json!({
+    "name": "requested_json_schema",
+    "strict": true,
+    "schema": schema_obj,
+  }))
+}
+
+/// Get the JSON schema from the `--json-schema` or `--json-schema-file` flag
+fn get_json_schema(
+  schema_str: Option<&str>,
+  schema_file: Option<&str>,
+) -> Result<Option<Value>, String> {
+  if let Some(schema_file) = schema_file {
+    let file_content = std::fs::read_to_string(schema_file).map_err(|err| {
+      format!("Couldn't read JSON schema file \"{schema_file}\": {err}")
+    })?;
+    return parse_json_schema(&file_content)
+      .map(Some)
+      .map_err(|err| format!("{err} (in \"{schema_file}\")"));
+  }
+
+  schema_str.map(parse_json_schema).transpose()
+}
+
+/// Check if the command supports `--short`, `--value`, etc.
+/// (The helper commands use their own prompts)
+fn supports_prompt_wrappers(
+  opts: &ExecOptions,
+  command: &Option<Commands>,
+) -> bool {
+  match command {
+    None => true,
+    Some(
+      Commands::All { .. } | Commands::Again { .. } | Commands::Run { .. },
+    ) => true,
+    Some(command) => get_command_model(opts, command).is_some(),
+  }
+}
+
+/// Load and render the prompt template for `cai run`.
+/// Returns the model, the system prompt, and the prompt.
+/// Stdin is combined with the prompt if it doesn't fill a placeholder.
+fn get_template_run(
+  name: &str,
+  template_args: &[String],
+  vars: &[(String, String)],
+  stdin: &str,
+  stdin_after: bool,
+  model_flag: &Option<Model>,
+) -> Result<(Option<Model>, Option<String>, String), String> {
+  let template = load_prompt_template(&get_templates_dir(), name)?;
+  let (prompt, uses_stdin) =
+    render_prompt_template(&template, template_args, vars, stdin)?;
+  let prompt = if uses_stdin {
+    prompt
+  } else {
+    combine_prompt(stdin, &[prompt], stdin_after)
+  };
+  // The `--model` flag overrides the model of the template
+  let model = match (model_flag, &template.model) {
+    (Some(model), _) => Some(model.clone()),
+    (None, Some(model_str)) => Some(model_str.parse::<Model>()?),
+    (None, None) => None,
+  };
+  Ok((model, template.system, prompt))
+}
+
+/// Get the model of the model subcommands (e.g. `cai groq`)
+fn get_command_model(opts: &ExecOptions, command: &Commands) -> Option<Model> {
+  let fixed_model =
+    |provider, model_id: &str| Model::Model(provider, model_id.to_string());
+  match command {
+    Commands::Groq { model, .. } => {
+      Some(get_provider_model(opts, Provider::Groq, model.clone()))
+    }
+    Commands::Mixtral { .. } => {
+      Some(fixed_model(Provider::Groq, "mixtral-8x7b-32768"))
+    }
+    Commands::Llama3 { .. } => {
+      Some(fixed_model(Provider::Groq, "llama-3.1-8b-instant"))
+    }
+    Commands::Openai { model, .. } => {
+      Some(get_provider_model(opts, Provider::OpenAI, model.clone()))
+    }
+    Commands::Gpt { .. } => Some(fixed_model(Provider::OpenAI, "gpt-4o")),
+    Commands::GptMini { .. } => {
+      Some(fixed_model(Provider::OpenAI, "gpt-4o-mini"))
+    }
+    Commands::Anthropic { model, .. } => {
+      Some(get_provider_model(opts, Provider::Anthropic, model.clone()))
+    }
+    Commands::ClaudeOpus { .. } => {
+      Some(fixed_model(Provider::Anthropic, "claude-3-opus-latest"))
+    }
+    Commands::ClaudeSonnet { .. } => {
+      Some(fixed_model(Provider::Anthropic, "claude-3-5-sonnet-latest"))
+    }
+    Commands::ClaudeHaiku { .. } => {
+      Some(fixed_model(Provider::Anthropic, "claude-3-5-haiku-latest"))
+    }
+    Commands::Llamafile { .. } => Some(fixed_model(Provider::Llamafile, "")),
+    Commands::Ollama { model, .. } => {
+      Some(get_provider_model(opts, Provider::Ollama, model.clone()))
+    }
+    Commands::Together { model, .. } => {
+      Some(get_provider_model(opts, Provider::Together, model.clone()))
+    }
+    Commands::Fireworks { model, .. } => {
+      Some(get_provider_model(opts, Provider::Fireworks, model.clone()))
+    }
+    Commands::Bedrock { model, .. } => {
+      Some(get_provider_model(opts, Provider::Bedrock, model.clone()))
+    }
+    _ => None,
+  }
+}
+
 async fn exec_with_args(args: Args, stdin: &str) {
+  if args.list_themes {
+    list_themes().iter().for_each(|theme| println!("{theme}"));
+    return;
+  }
   let stdin = if stdin.is_empty() {
     "".into()
   } else {
     format!("{}\n", stdin)
   };
-  let opts = ExecOptions {
+  let json_schema = match get_json_schema(
+    args.json_schema.as_deref(),
+    args.json_schema_file.as_deref(),
+  ) {
+    Ok(json_schema) => json_schema,
+    Err(err) => {
+      eprintln!("{}", cformat!("<red>ERROR: {err}</red>"));
+      std::process::exit(1);
+    }
+  };
+  let mut opts = ExecOptions {
     is_raw: args.raw,
-    is_json: args.json,
-    json_schema: args
-      .json_schema
-      .map(|schema_str| {
-        serde_json::from_str(&schema_str).expect("Invalid JSON schema")
-      })
-      .map(|schema: Value| {
-        let mut schema_obj = schema.as_object().unwrap().clone();
-        schema_obj.insert("additionalProperties".to_string(), false.into());
-        if !schema_obj.contains_key("type") {
-          schema_obj.insert("type".to_string(), "object".into());
-        }
-        let api_object = json!({
-          "name": "requested_json_schema",
-          "strict": true,
-          "schema": schema_obj,
-        });
-        api_object
-      }),
+    is_json: args.json || args.strict_json,
+    strict_json: args.strict_json,
+    is_plain: args.plain,
+    json_schema,
+    output: args.output,
+    force: args.force,
+    system_prompts: args.system_prompts,
+    // Colors are also disabled for each stream which isn't a terminal
+    no_color: args.no_color
+      || env::var("NO_COLOR").is_ok_and(|val| !val.is_empty()),
+    no_emoji: args.no_emoji
+      || env::var("CAI_NO_EMOJI").is_ok_and(|val| !val.is_empty()),
+    params: args.param.into_iter().collect(),
+    thinking_budget: args.thinking,
+    system_role: args.system_role,
+    pool_size: args.pool_size,
+    exit_code_from: args.exit_code_from,
+    extract: args.extract,
+    proxy: args.proxy,
+    anonymize: args.anonymize,
+    verbose: args.verbose,
+    measure: args.measure,
+    images: args.image,
+    print_prompt_tokens: args.print_prompt_tokens,
+    confirm: args.confirm,
+    yes: args.yes,
+    wrap_width: args.width,
+    json_repair: args.json_repair,
+    cache: args.cache,
+    no_cache: args.no_cache,
+    oauth: args.oauth,
+    model: args.model.clone(),
+    with_date: args.with_date,
+    date_format: args.date_format,
+    extra_headers: args.extra_header,
+    stop_sequences: args.stop,
+    seed: args.seed,
+    theme: args.theme,
+    quiet: args.quiet,
+    meta_stdout: args.meta_stdout,
+    ollama_native: args.ollama_native,
+    jobs: args.jobs,
+    json_meta: args.json_meta,
+    show_reasoning: args.show_reasoning,
+    max_tokens: args.max_tokens,
+    config_path: args.config.clone(),
+    prompt_wrappers: [
+      ("value", args.wrap_value),
+      ("short", args.wrap_short),
+      ("rewrite", args.wrap_rewrite),
+      ("svg", args.wrap_svg),
+      ("reply", args.wrap_reply),
+    ]
+    .into_iter()
+    .filter(|(_, is_enabled)| *is_enabled)
+    .map(|(name, _)| name.to_string())
+    .collect(),
+    no_context_check: args.no_context_check,
+    truncation: if args.truncate_head {
+      Some(Truncation::Head)
+    } else if args.truncate_tail {
+      Some(Truncation::Tail)
+    } else {
+      None
+    },
+  };
+
+  // Resolved once instead of for every result.
+  // (The config commands must also work with an invalid config.)
+  if !matches!(args.command, Some(Commands::Config { .. })) {
+    match get_display_settings(&opts) {
+      Ok((wrap_width, theme)) => {
+        opts.wrap_width = wrap_width;
+        opts.theme = theme;
+      }
+      Err(err) => {
+        eprintln!(
+          "{}",
+          fmt_output(&opts, &cformat!("<red>ERROR: {err}</red>"))
+        );
+        std::process::exit(1);
+      }
+    }
+  }
+
+  let command_model = args
+    .command
+    .as_ref()
+    .and_then(|command| get_command_model(&opts, command));
+
+  if let (Some(wrapper), false) = (
+    opts.prompt_wrappers.first(),
+    supports_prompt_wrappers(&opts, &args.command),
+  ) {
+    eprintln!(
+      "{}",
+      fmt_output(
+        &opts,
+        &cformat!(
+          "<red>ERROR: --{wrapper} is only supported for prompts \
+          and the model subcommands</red>"
+        )
+      )
+    );
+    std::process::exit(1);
+  }
+
+  // Uses the context window of the model of the subcommand
+  // or else of the `--model` (or the default) model
+  let stdin = if args.chunk && !stdin.is_empty() {
+    let chunk_model = command_model.as_ref().or(args.model.as_ref());
+    match condense_input(&chunk_model, &opts, &stdin).await {
+      Ok(condensed_stdin) => condensed_stdin,
+      Err(err) => {
+        eprintln!(
+          "{}",
+          fmt_output(&opts, &cformat!("<red>ERROR: {err}</red>"))
+        );
+        std::process::exit(1);
+      }
+    }
+  } else {
+    stdin
   };
 
   match args.command {
     None => {
       // No subcommand provided -> Use input as prompt for the default model
       submit_prompt(
-        &None,
+        &args.model.as_ref(),
         &opts,
-        &format!("{stdin}{}", &args.prompt.join(" ")), //
+        &combine_prompt(&stdin, &args.prompt, args.stdin_after),
       )
       .await
     }
     Some(cmd) => match cmd {
-      Commands::Groq { model, prompt } => {
+      Commands::Groq { prompt, .. }
+      | Commands::Mixtral { prompt }
+      | Commands::Llama3 { prompt }
+      | Commands::Openai { prompt, .. }
+      | Commands::Gpt { prompt }
+      | Commands::GptMini { prompt }
+      | Commands::Anthropic { prompt, .. }
+      | Commands::ClaudeOpus { prompt }
+      | Commands::ClaudeSonnet { prompt }
+      | Commands::ClaudeHaiku { prompt }
+      | Commands::Llamafile { prompt }
+      | Commands::Ollama { prompt, .. }
+      | Commands::Together { prompt, .. }
+      | Commands::Fireworks { prompt, .. }
+      | Commands::Bedrock { prompt, .. } => {
         submit_prompt(
-          &Some(&Model::Model(Provider::Groq, model)),
+          &command_model.as_ref(),
           &opts,
-          &format!("{stdin}{}", prompt.join(" ")),
+          &combine_prompt(&stdin, &prompt, args.stdin_after),
         )
         .await
       }
-      Commands::Mixtral { prompt } => {
-        submit_prompt(
-          &Some(&Model::Model(
-            Provider::Groq,
-            "mixtral-8x7b-32768".to_string(),
-          )),
-          &opts,
-          &format!("{stdin}{}", prompt.join(" ")),
-        )
-        .await
+      Commands::All {
+        format,
+        only,
+        exclude,
+        prompt,
+      } => {
+        // Metadata of all models is printed as a single JSON array
+        let format = if args.json_meta {
+          AllFormat::Json
+        } else {
+          format
+        };
+        let full_config =
+          get_full_config(&get_config_path(&opts)).unwrap_or_default();
+        let (models, skipped_models): (Vec<Model>, Vec<Model>) = [
+          Model::Model(
+            Provider::Anthropic,
+            "claude-3-5-sonnet-latest".to_string(),
+          ),
+          Model::Model(Provider::Groq, "llama-3.1-8b-instant".to_string()),
+          Model::Model(Provider::OpenAI, "gpt-4o-mini".to_string()),
+          Model::Model(Provider::Ollama, "llama3".to_string()),
+          Model::Model(Provider::Llamafile, "".to_string()),
+        ]
+        .into_iter()
+        .filter(|Model::Model(provider, _)| {
+          (only.is_empty() || only.contains(provider))
+            && !exclude.contains(provider)
+        })
+        // Explicitly selected providers show the missing key error
+        .partition(|Model::Model(provider, _)| {
+          !only.is_empty() || has_api_key(&full_config, provider)
+        });
+
+        for Model::Model(provider, _) in &skipped_models {
+          eprintln!(
+            "{}",
+            fmt_output(
+              &opts,
+              &cformat!("<dim>⏭️ Skipped {provider} (no API key)</dim>")
+            )
+          );
+        }
+
+        let prompt_str = wrap_prompt(
+          &opts.prompt_wrappers,
+          &combine_prompt(&stdin, &prompt, args.stdin_after),
+        );
+
+        // Results are buffered and printed in the declared order
+        if format != AllFormat::Pretty {
+          let json_lines = join_all(
+            models
+              .iter()
+              .map(|model| get_json_line(model, &opts, &prompt_str)),
+          )
+          .await;
+          let json_output = if format == AllFormat::Json {
+            serde_json::to_string_pretty(&json_lines).unwrap()
+          } else {
+            json_lines
+              .iter()
+              .map(|json_line| json_line.to_string())
+              .collect::<Vec<_>>()
+              .join("\n")
+          };
+          println!("{json_output}");
+          if let Some(output) = &opts.output {
+            if let Err(err) = std::fs::write(output, json_output + "\n") {
+              eprintln!(
+                "{}",
+                fmt_output(
+                  &opts,
+                  &cformat!("<red>ERROR: Couldn't write {output}: {err}</red>")
+                )
+              );
+              std::process::exit(1);
+            }
+          }
+          return;
+        }
+
+        // Each model writes its response to its own output file.
+        // The header labels the responses, so it must also be redirected.
+        let model_opts: Vec<ExecOptions> = models
+          .iter()
+          .map(|model| ExecOptions {
+            output: opts
+              .output
+              .as_ref()
+              .map(|output| get_model_output_path(output, model)),
+            meta_stdout: true,
+            ..opts.clone()
+          })
+          .collect();
+        let results = join_all(models.iter().zip(&model_opts).map(
+          |(model, model_opts)| async {
+            get_ai_result(&Some(model), model_opts, &prompt_str).await
+          },
+        ))
+        .await;
+
+        for ((model, model_opts), result) in
+          models.iter().zip(&model_opts).zip(results)
+        {
+          let output_result = result
+            .and_then(|ai_result| output_ai_result(model_opts, &ai_result));
+          if let Err(err) = output_result {
+            let err_fmt = match err.downcast_ref::<ApiError>() {
+              Some(api_err) => api_err.to_string(),
+              None => cformat!(
+                "<bold>⏱️    0 ms</bold> | <bold>🧠 {}</bold>\n\n{}",
+                model,
+                capitalize_str(&err.to_string())
+              ),
+            };
+            eprintln!(
+              "{}\n",
+              fmt_output(&opts, &cformat!("<red>{}</red>", err_fmt))
+            );
+          }
+        }
       }
-      Commands::Llama3 { prompt } => {
-        submit_prompt(
-          &Some(&Model::Model(
-            Provider::Groq,
-            "llama-3.1-8b-instant".to_string(),
-          )),
-          &opts,
-          &format!("{stdin}{}", prompt.join(" ")),
-        )
-        .await
+      Commands::Changelog { commit_hash } => {
+        if let Err(err) = generate_changelog(&opts, &commit_hash).await {
+          eprintln!(
+            "Error generating changelog: {}",
+            fmt_output(&opts, &err.to_string())
+          );
+          std::process::exit(1);
+        }
       }
-      Commands::Openai { model, prompt } => {
-        submit_prompt(
-          &Some(&Model::Model(Provider::OpenAI, model)),
-          &opts,
-          &format!("{stdin}{}", prompt.join(" ")),
-        )
-        .await
+      Commands::Rename {
+        recursive,
+        dry_run,
+        jobs,
+        pages,
+        first_page,
+        template,
+        files,
+      } => {
+        let pages = if first_page {
+          Some(PageRange {
+            start: 1,
+            end: Some(1),
+          })
+        } else {
+          pages
+        };
+        let files = collect_files(&files, recursive);
+        let file_count = files.len();
+        // Files are analyzed concurrently and renamed as soon as
+        // their analysis completes. The lock makes checking for existing
+        // file names and renaming atomic to avoid races.
+        // (errors, skipped, paths claimed by earlier files of the batch)
+        let counts = std::sync::Mutex::new((0, 0, HashSet::new()));
+        futures::stream::iter(files)
+          .for_each_concurrent(jobs.or(args.jobs).unwrap_or(4).max(1), |file| {
+            let (opts, template, counts) = (&opts, &template, &counts);
+            async move {
+              let analysis = analyze_file_content(opts, &file, pages).await;
+              let mut counts = counts.lock().unwrap();
+              let (error_count, skipped_count, claimed_paths) = &mut *counts;
+              match analysis {
+                Ok(analysis) => {
+                  match render_template(template, &analysis, &file) {
+                    Some(new_name) => {
+                      if !rename_file(file, new_name, dry_run, claimed_paths) {
+                        *error_count += 1;
+                      }
+                    }
+                    None => {
+                      eprintln!(
+                        "Skipping {file} (No meaningful description found)"
+                      );
+                      *skipped_count += 1;
+                    }
+                  }
+                }
+                Err(err) => {
+                  eprintln!(
+                    "Error analyzing file {file}: {}",
+                    fmt_output(opts, &err.to_string())
+                  );
+                  *error_count += 1;
+                }
+              }
+            }
+          })
+          .await;
+        let (error_count, skipped_count, _) = counts.into_inner().unwrap();
+
+        if file_count > 1 {
+          println!(
+            "\n{} of {file_count} files {}, {error_count} failed{}",
+            file_count - error_count - skipped_count,
+            if dry_run { "analyzed" } else { "renamed" },
+            if skipped_count > 0 {
+              format!(", {skipped_count} skipped")
+            } else {
+              String::new()
+            },
+          );
+        }
+        if error_count > 0 {
+          std::process::exit(1);
+        }
       }
-      Commands::Gpt { prompt } => {
-        submit_prompt(
-          &Some(&Model::Model(Provider::OpenAI, "gpt-4o".to_string())),
-          &opts,
-          &format!("{stdin}{}", prompt.join(" ")),
-        )
-        .await
+      Commands::Ocr { file } => {
+        if let Err(err) = extract_text_from_file(&opts, &file).await {
+          eprintln!(
+            "Error extracting text: {}",
+            fmt_output(&opts, &err.to_string())
+          );
+          std::process::exit(1);
+        }
       }
-      Commands::GptMini { prompt } => {
-        submit_prompt(
-          &Some(&Model::Model(Provider::OpenAI, "gpt-4o-mini".to_string())),
-          &opts,
-          &format!("{stdin}{}", prompt.join(" ")),
-        )
-        .await
+      Commands::Sentiment { prompt } => {
+        let text = combine_prompt(&stdin, &prompt, args.stdin_after);
+        if let Err(err) = analyze_sentiment(&opts, &text).await {
+          eprintln!(
+            "Error analyzing sentiment: {}",
+            fmt_output(&opts, &err.to_string())
+          );
+          std::process::exit(1);
+        }
       }
-      Commands::Anthropic { model, prompt } => {
-        submit_prompt(
-          &Some(&Model::Model(Provider::Anthropic, model)),
-          &opts,
-          &format!("{stdin}{}", prompt.join(" ")),
-        )
-        .await
+      Commands::Brainstorm { count, prompt } => {
+        if let Err(err) = brainstorm(&opts, count, prompt).await {
+          eprintln!(
+            "Error brainstorming: {}",
+            fmt_output(&opts, &err.to_string())
+          );
+          std::process::exit(1);
+        }
       }
-      Commands::ClaudeOpus { prompt } => {
-        submit_prompt(
-          &Some(&Model::Model(
-            Provider::Anthropic,
-            "claude-3-opus-latest".to_string(),
-          )),
-          &opts,
-          &format!("{stdin}{}", prompt.join(" ")),
-        )
-        .await
+      Commands::Outline { depth, prompt } => {
+        let text = combine_prompt(&stdin, &prompt, args.stdin_after);
+        if let Err(err) = outline(&opts, depth, &text).await {
+          eprintln!(
+            "Error creating outline: {}",
+            fmt_output(&opts, &err.to_string())
+          );
+          std::process::exit(1);
+        }
       }
-      Commands::ClaudeSonnet { prompt } => {
-        submit_prompt(
-          &Some(&Model::Model(
-            Provider::Anthropic,
-            "claude-3-5-sonnet-latest".to_string(),
-          )),
-          &opts,
-          &format!("{stdin}{}", prompt.join(" ")),
-        )
-        .await
+      Commands::Explain { command } => {
+        let command = combine_prompt(&stdin, &command, args.stdin_after);
+        if let Err(err) = explain_command(&opts, &command).await {
+          eprintln!(
+            "Error explaining the command: {}",
+            fmt_output(&opts, &err.to_string())
+          );
+          std::process::exit(1);
+        }
       }
-      Commands::ClaudeHaiku { prompt } => {
-        submit_prompt(
-          &Some(&Model::Model(
-            Provider::Anthropic,
-            "claude-3-5-haiku-latest".to_string(),
-          )),
-          &opts,
-          &format!("{stdin}{}", prompt.join(" ")),
-        )
-        .await
+      Commands::Cmd { run, description } => {
+        let description =
+          combine_prompt(&stdin, &description, args.stdin_after);
+        let command = match generate_command(&opts, &description).await {
+          Ok(command) => command,
+          Err(err) => {
+            eprintln!(
+              "Error generating the command: {}",
+              fmt_output(&opts, &err.to_string())
+            );
+            std::process::exit(1);
+          }
+        };
+        println!("{command}");
+        if run {
+          match run_command(&opts, &command) {
+            Ok(exit_code) => std::process::exit(exit_code),
+            Err(err) => {
+              eprintln!(
+                "Error running the command: {}",
+                fmt_output(&opts, &err.to_string())
+              );
+              std::process::exit(1);
+            }
+          }
+        }
       }
-      Commands::Llamafile { prompt } => {
-        submit_prompt(
-          &Some(&Model::Model(Provider::Llamafile, "".to_string())),
-          &opts,
-          &format!("{stdin}{}", prompt.join(" ")),
-        )
-        .await //
+      Commands::ComparePrompts { prompt_a, prompt_b } => {
+        let prompt_a = combine_prompt(&stdin, &[prompt_a], args.stdin_after);
+        let prompt_b = combine_prompt(&stdin, &[prompt_b], args.stdin_after);
+        if let Err(err) =
+          compare_prompts(&args.model.as_ref(), &opts, &prompt_a, &prompt_b)
+            .await
+        {
+          eprintln!(
+            "Error comparing prompts: {}",
+            fmt_output(&opts, &err.to_string())
+          );
+          std::process::exit(1);
+        }
       }
-      Commands::Ollama { model, prompt } => {
+      Commands::Anon { prompt } => {
         submit_prompt(
-          &Some(&Model::Model(Provider::Ollama, model)),
-          &opts,
-          &format!("{stdin}{}", prompt.join(" ")),
+          &args.model.as_ref(),
+          &ExecOptions {
+            anonymize: true,
+            ..opts
+          },
+          &combine_prompt(&stdin, &prompt, args.stdin_after),
         )
-        .await //
+        .await
       }
-      Commands::All { prompt } => {
-        let models = vec![
-          Model::Model(
-            Provider::Anthropic,
-            "claude-3-5-sonnet-latest".to_string(),
-          ),
-          Model::Model(Provider::Groq, "llama-3.1-8b-instant".to_string()),
-          Model::Model(Provider::OpenAI, "gpt-4o-mini".to_string()),
-          Model::Model(Provider::Ollama, "llama3".to_string()),
-          Model::Model(Provider::Llamafile, "".to_string()),
-        ];
-
-        let mut handles = vec![];
-
-        for model in models.into_iter() {
-          let prompt_str = format!("{}\n{}", stdin, prompt.join(" "));
-          let model_fmt = model.to_string();
-          let opts_clone = opts.clone();
-
-          handles.push(tokio::spawn(async move {
-            match exec_tool(&Some(&model), &opts_clone, &prompt_str).await {
-              Ok(_) => {}
-              Err(err) => {
-                let err_fmt = capitalize_str(&err.to_string());
-                eprintln!(
-                  "{}",
-                  cformat!(
-                    "<bold>⏱️    0 ms</bold> | \
-                    <bold>🧠 {}</bold><red>\nERROR:\n{}</red>\n",
-                    model_fmt,
-                    err_fmt
-                  )
-                );
-              }
-            }
-          }));
+      Commands::Again { prompt } => match load_last_model() {
+        Ok(model) => {
+          submit_prompt(
+            &Some(&model),
+            &opts,
+            &combine_prompt(&stdin, &prompt, args.stdin_after),
+          )
+          .await
         }
-
-        join_all(handles).await;
-      }
-      Commands::Changelog { commit_hash } => {
-        if let Err(err) = generate_changelog(&opts, &commit_hash).await {
-          eprintln!("Error generating changelog: {}", err);
+        Err(err) => {
+          eprintln!(
+            "{}",
+            fmt_output(&opts, &cformat!("<red>ERROR: {err}</red>"))
+          );
           std::process::exit(1);
         }
+      },
+      Commands::Run {
+        name,
+        var,
+        args: template_args,
+      } => {
+        let template_run = get_template_run(
+          &name,
+          &template_args,
+          &var,
+          &stdin,
+          args.stdin_after,
+          &args.model,
+        );
+        let (model, system, prompt) = match template_run {
+          Ok(template_run) => template_run,
+          Err(err) => {
+            eprintln!(
+              "{}",
+              fmt_output(&opts, &cformat!("<red>ERROR: {err}</red>"))
+            );
+            std::process::exit(1);
+          }
+        };
+        let mut opts = opts.clone();
+        if let Some(system) = system {
+          opts.system_prompts.insert(0, system);
+        }
+        // E.g. `cai run short --short` shouldn't add the instruction twice
+        opts.prompt_wrappers.retain(|wrapper| *wrapper != name);
+        submit_prompt(&model.as_ref(), &opts, &prompt).await
       }
-      Commands::Rename { file } => {
-        match analyze_file_content(&opts, &file).await {
-          Ok(analysis) => {
-            let timestamp_str = analysis.timestamp.unwrap_or_default();
-            let timestamp_norm = timestamp_str.trim().to_lowercase();
-            let valid_timestamp = chrono::NaiveDateTime::parse_from_str(
-              &timestamp_norm,
-              "%Y-%m-%dt%H:%Mz",
-            )
-            .or_else(|_| {
-              chrono::NaiveDateTime::parse_from_str(&timestamp_norm, "%Y-%m-%d")
-            })
-            .is_ok();
-            let timestamp = if valid_timestamp {
-              timestamp_norm
-                .replace(":", "")
-                .replace("z", "")
-                .replace("t0000", "")
-            } else {
-              chrono::Local::now().format("%Y-%m-%dt%H%M").to_string()
-            };
-            let description = analysis //
-              .description
-              .trim()
-              .to_lowercase()
-              .replace(' ', "_");
-            rename_file(file, timestamp, description);
+      Commands::Config {
+        command: Some(ConfigCommand::Path),
+      } => println!("{}", get_config_path(&opts)),
+      Commands::Config {
+        command: Some(ConfigCommand::Set { key, value }),
+      } => match set_config_value(&opts, &key, &value) {
+        Ok(warning) => {
+          if let Some(warning) = warning {
+            eprintln!("Warning: {warning}");
           }
+        }
+        Err(err) => {
+          eprintln!(
+            "{}",
+            fmt_output(&opts, &cformat!("<red>ERROR: {err}</red>"))
+          );
+          std::process::exit(1);
+        }
+      },
+      Commands::Config { command: None } => match get_config_listing(&opts) {
+        Ok(listing) => {
+          let full_config =
+            get_full_config(&get_config_path(&opts)).unwrap_or_default();
+          let provider_statuses: Vec<(Provider, bool)> = PROVIDERS
+            .iter()
+            .map(|provider| (*provider, has_api_key(&full_config, provider)))
+            .collect();
+          print!(
+            "{}",
+            fmt_stdout(
+              &opts,
+              &fmt_config_listing(
+                &get_config_path(&opts),
+                &listing,
+                &provider_statuses
+              )
+            )
+          );
+        }
+        Err(err) => {
+          eprintln!(
+            "{}",
+            fmt_output(&opts, &cformat!("<red>ERROR: {err}</red>"))
+          );
+          std::process::exit(1);
+        }
+      },
+      Commands::Cache {
+        command: CacheCommand::Clear,
+      } => match clear_cache() {
+        Ok(count) => println!("Removed {count} cached responses"),
+        Err(err) => {
+          eprintln!(
+            "{}",
+            fmt_output(&opts, &cformat!("<red>ERROR: {err}</red>"))
+          );
+          std::process::exit(1);
+        }
+      },
+      Commands::Pick => {
+        let Some((model, prompt)) = pick_model_and_prompt(&opts) else {
+          std::process::exit(1);
+        };
+        submit_prompt(&Some(&model), &opts, &prompt).await
+      }
+      Commands::Doctor { timeout } => {
+        let timeout = std::time::Duration::from_secs(timeout);
+        match run_doctor(&opts, timeout).await {
+          Ok(true) => {}
+          Ok(false) => std::process::exit(1),
           Err(err) => {
-            eprintln!("Error analyzing file: {}", err);
+            eprintln!(
+              "{}",
+              fmt_output(&opts, &cformat!("<red>ERROR: {err}</red>"))
+            );
             std::process::exit(1);
           }
         }
       }
+      Commands::Tokens { prompt } => {
+        let provider = match &args.model {
+          Some(Model::Model(provider, _)) => *provider,
+          None => Provider::OpenAI,
+        };
+        let text = combine_prompt(&stdin, &prompt, args.stdin_after);
+        println!("{}", estimate_tokens(&provider, &text));
+      }
       /////////////////////////////////////////
       //========== LANGUAGE CONTEXTS ==========
       /////////////////////////////////////////
-      Commands::Ocr { file } => {
-        if let Err(err) = extract_text_from_file(&opts, &file).await {
-          eprintln!("Error extracting text: {}", err);
-          std::process::exit(1);
-        }
-      }
       Commands::Bash { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Bash", prompt).await {
-          eprintln!("Error prompting with Bash context: {}", err);
+          eprintln!(
+            "Error prompting with Bash context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::C { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "C", prompt).await {
-          eprintln!("Error prompting with C context: {}", err);
+          eprintln!(
+            "Error prompting with C context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Cpp { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "C++", prompt).await {
-          eprintln!("Error prompting with C++ context: {}", err);
+          eprintln!(
+            "Error prompting with C++ context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Cs { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "C#", prompt).await {
-          eprintln!("Error prompting with C# context: {}", err);
+          eprintln!(
+            "Error prompting with C# context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Elm { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Elm", prompt).await {
-          eprintln!("Error prompting with Elm context: {}", err);
+          eprintln!(
+            "Error prompting with Elm context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Fish { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Fish", prompt).await {
-          eprintln!("Error prompting with Fish context: {}", err);
+          eprintln!(
+            "Error prompting with Fish context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Fs { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "F#", prompt).await {
-          eprintln!("Error prompting with F# context: {}", err);
+          eprintln!(
+            "Error prompting with F# context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Gd { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Godot", prompt).await {
-          eprintln!("Error prompting with Godot context: {}", err);
+          eprintln!(
+            "Error prompting with Godot context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Gl { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Gleam", prompt).await {
-          eprintln!("Error prompting with Gleam context: {}", err);
+          eprintln!(
+            "Error prompting with Gleam context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Go { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Go", prompt).await {
-          eprintln!("Error prompting with Go context: {}", err);
+          eprintln!(
+            "Error prompting with Go context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Hs { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Haskell", prompt).await
         {
-          eprintln!("Error prompting with Haskell context: {}", err);
+          eprintln!(
+            "Error prompting with Haskell context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Java { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Java", prompt).await {
-          eprintln!("Error prompting with Java context: {}", err);
+          eprintln!(
+            "Error prompting with Java context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
@@ -697,32 +1907,47 @@ async fn exec_with_args(args: Args, stdin: &str) {
         if let Err(err) =
           prompt_with_lang_cntxt(&opts, "JavaScript", prompt).await
         {
-          eprintln!("Error prompting with JavaScript context: {}", err);
+          eprintln!(
+            "Error prompting with JavaScript context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Kt { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Kotlin", prompt).await
         {
-          eprintln!("Error prompting with Kotlin context: {}", err);
+          eprintln!(
+            "Error prompting with Kotlin context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Lua { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Lua", prompt).await {
-          eprintln!("Error prompting with Lua context: {}", err);
+          eprintln!(
+            "Error prompting with Lua context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Oc { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "OCaml", prompt).await {
-          eprintln!("Error prompting with OCaml context: {}", err);
+          eprintln!(
+            "Error prompting with OCaml context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Php { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "PHP", prompt).await {
-          eprintln!("Error prompting with PHP context: {}", err);
+          eprintln!(
+            "Error prompting with PHP context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
@@ -730,7 +1955,10 @@ async fn exec_with_args(args: Args, stdin: &str) {
         if let Err(err) =
           prompt_with_lang_cntxt(&opts, "Postgres", prompt).await
         {
-          eprintln!("Error prompting with Postgres context: {}", err);
+          eprintln!(
+            "Error prompting with Postgres context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
@@ -738,39 +1966,57 @@ async fn exec_with_args(args: Args, stdin: &str) {
         if let Err(err) =
           prompt_with_lang_cntxt(&opts, "PureScript", prompt).await
         {
-          eprintln!("Error prompting with PureScript context: {}", err);
+          eprintln!(
+            "Error prompting with PureScript context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Py { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Python", prompt).await
         {
-          eprintln!("Error prompting with Python context: {}", err);
+          eprintln!(
+            "Error prompting with Python context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Rb { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Ruby", prompt).await {
-          eprintln!("Error prompting with Ruby context: {}", err);
+          eprintln!(
+            "Error prompting with Ruby context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Rs { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Rust", prompt).await {
-          eprintln!("Error prompting with Rust context: {}", err);
+          eprintln!(
+            "Error prompting with Rust context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Sql { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "SQLite", prompt).await
         {
-          eprintln!("Error prompting with SQLite context: {}", err);
+          eprintln!(
+            "Error prompting with SQLite context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Sw { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Swift", prompt).await {
-          eprintln!("Error prompting with Swift context: {}", err);
+          eprintln!(
+            "Error prompting with Swift context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
@@ -778,20 +2024,29 @@ async fn exec_with_args(args: Args, stdin: &str) {
         if let Err(err) =
           prompt_with_lang_cntxt(&opts, "TypeScript", prompt).await
         {
-          eprintln!("Error prompting with TypeScript context: {}", err);
+          eprintln!(
+            "Error prompting with TypeScript context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Wl { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Wolfram", prompt).await
         {
-          eprintln!("Error prompting with Wolfram context: {}", err);
+          eprintln!(
+            "Error prompting with Wolfram context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
       Commands::Zig { prompt } => {
         if let Err(err) = prompt_with_lang_cntxt(&opts, "Zig", prompt).await {
-          eprintln!("Error prompting with Zig context: {}", err);
+          eprintln!(
+            "Error prompting with Zig context: {}",
+            fmt_output(&opts, &err.to_string())
+          );
           std::process::exit(1);
         }
       }
@@ -799,26 +2054,371 @@ async fn exec_with_args(args: Args, stdin: &str) {
   };
 }
 
-fn rename_file(file: String, timestamp: String, description: String) {
-  let path = std::path::Path::new(&file);
-  let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
-  let mut new_name = format!("{}_{}.{}", timestamp, description, ext);
+/// Expand glob patterns and directories (if `recursive` is set)
+/// into a sorted list of files
+fn collect_files(paths: &[String], recursive: bool) -> Vec<String> {
+  let mut files = vec![];
 
-  let mut counter = 0;
-  loop {
-    if std::path::Path::new(&new_name).exists() {
-      counter += 1;
-      new_name = format!("{}_{}_{}.{}", timestamp, description, counter, ext)
+  for path_str in paths {
+    let expanded: Vec<std::path::PathBuf> =
+      if path_str.contains(['*', '?', '[']) {
+        match glob::glob(path_str) {
+          Ok(entries) => entries.filter_map(Result::ok).collect(),
+          Err(err) => {
+            eprintln!("Invalid glob pattern \"{path_str}\": {err}");
+            vec![]
+          }
+        }
+      } else {
+        vec![std::path::PathBuf::from(path_str)]
+      };
+
+    for path in expanded {
+      if !path.is_dir() {
+        files.push(path.to_string_lossy().to_string());
+      } else if recursive {
+        let mut dir_files = vec![];
+        collect_dir_files(&path, &mut dir_files);
+        dir_files.sort();
+        files.extend(dir_files);
+      } else {
+        eprintln!(
+          "Skipping directory \"{}\" (Use --recursive to include it)",
+          path.display()
+        );
+      }
+    }
+  }
+
+  files
+}
+
+fn collect_dir_files(dir: &std::path::Path, files: &mut Vec<String>) {
+  let Ok(entries) = std::fs::read_dir(dir) else {
+    eprintln!("Couldn't read directory \"{}\"", dir.display());
+    return;
+  };
+
+  for entry in entries.filter_map(Result::ok) {
+    let path = entry.path();
+    let is_hidden = entry.file_name().to_string_lossy().starts_with('.');
+    if is_hidden {
+      continue;
+    }
+    if path.is_dir() {
+      collect_dir_files(&path, files);
     } else {
+      files.push(path.to_string_lossy().to_string());
+    }
+  }
+}
+
+const TEMPLATE_PLACEHOLDERS: [&str; 6] =
+  ["timestamp", "date", "time", "desc", "ext", "orig"];
+
+/// Validate that the rename template only uses known placeholders
+fn parse_template(template: &str) -> Result<String, String> {
+  let mut rest = template;
+
+  while let Some(start) = rest.find('{') {
+    if rest[..start].contains('}') {
       break;
     }
+    let end = rest[start..]
+      .find('}')
+      .ok_or(format!("Unclosed placeholder in template \"{template}\""))?;
+    let placeholder = &rest[start + 1..start + end];
+    if !TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+      return Err(format!(
+        "Unknown placeholder {{{placeholder}}}. Available placeholders are: {}",
+        TEMPLATE_PLACEHOLDERS
+          .map(|name| format!("{{{name}}}"))
+          .join(", ")
+      ));
+    }
+    rest = &rest[start + end + 1..];
   }
 
-  if let Err(err) = std::fs::rename(&file, &new_name) {
-    eprintln!("Error renaming file: {}", err);
-    std::process::exit(1);
+  if rest.contains('}') {
+    return Err(format!("Unopened placeholder in template \"{template}\""));
+  }
+
+  Ok(template.to_string())
+}
+
+/// Get the creation time of the file
+/// or its modification time if the file system doesn't record it
+fn get_file_time(file: &str) -> Option<chrono::DateTime<chrono::Local>> {
+  let metadata = std::fs::metadata(file).ok()?;
+  metadata
+    .created()
+    .or_else(|_| metadata.modified())
+    .ok()
+    .map(chrono::DateTime::from)
+}
+
+/// Normalize the timestamp of the analysis (e.g. `2024-05-01t1230`).
+/// If it is invalid, use the time of the file or else the current time.
+fn get_rename_timestamp(analysis: &FileAnalysis, file: &str) -> String {
+  let timestamp_str = analysis.timestamp.clone().unwrap_or_default();
+  let timestamp_norm = timestamp_str.trim().to_lowercase();
+  let valid_timestamp =
+    chrono::NaiveDateTime::parse_from_str(&timestamp_norm, "%Y-%m-%dt%H:%Mz")
+      .or_else(|_| {
+        chrono::NaiveDateTime::parse_from_str(&timestamp_norm, "%Y-%m-%d")
+      })
+      .is_ok();
+  if valid_timestamp {
+    timestamp_norm
+      .replace(":", "")
+      .replace("z", "")
+      .replace("t0000", "")
+  } else {
+    get_file_time(file)
+      .unwrap_or_else(chrono::Local::now)
+      .format("%Y-%m-%dt%H%M")
+      .to_string()
+  }
+}
+
+/// Descriptions which don't say anything about the file on their own
+const GENERIC_DESCRIPTION_WORDS: &[&str] = &[
+  "analysis",
+  "content",
+  "document",
+  "file",
+  "n/a",
+  "none",
+  "summary",
+  "text",
+  "transaction",
+  "unknown",
+  "untitled",
+];
+
+/// Lowercase the text, replace spaces with underscores,
+/// and remove all characters which are problematic in file names
+fn sanitize_file_name_part(text: &str) -> String {
+  text
+    .trim()
+    .to_lowercase()
+    .replace(' ', "_")
+    .chars()
+    .filter(|char| char.is_alphanumeric() || "_-.".contains(*char))
+    .collect::<String>()
+    .trim_matches(|char| "_-.".contains(char))
+    .to_string()
+}
+
+/// Get the description for the new file name.
+/// Falls back to the original file name if the analysis' description
+/// is empty or too generic and returns `None` if neither is meaningful.
+fn get_rename_description(
+  analysis: &FileAnalysis,
+  file: &str,
+) -> Option<String> {
+  let description = analysis.description.to_lowercase();
+  let is_generic = description
+    .split_whitespace()
+    .all(|word| GENERIC_DESCRIPTION_WORDS.contains(&word));
+  if !is_generic {
+    let sanitized = sanitize_file_name_part(&description);
+    if sanitized.chars().any(|char| char.is_alphanumeric()) {
+      return Some(sanitized);
+    }
+  }
+
+  let stem = std::path::Path::new(file)
+    .file_stem()
+    .and_then(|stem| stem.to_str())
+    .unwrap_or("");
+  Some(sanitize_file_name_part(stem))
+    .filter(|stem| stem.chars().any(|char| char.is_alphanumeric()))
+}
+
+/// Render the new file name from the template
+/// (`None` if no meaningful description could be derived)
+fn render_template(
+  template: &str,
+  analysis: &FileAnalysis,
+  file: &str,
+) -> Option<String> {
+  let path = std::path::Path::new(file);
+  let timestamp = get_rename_timestamp(analysis, file);
+  let (date, time) = timestamp.split_once('t').unwrap_or((&timestamp, ""));
+  let description = if template.contains("{desc}") {
+    get_rename_description(analysis, file)?
+  } else {
+    String::new()
+  };
+
+  let new_name = template
+    .replace("{timestamp}", &timestamp)
+    .replace("{date}", date)
+    .replace("{time}", time)
+    .replace("{desc}", &description)
+    .replace(
+      "{ext}",
+      path.extension().and_then(|ext| ext.to_str()).unwrap_or(""),
+    )
+    .replace(
+      "{orig}",
+      path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(""),
+    );
+  Some(new_name)
+}
+
+/// Rename the file (in its directory) and return if it was successful
+/// Rename the file (or only print the new name with `dry_run`).
+/// The claimed paths make dry runs report the same names as real ones.
+fn rename_file(
+  file: String,
+  new_name: String,
+  dry_run: bool,
+  claimed_paths: &mut HashSet<std::path::PathBuf>,
+) -> bool {
+  let path = std::path::Path::new(&file);
+  let mut new_path = path.with_file_name(&new_name);
+
+  // Add a counter before the extension if the file already exists
+  let mut counter = 0;
+  while new_path.exists() || claimed_paths.contains(&new_path) {
+    counter += 1;
+    let new_name_path = std::path::Path::new(&new_name);
+    let stem = new_name_path
+      .file_stem()
+      .and_then(|stem| stem.to_str())
+      .unwrap_or("");
+    new_path = path.with_file_name(match new_name_path.extension() {
+      Some(ext) => format!("{stem}_{counter}.{}", ext.to_string_lossy()),
+      None => format!("{new_name}_{counter}"),
+    });
+  }
+
+  let new_name = new_path.to_string_lossy().to_string();
+  if dry_run {
+    println!("Would rename {} to {}", file, new_name);
+    claimed_paths.insert(new_path);
+    return true;
+  }
+
+  if let Err(err) = std::fs::rename(&file, &new_path) {
+    eprintln!("Error renaming file {}: {}", file, err);
+    return false;
   }
   println!("Renamed {} to {}", file, new_name);
+  claimed_paths.insert(new_path);
+  true
+}
+
+/// Collect the `--system` and `--system-file` prompts
+/// in the order they were specified on the command line
+fn get_system_prompts(matches: &ArgMatches) -> Vec<String> {
+  let mut indexed_prompts: Vec<(usize, String)> = vec![];
+
+  if let (Some(indices), Some(prompts)) = (
+    matches.indices_of("system"),
+    matches.get_many::<String>("system"),
+  ) {
+    indexed_prompts.extend(indices.zip(prompts.cloned()));
+  }
+
+  if let (Some(indices), Some(files)) = (
+    matches.indices_of("system_file"),
+    matches.get_many::<String>("system_file"),
+  ) {
+    for (index, file) in indices.zip(files) {
+      match std::fs::read_to_string(file) {
+        Ok(prompt) => indexed_prompts.push((index, prompt.trim().to_string())),
+        Err(err) => {
+          eprintln!("Error reading system prompt file {}: {}", file, err);
+          std::process::exit(1);
+        }
+      }
+    }
+  }
+
+  indexed_prompts.sort_by_key(|(index, _)| *index);
+  indexed_prompts
+    .into_iter()
+    .map(|(_, prompt)| prompt)
+    .collect()
+}
+
+fn parse_args(args_vector: Vec<String>) -> Args {
+  let matches = Args::command().get_matches_from(args_vector);
+  let mut args =
+    Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+  args.system_prompts = get_system_prompts(&matches);
+  args
+}
+
+/// Let the user select an item with the arrow keys.
+/// Returns `None` if the selection was aborted.
+fn select_item(
+  term: &Term,
+  items: &[String],
+) -> std::io::Result<Option<usize>> {
+  let page_size = items.len().min(15);
+  let mut selected: usize = 0;
+
+  term.hide_cursor()?;
+  loop {
+    let first = selected.saturating_sub(page_size - 1);
+    for (index, item) in items.iter().enumerate().skip(first).take(page_size) {
+      if index == selected {
+        term.write_line(&cformat!("<bold><cyan>> {item}</cyan></bold>"))?;
+      } else {
+        term.write_line(&format!("  {item}"))?;
+      }
+    }
+
+    let key = term.read_key()?;
+    term.clear_last_lines(page_size)?;
+    match key {
+      Key::ArrowUp | Key::Char('k') => {
+        selected = selected.checked_sub(1).unwrap_or(items.len() - 1);
+      }
+      Key::ArrowDown | Key::Char('j') => {
+        selected = (selected + 1) % items.len();
+      }
+      Key::Enter => break,
+      Key::Escape | Key::Char('q') => {
+        term.show_cursor()?;
+        return Ok(None);
+      }
+      _ => {}
+    }
+  }
+  term.show_cursor()?;
+
+  Ok(Some(selected))
+}
+
+/// Interactively pick a model and enter a prompt
+fn pick_model_and_prompt(opts: &ExecOptions) -> Option<(Model, String)> {
+  let term = Term::stderr();
+  if !term.is_term() {
+    eprintln!("ERROR: Picking a model requires an interactive terminal");
+    return None;
+  }
+
+  let choices = get_model_choices(opts);
+  let names: Vec<String> =
+    choices.iter().map(|(name, _)| name.clone()).collect();
+
+  eprintln!("{}", cformat!("<bold>Select a model (↑/↓, Enter):</bold>"));
+  let selected = select_item(&term, &names).ok()??;
+  let (name, model) = choices[selected].clone();
+  eprintln!("{}", cformat!("<dim>{name}</dim>"));
+
+  eprint!("{}", cformat!("<bold>Prompt:</bold> "));
+  let prompt = term.read_line().ok()?;
+
+  Some((model, prompt.trim().to_string()))
 }
 
 #[tokio::main]
@@ -827,7 +2427,7 @@ async fn main() {
   let mut args_vector = std::env::args().collect::<Vec<_>>();
 
   if stdin.is_terminal() {
-    exec_with_args(Args::parse_from(args_vector), "").await;
+    exec_with_args(parse_args(args_vector), "").await;
   } else {
     let input = read_to_string(stdin).unwrap();
     let only_stdin = !input.is_empty() && args_vector.len() <= 1;
@@ -836,7 +2436,18 @@ async fn main() {
       args_vector.push("".to_string());
     }
 
-    let mut args = Args::parse_from(args_vector);
+    let mut args = parse_args(args_vector);
+    // Only removes the common prefix, so nested indentation is kept
+    let input = if args.dedent {
+      textwrap::dedent(&input)
+    } else {
+      input
+    };
+    let input = if args.line_numbers {
+      number_lines(input.trim_end())
+    } else {
+      input
+    };
 
     if only_stdin {
       args.prompt = vec![input];
@@ -853,8 +2464,392 @@ mod tests {
 
   #[test]
   fn test_parse_args() {
-    let parse_res = Args::try_parse_from(&["gpt"]);
+    let parse_res = Args::try_parse_from(["gpt"]);
     assert!(parse_res.is_err());
     assert!(&parse_res.unwrap_err().to_string().contains("Usage: gpt"));
   }
+
+  #[test]
+  fn test_system_prompts_order() {
+    let file_path = std::env::temp_dir().join("cai_test_system_prompt.txt");
+    std::fs::write(&file_path, "From file\n").unwrap();
+    let args = parse_args(vec![
+      "cai".to_string(),
+      "--system".to_string(),
+      "First".to_string(),
+      "--system-file".to_string(),
+      file_path.to_str().unwrap().to_string(),
+      "--system".to_string(),
+      "Last".to_string(),
+      "Hello".to_string(),
+    ]);
+    std::fs::remove_file(&file_path).unwrap();
+    assert_eq!(args.system_prompts, vec!["First", "From file", "Last"]);
+  }
+
+  #[test]
+  fn test_parse_optional_provider_model() {
+    let args = Args::try_parse_from(["cai", "openai"]).unwrap();
+    assert_eq!(
+      args.command,
+      Some(Commands::Openai {
+        model: None,
+        prompt: vec![]
+      })
+    );
+  }
+
+  #[test]
+  fn test_parse_model_flag() {
+    let args = Args::try_parse_from(["cai", "-m", "groq/ll", "Hello"]).unwrap();
+    assert_eq!(
+      args.model,
+      Some(Model::Model(Provider::Groq, "ll".to_string()))
+    );
+
+    let parse_res = Args::try_parse_from(["cai", "-m", "foo/bar", "Hello"]);
+    assert!(parse_res
+      .unwrap_err()
+      .to_string()
+      .contains("Valid providers are"));
+  }
+
+  #[test]
+  fn test_combine_prompt() {
+    let prompt = vec!["hello".to_string(), "world".to_string()];
+    assert_eq!(
+      combine_prompt("Translate to French: {{args}}\n", &prompt, false),
+      "Translate to French: hello world"
+    );
+    assert_eq!(
+      combine_prompt("Some context\n", &prompt, false),
+      "Some context\nhello world"
+    );
+    assert_eq!(
+      combine_prompt("Some context\n", &prompt, true),
+      "hello world\nSome context\n"
+    );
+  }
+
+  #[test]
+  fn test_number_lines() {
+    let text = (1..=10).map(|n| format!("line {n}")).collect::<Vec<_>>();
+    let numbered = number_lines(&text.join("\n"));
+    let numbered_lines: Vec<&str> = numbered.lines().collect();
+    assert_eq!(numbered_lines[0], " 1 | line 1");
+    assert_eq!(numbered_lines[9], "10 | line 10");
+  }
+
+  #[test]
+  fn test_parse_params() {
+    let args = parse_args(vec![
+      "cai".to_string(),
+      "--param".to_string(),
+      "seed=42".to_string(),
+      "--param".to_string(),
+      "reasoning_effort=low".to_string(),
+      "Hello".to_string(),
+    ]);
+    assert_eq!(
+      args.param,
+      vec![
+        ("seed".to_string(), json!(42)),
+        ("reasoning_effort".to_string(), json!("low")),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_get_json_schema() {
+    let schema_file = env::temp_dir().join("cai_test_json_schema.json");
+    std::fs::write(
+      &schema_file,
+      r#"{
+        "properties": {
+          "name": { "type": "string" },
+          "age": { "type": "integer" },
+          "tags": { "type": "array", "items": { "type": "string" } }
+        },
+        "required": ["name", "age", "tags"]
+      }"#,
+    )
+    .unwrap();
+    let schema_file_str = schema_file.to_string_lossy().to_string();
+
+    let schema = get_json_schema(None, Some(&schema_file_str))
+      .unwrap()
+      .unwrap();
+    assert_eq!(schema["name"], "requested_json_schema");
+    assert_eq!(schema["schema"]["type"], "object");
+    assert_eq!(schema["schema"]["additionalProperties"], false);
+    assert_eq!(schema["schema"]["properties"]["age"]["type"], "integer");
+    assert_eq!(
+      get_json_schema(
+        Some(&std::fs::read_to_string(&schema_file).unwrap()),
+        None
+      ),
+      Ok(Some(schema))
+    );
+
+    assert_eq!(get_json_schema(None, None), Ok(None));
+    assert_eq!(
+      get_json_schema(Some("{\"a\": }"), None),
+      Err("Invalid JSON schema: expected value at line 1 column 7".to_string())
+    );
+    assert_eq!(
+      get_json_schema(Some(r#"{"properties": {"a": {"minimum": 1}}}"#), None),
+      Err(
+        "Invalid JSON schema: \
+        Unsupported keywords \"minimum\" at $.properties.a"
+          .to_string()
+      )
+    );
+  }
+
+  #[test]
+  fn test_collect_files() {
+    let dir = env::temp_dir().join("cai_test_collect_files");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    for file in ["a.pdf", "b.txt", "sub/c.pdf"] {
+      std::fs::write(dir.join(file), "").unwrap();
+    }
+    let dir_str = dir.to_string_lossy().to_string();
+
+    assert_eq!(
+      collect_files(&[format!("{dir_str}/*.pdf")], false),
+      vec![format!("{dir_str}/a.pdf")]
+    );
+    assert_eq!(
+      collect_files(std::slice::from_ref(&dir_str), false),
+      Vec::<String>::new()
+    );
+    assert_eq!(
+      collect_files(std::slice::from_ref(&dir_str), true),
+      vec![
+        format!("{dir_str}/a.pdf"),
+        format!("{dir_str}/b.txt"),
+        format!("{dir_str}/sub/c.pdf"),
+      ]
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_rename_file_dry_run() {
+    let dir = env::temp_dir()
+      .join(format!("cai_test_rename_dry_run_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    for file in ["a.pdf", "b.pdf", "invoice.pdf"] {
+      std::fs::write(dir.join(file), "").unwrap();
+    }
+
+    // Both files get a new name, although the first one isn't renamed
+    let mut claimed_paths = HashSet::new();
+    for file in ["a.pdf", "b.pdf"] {
+      let file = dir.join(file).to_string_lossy().to_string();
+      assert!(rename_file(
+        file,
+        "invoice.pdf".into(),
+        true,
+        &mut claimed_paths
+      ));
+    }
+    assert_eq!(
+      claimed_paths,
+      HashSet::from([dir.join("invoice_1.pdf"), dir.join("invoice_2.pdf")])
+    );
+    assert!(dir.join("a.pdf").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_render_template() {
+    let analysis = FileAnalysis {
+      description: "Invoice ACME".to_string(),
+      timestamp: Some("2024-05-01T12:30Z".to_string()),
+    };
+    let file = "scans/scan_001.pdf";
+    assert_eq!(
+      render_template("{timestamp}_{desc}.{ext}", &analysis, file),
+      Some("2024-05-01t1230_invoice_acme.pdf".to_string())
+    );
+    assert_eq!(
+      render_template("{desc}-{date}.{ext}", &analysis, file),
+      Some("invoice_acme-2024-05-01.pdf".to_string())
+    );
+    assert_eq!(
+      render_template("{orig}_{date}_{time}.{ext}", &analysis, file),
+      Some("scan_001_2024-05-01_1230.pdf".to_string())
+    );
+  }
+
+  #[test]
+  fn test_get_rename_description() {
+    let analysis = |description: &str| FileAnalysis {
+      description: description.to_string(),
+      timestamp: Some("2024-05-01T12:30Z".to_string()),
+    };
+    assert_eq!(
+      get_rename_description(&analysis("Invoice: ACME/2024"), "a.pdf"),
+      Some("invoice_acme2024".to_string())
+    );
+    assert_eq!(
+      get_rename_description(&analysis("  "), "scans/Scan 001.pdf"),
+      Some("scan_001".to_string())
+    );
+    assert_eq!(
+      get_rename_description(&analysis("Document"), "scans/scan_001.pdf"),
+      Some("scan_001".to_string())
+    );
+    assert_eq!(
+      render_template("{date}_{desc}.{ext}", &analysis(""), "scans/__.pdf"),
+      None
+    );
+    assert_eq!(
+      render_template("{date}.{ext}", &analysis(""), "scans/__.pdf"),
+      Some("2024-05-01.pdf".to_string())
+    );
+  }
+
+  #[test]
+  fn test_get_rename_timestamp() {
+    let analysis = FileAnalysis {
+      description: "Invoice ACME".to_string(),
+      timestamp: Some("unknown".to_string()),
+    };
+    let file_path = env::temp_dir().join(format!(
+      "cai_test_rename_timestamp_{}.pdf",
+      std::process::id()
+    ));
+    let file = std::fs::File::create(&file_path).unwrap();
+    let modified_time =
+      chrono::DateTime::parse_from_rfc3339("2020-01-02T03:04:00Z").unwrap();
+    file.set_modified(modified_time.into()).unwrap();
+    let file_str = file_path.to_string_lossy().to_string();
+
+    let metadata = std::fs::metadata(&file_path).unwrap();
+    let expected_time: chrono::DateTime<chrono::Local> =
+      match metadata.created() {
+        Ok(created_time) => created_time.into(),
+        Err(_) => modified_time.into(),
+      };
+    assert_eq!(
+      get_rename_timestamp(&analysis, &file_str),
+      expected_time.format("%Y-%m-%dt%H%M").to_string()
+    );
+
+    // Missing files fall back to the current time
+    std::fs::remove_file(&file_path).unwrap();
+    let get_now = || chrono::Local::now().format("%Y-%m-%dt%H%M").to_string();
+    let before = get_now();
+    let timestamp = get_rename_timestamp(&analysis, &file_str);
+    let after = get_now();
+    assert!(before <= timestamp && timestamp <= after, "{timestamp}");
+  }
+
+  #[test]
+  fn test_parse_template() {
+    assert!(parse_template("{desc}-{date}.{ext}").is_ok());
+    assert!(parse_template("{description}.{ext}").is_err());
+    assert!(parse_template("{desc.{ext}").is_err());
+    assert!(parse_template("desc}.{ext}").is_err());
+  }
+
+  #[test]
+  fn test_parse_brainstorm_count() {
+    let args = Args::try_parse_from([
+      "cai",
+      "brainstorm",
+      "names",
+      "for",
+      "a",
+      "coffee",
+      "shop",
+      "--count",
+      "15",
+    ])
+    .unwrap();
+    assert_eq!(
+      args.command,
+      Some(Commands::Brainstorm {
+        count: 15,
+        prompt: vec![
+          "names".to_string(),
+          "for".to_string(),
+          "a".to_string(),
+          "coffee".to_string(),
+          "shop".to_string(),
+        ],
+      })
+    );
+  }
+
+  #[test]
+  fn test_get_model_output_path() {
+    let model = Model::Model(Provider::OpenAI, "gpt-4o-mini".to_string());
+    assert_eq!(
+      get_model_output_path("out/answer.md", &model),
+      "out/answer.openai-gpt-4o-mini.md"
+    );
+    assert_eq!(
+      get_model_output_path("answer", &model),
+      "answer.openai-gpt-4o-mini"
+    );
+    let model = Model::Model(Provider::Llamafile, "".to_string());
+    assert_eq!(
+      get_model_output_path("answer.md", &model),
+      "answer.llamafile.md"
+    );
+  }
+
+  #[test]
+  fn test_get_command_model() {
+    let get_model = |args: &[&str]| {
+      Args::try_parse_from(args)
+        .unwrap()
+        .command
+        .as_ref()
+        .and_then(|command| get_command_model(&Default::default(), command))
+    };
+    assert_eq!(
+      get_model(&["cai", "gm", "Hi"]),
+      Some(Model::Model(Provider::OpenAI, "gpt-4o-mini".to_string()))
+    );
+    assert_eq!(get_model(&["cai", "explain", "ls"]), None);
+    assert_eq!(get_model(&["cai", "Hi"]), None);
+  }
+
+  #[test]
+  fn test_parse_prompt_wrappers() {
+    let args =
+      Args::try_parse_from(["cai", "anthropic", "sonnet", "--short", "Hi"])
+        .unwrap();
+    assert!(args.wrap_short);
+    assert!(!args.wrap_value);
+    assert_eq!(
+      args.command,
+      Some(Commands::Anthropic {
+        model: Some("sonnet".to_string()),
+        prompt: vec!["Hi".to_string()],
+      })
+    );
+
+    assert!(supports_prompt_wrappers(&Default::default(), &args.command));
+
+    let args =
+      Args::try_parse_from(["cai", "config", "set", "key", "value"]).unwrap();
+    assert!(!args.wrap_value);
+
+    let args =
+      Args::try_parse_from(["cai", "changelog", "abc", "--short"]).unwrap();
+    assert!(args.wrap_short);
+    assert!(!supports_prompt_wrappers(
+      &Default::default(),
+      &args.command
+    ));
+  }
 }
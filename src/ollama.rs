@@ -0,0 +1,145 @@
+use serde_json::{json, Map, Value};
+
+/// Path of Ollama's native chat endpoint
+const NATIVE_PATH: &str = "/api/chat";
+
+/// Top-level OpenAI parameters and their names in the native `options`
+const OPTION_NAMES: &[(&str, &str)] = &[
+  ("max_tokens", "num_predict"),
+  ("seed", "seed"),
+  ("stop", "stop"),
+  ("temperature", "temperature"),
+  ("top_p", "top_p"),
+];
+
+/// Replace the OpenAI compatible path of the URL with the native one
+pub fn get_native_url(url: &str) -> String {
+  let base_url = url
+    .strip_suffix("/v1/chat/completions")
+    .unwrap_or(url.trim_end_matches('/'));
+  format!("{base_url}{NATIVE_PATH}")
+}
+
+/// Convert a multimodal content array to the text and the base64 images
+fn split_content(content: &[Value]) -> Result<(String, Vec<Value>), String> {
+  let mut texts = vec![];
+  let mut images = vec![];
+  for part in content {
+    if let Some(text) = part["text"].as_str() {
+      texts.push(text);
+    }
+    if let Some(url) = part["image_url"]["url"].as_str() {
+      let (_, data) = url
+        .split_once(";base64,")
+        .ok_or("Ollama's native API only supports local images")?;
+      images.push(Value::String(data.to_string()));
+    }
+  }
+  Ok((texts.join("\n"), images))
+}
+
+/// Convert an OpenAI compatible request body to Ollama's native format
+/// (https://github.com/ollama/ollama/blob/main/docs/api.md#generate-a-chat-completion)
+pub fn to_native_body(req_body_obj: &Value) -> Result<Value, String> {
+  let mut body = req_body_obj.as_object().cloned().unwrap_or_default();
+
+  let options: Map<String, Value> = OPTION_NAMES
+    .iter()
+    .filter_map(|(name, option_name)| {
+      body
+        .remove(*name)
+        .map(|value| (option_name.to_string(), value))
+    })
+    .collect();
+  if !options.is_empty() {
+    body.insert("options".to_string(), Value::Object(options));
+  }
+
+  if let Some(response_format) = body.remove("response_format") {
+    let format = match response_format["type"].as_str() {
+      Some("json_schema") => response_format["json_schema"]["schema"].clone(),
+      _ => json!("json"),
+    };
+    body.insert("format".to_string(), format);
+  }
+
+  if let Some(messages) = body
+    .get_mut("messages")
+    .and_then(|messages| messages.as_array_mut())
+  {
+    for message in messages {
+      if let Some(content) = message["content"].as_array() {
+        let (text, images) = split_content(content)?;
+        message["content"] = Value::String(text);
+        message["images"] = Value::Array(images);
+      }
+    }
+  }
+
+  body.insert("stream".to_string(), Value::Bool(false));
+
+  Ok(Value::Object(body))
+}
+
+/// Extract the content and the thinking from a native response
+pub fn parse_native_response(resp_json: &Value) -> (String, Option<String>) {
+  let message = &resp_json["message"];
+  let content = message["content"].as_str().unwrap_or_default().to_string();
+  let thinking = message["thinking"]
+    .as_str()
+    .filter(|thinking| !thinking.is_empty())
+    .map(|thinking| thinking.to_string());
+  (content, thinking)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_to_native_body() {
+    assert_eq!(
+      get_native_url("http://localhost:11434/v1/chat/completions"),
+      "http://localhost:11434/api/chat"
+    );
+
+    let req_body_obj = json!({
+      "model": "llama3.2",
+      "max_tokens": 100,
+      "seed": 7,
+      "response_format": {
+        "type": "json_schema",
+        "json_schema": { "name": "x", "schema": { "type": "object" } },
+      },
+      "messages": [{
+        "role": "user",
+        "content": [
+          { "type": "image_url", "image_url": { "url": "data:a;base64,QQ==" } },
+          { "type": "text", "text": "Describe it" },
+        ],
+      }],
+    });
+    assert_eq!(
+      to_native_body(&req_body_obj).unwrap(),
+      json!({
+        "model": "llama3.2",
+        "options": { "num_predict": 100, "seed": 7 },
+        "format": { "type": "object" },
+        "messages": [{
+          "role": "user",
+          "content": "Describe it",
+          "images": ["QQ=="],
+        }],
+        "stream": false,
+      })
+    );
+
+    assert_eq!(
+      parse_native_response(&json!({
+        "message": { "role": "assistant", "content": "Hi", "thinking": "" },
+        "done": true,
+      })),
+      ("Hi".to_string(), None)
+    );
+  }
+}
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use config::{Config, File, FileFormat};
+use serde_derive::Deserialize;
+
+/// Instructions of the built-in templates.
+/// Each one is used with the prompt `{instruction}\n\n{input}`.
+pub const PROMPT_WRAPPERS: &[(&str, &str)] = &[
+  (
+    "value",
+    "Only output the requested value. \
+    No explanation, no units unless asked for, and no markdown formatting.",
+  ),
+  (
+    "short",
+    "Answer as briefly as possible. \
+    Use at most a few sentences and leave out any preamble.",
+  ),
+  (
+    "rewrite",
+    "Rewrite the following text to improve its clarity, grammar, and style. \
+    Keep its meaning, language, and tone and only output the rewritten text.",
+  ),
+  (
+    "svg",
+    "Create an SVG image of the following description. \
+    Only output the SVG code without any explanation or code fences.",
+  ),
+  (
+    "reply",
+    "Write a reply to the following message. \
+    Match its language and tone and only output the reply.",
+  ),
+];
+
+/// File extensions of user defined templates and their formats
+const TEMPLATE_FORMATS: &[(&str, FileFormat)] = &[
+  ("yaml", FileFormat::Yaml),
+  ("yml", FileFormat::Yaml),
+  ("toml", FileFormat::Toml),
+];
+
+/// A named prompt with `{placeholder}` slots
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct PromptTemplate {
+  /// Model to use in the format provider/model
+  pub model: Option<String>,
+  /// System prompt to prepend to the ones from the command line
+  pub system: Option<String>,
+  /// Prompt body with `{placeholder}` slots
+  pub prompt: String,
+}
+
+/// Prepend the instructions of the named prompt wrappers
+/// (e.g. `short` for `--short`) to the prompt
+pub fn wrap_prompt(wrapper_names: &[String], prompt: &str) -> String {
+  let instructions: Vec<&str> = PROMPT_WRAPPERS
+    .iter()
+    .filter(|(name, _)| wrapper_names.iter().any(|wrapper| wrapper == name))
+    .map(|(_, instruction)| *instruction)
+    .collect();
+  if instructions.is_empty() {
+    prompt.to_string()
+  } else {
+    format!("{}\n\n{prompt}", instructions.join("\n"))
+  }
+}
+
+/// Get the built-in template for the name
+fn get_builtin_template(name: &str) -> Option<PromptTemplate> {
+  PROMPT_WRAPPERS
+    .iter()
+    .find(|(wrapper_name, _)| *wrapper_name == name)
+    .map(|(_, instruction)| PromptTemplate {
+      model: None,
+      system: None,
+      prompt: format!("{instruction}\n\n{{input}}"),
+    })
+}
+
+/// Parse a YAML or TOML template
+fn parse_template(
+  content: &str,
+  format: FileFormat,
+) -> Result<PromptTemplate, String> {
+  Config::builder()
+    .add_source(File::from_str(content, format))
+    .build()
+    .and_then(|config| config.try_deserialize())
+    .map_err(|err| err.to_string())
+}
+
+/// Load the template from the templates directory.
+/// Falls back to the built-in templates if there is no such file.
+pub fn load_prompt_template(
+  templates_dir: &Path,
+  name: &str,
+) -> Result<PromptTemplate, String> {
+  for (extension, format) in TEMPLATE_FORMATS {
+    let path = templates_dir.join(format!("{name}.{extension}"));
+    if let Ok(content) = std::fs::read_to_string(&path) {
+      return parse_template(&content, *format).map_err(|err| {
+        format!("Invalid template \"{}\": {err}", path.display())
+      });
+    }
+  }
+
+  get_builtin_template(name).ok_or_else(|| {
+    let builtin_names: Vec<&str> =
+      PROMPT_WRAPPERS.iter().map(|(name, _)| *name).collect();
+    format!(
+      "Template \"{name}\" doesn't exist in \"{}\" \
+      and isn't a built-in one ({})",
+      templates_dir.display(),
+      builtin_names.join(", ")
+    )
+  })
+}
+
+/// Check if the text between braces is a placeholder name
+fn is_placeholder_name(name: &str) -> bool {
+  !name.is_empty()
+    && name
+      .chars()
+      .all(|char| char.is_alphanumeric() || char == '_')
+}
+
+/// Get the names of all placeholders in the order of their first occurrence
+fn get_placeholders(prompt: &str) -> Vec<&str> {
+  let mut placeholders = vec![];
+  let mut rest = prompt;
+  while let Some((_, after_open)) = rest.split_once('{') {
+    let Some((name, after_close)) = after_open.split_once('}') else {
+      break;
+    };
+    if is_placeholder_name(name) && !placeholders.contains(&name) {
+      placeholders.push(name);
+      rest = after_close;
+    } else {
+      rest = after_open;
+    }
+  }
+  placeholders
+}
+
+/// Replace the placeholders in a single pass,
+/// so placeholders within the values are kept as they are
+fn fill_placeholders(prompt: &str, values: &HashMap<&str, &str>) -> String {
+  let mut filled = String::new();
+  let mut rest = prompt;
+  while let Some((before_open, after_open)) = rest.split_once('{') {
+    filled.push_str(before_open);
+    let value = after_open
+      .split_once('}')
+      .filter(|(name, _)| is_placeholder_name(name))
+      .and_then(|(name, after_close)| Some((values.get(name)?, after_close)));
+    match value {
+      Some((value, after_close)) => {
+        filled.push_str(value);
+        rest = after_close;
+      }
+      None => {
+        filled.push('{');
+        rest = after_open;
+      }
+    }
+  }
+  filled.push_str(rest);
+  filled
+}
+
+/// Fill the placeholders of the prompt.
+/// Named variables are used first and the remaining placeholders
+/// are filled with the positional arguments in order.
+/// Extra positional arguments are appended to the last placeholder,
+/// and stdin is used for it if there are no arguments left.
+/// Returns the prompt and whether stdin was used.
+pub fn render_prompt_template(
+  template: &PromptTemplate,
+  args: &[String],
+  vars: &[(String, String)],
+  stdin: &str,
+) -> Result<(String, bool), String> {
+  let mut values: HashMap<&str, &str> = vars
+    .iter()
+    .map(|(name, value)| (name.as_str(), value.as_str()))
+    .collect();
+  let unset_placeholders: Vec<&str> = get_placeholders(&template.prompt)
+    .into_iter()
+    .filter(|name| !values.contains_key(name))
+    .collect();
+
+  let rest_args = args
+    .get(unset_placeholders.len().saturating_sub(1)..)
+    .map(|rest| rest.join(" "))
+    .unwrap_or_default();
+  let stdin = stdin.trim_end();
+  let mut uses_stdin = false;
+  for (index, name) in unset_placeholders.iter().enumerate() {
+    let is_last = index + 1 == unset_placeholders.len();
+    let value = match args.get(index) {
+      Some(_) if is_last => rest_args.as_str(),
+      Some(arg) => arg.as_str(),
+      None if is_last && !stdin.is_empty() => {
+        uses_stdin = true;
+        stdin
+      }
+      None => "",
+    };
+    if value.is_empty() {
+      return Err(format!(
+        "Missing value for {{{name}}}. \
+        Pass it as an argument, via --var, or via stdin"
+      ));
+    }
+    values.insert(name, value);
+  }
+
+  Ok((fill_placeholders(&template.prompt, &values), uses_stdin))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_load_prompt_template() {
+    let templates_dir = std::env::temp_dir()
+      .join(format!("cai_test_templates_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&templates_dir);
+    std::fs::create_dir_all(&templates_dir).unwrap();
+    std::fs::write(
+      templates_dir.join("review.yaml"),
+      "model: anthropic/claude-haiku\n\
+      system: You are a strict reviewer\n\
+      prompt: Review this {lang} code for {focus}\n",
+    )
+    .unwrap();
+    std::fs::write(templates_dir.join("short.toml"), "prompt = \"Hi {x}\"\n")
+      .unwrap();
+
+    let review = load_prompt_template(&templates_dir, "review").unwrap();
+    assert_eq!(review.model.as_deref(), Some("anthropic/claude-haiku"));
+    assert_eq!(
+      render_prompt_template(
+        &review,
+        &["bugs".to_string(), "and".to_string(), "style".to_string()],
+        &[("lang".to_string(), "Rust".to_string())],
+        "",
+      ),
+      Ok((
+        "Review this Rust code for bugs and style".to_string(),
+        false
+      ))
+    );
+    assert_eq!(
+      render_prompt_template(&review, &["Rust".to_string()], &[], ""),
+      Err(
+        "Missing value for {focus}. \
+        Pass it as an argument, via --var, or via stdin"
+          .into()
+      )
+    );
+    assert_eq!(
+      render_prompt_template(&review, &["Rust".to_string()], &[], "bugs\n"),
+      Ok(("Review this Rust code for bugs".to_string(), true))
+    );
+
+    // Placeholders in values aren't replaced again
+    assert_eq!(
+      render_prompt_template(
+        &review,
+        &["{lang}".to_string()],
+        &[("lang".to_string(), "{focus}".to_string())],
+        "",
+      ),
+      Ok(("Review this {focus} code for {lang}".to_string(), false))
+    );
+
+    // User defined templates override the built-in ones
+    let short = load_prompt_template(&templates_dir, "short").unwrap();
+    assert_eq!(short.prompt, "Hi {x}");
+
+    let value = load_prompt_template(&templates_dir, "value").unwrap();
+    assert!(value.prompt.ends_with("\n\n{input}"));
+    assert!(load_prompt_template(&templates_dir, "missing").is_err());
+
+    std::fs::remove_dir_all(&templates_dir).unwrap();
+  }
+
+  #[test]
+  fn test_wrap_prompt() {
+    assert_eq!(wrap_prompt(&[], "Explain X"), "Explain X");
+
+    let short_template = get_builtin_template("short").unwrap();
+    assert_eq!(
+      wrap_prompt(&["short".to_string()], "Explain X"),
+      short_template.prompt.replace("{input}", "Explain X")
+    );
+  }
+}
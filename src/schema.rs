@@ -0,0 +1,244 @@
+use serde_json::Value;
+
+/// Keywords which are validated or are only annotations
+const SUPPORTED_KEYWORDS: &[&str] = &[
+  "type",
+  "properties",
+  "required",
+  "additionalProperties",
+  "items",
+  "enum",
+  "anyOf",
+  "$schema",
+  "$id",
+  "$comment",
+  "title",
+  "description",
+  "default",
+  "examples",
+  "deprecated",
+];
+
+/// Check if the value has the JSON schema type
+fn has_type(value: &Value, type_name: &str) -> bool {
+  match type_name {
+    "object" => value.is_object(),
+    "array" => value.is_array(),
+    "string" => value.is_string(),
+    "number" => value.is_number(),
+    // Integral floats like `3.0` are integers as well
+    "integer" => value.as_f64().is_some_and(|number| number.fract() == 0.0),
+    "boolean" => value.is_boolean(),
+    "null" => value.is_null(),
+    _ => true,
+  }
+}
+
+/// Collect all violations of the schema at the given JSON path
+fn validate_value(
+  value: &Value,
+  schema: &Value,
+  path: &str,
+  errors: &mut Vec<String>,
+) {
+  let type_names: Vec<&str> = match &schema["type"] {
+    Value::String(type_name) => vec![type_name.as_str()],
+    Value::Array(type_names) => {
+      type_names.iter().filter_map(|name| name.as_str()).collect()
+    }
+    _ => vec![],
+  };
+  if !type_names.is_empty()
+    && !type_names
+      .iter()
+      .any(|type_name| has_type(value, type_name))
+  {
+    errors.push(format!(
+      "{path}: Expected type {}, got {value}",
+      type_names.join(" or ")
+    ));
+    return;
+  }
+
+  if let Some(variants) = schema["enum"].as_array() {
+    if !variants.contains(value) {
+      errors.push(format!("{path}: {value} is not one of the allowed values"));
+    }
+  }
+
+  if let Some(any_of) = schema["anyOf"].as_array() {
+    let matches_any = any_of.iter().any(|sub_schema| {
+      let mut sub_errors = vec![];
+      validate_value(value, sub_schema, path, &mut sub_errors);
+      sub_errors.is_empty()
+    });
+    if !matches_any {
+      errors.push(format!("{path}: Doesn't match any of the schemas"));
+    }
+  }
+
+  if let Value::Object(obj) = value {
+    if let Some(required) = schema["required"].as_array() {
+      for key in required.iter().filter_map(|key| key.as_str()) {
+        if !obj.contains_key(key) {
+          errors.push(format!("{path}: Missing required property \"{key}\""));
+        }
+      }
+    }
+    for (key, field_value) in obj {
+      match schema["properties"].get(key) {
+        Some(field_schema) => validate_value(
+          field_value,
+          field_schema,
+          &format!("{path}.{key}"),
+          errors,
+        ),
+        None if schema["additionalProperties"] == false => {
+          errors.push(format!("{path}: Unexpected property \"{key}\""));
+        }
+        None => {}
+      }
+    }
+  }
+
+  if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items"))
+  {
+    for (index, item) in items.iter().enumerate() {
+      validate_value(item, item_schema, &format!("{path}[{index}]"), errors);
+    }
+  }
+}
+
+/// Collect the unsupported keywords of the schema and its sub-schemas
+/// (e.g. `"pattern" at $.properties.name`)
+fn collect_unsupported_keywords(
+  schema: &Value,
+  path: &str,
+  keywords: &mut Vec<String>,
+) {
+  let Value::Object(schema_obj) = schema else {
+    return;
+  };
+  for (keyword, sub_schema) in schema_obj {
+    match keyword.as_str() {
+      "properties" => {
+        for (key, field_schema) in sub_schema.as_object().into_iter().flatten()
+        {
+          collect_unsupported_keywords(
+            field_schema,
+            &format!("{path}.properties.{key}"),
+            keywords,
+          );
+        }
+      }
+      "items" | "additionalProperties" => collect_unsupported_keywords(
+        sub_schema,
+        &format!("{path}.{keyword}"),
+        keywords,
+      ),
+      "anyOf" => {
+        for (index, variant) in
+          sub_schema.as_array().into_iter().flatten().enumerate()
+        {
+          collect_unsupported_keywords(
+            variant,
+            &format!("{path}.anyOf[{index}]"),
+            keywords,
+          );
+        }
+      }
+      keyword if SUPPORTED_KEYWORDS.contains(&keyword) => {}
+      keyword => keywords.push(format!("\"{keyword}\" at {path}")),
+    }
+  }
+}
+
+/// Get the keywords of the schema which `validate_json` doesn't support
+/// (e.g. `$ref`, `minimum`, or `pattern`)
+pub fn get_unsupported_keywords(schema: &Value) -> Vec<String> {
+  let mut keywords = vec![];
+  collect_unsupported_keywords(schema, "$", &mut keywords);
+  keywords
+}
+
+/// Validate the JSON text against the schema.
+/// Supports the subset of JSON schema used for structured outputs
+/// (type, properties, required, additionalProperties, items, enum, anyOf).
+pub fn validate_json(json_str: &str, schema: &Value) -> Vec<String> {
+  match serde_json::from_str::<Value>(json_str) {
+    Ok(value) => {
+      let mut errors = vec![];
+      validate_value(&value, schema, "$", &mut errors);
+      errors
+    }
+    Err(err) => vec![format!("Invalid JSON: {err}")],
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_validate_json() {
+    let schema = json!({
+      "type": "object",
+      "properties": {
+        "name": { "type": "string" },
+        "age": { "type": "integer" },
+        "mood": { "enum": ["happy", "sad"] },
+        "tags": { "type": "array", "items": { "type": "string" } },
+      },
+      "required": ["name", "age"],
+      "additionalProperties": false,
+    });
+
+    assert_eq!(
+      validate_json(r#"{"name": "Jo", "age": 3.0, "tags": ["a"]}"#, &schema),
+      Vec::<String>::new()
+    );
+    assert_eq!(
+      validate_json(
+        r#"{"age": 3.5, "mood": "ok", "tags": [1], "extra": true}"#,
+        &schema
+      ),
+      vec![
+        "$: Missing required property \"name\"",
+        "$.age: Expected type integer, got 3.5",
+        "$: Unexpected property \"extra\"",
+        "$.mood: \"ok\" is not one of the allowed values",
+        "$.tags[0]: Expected type string, got 1",
+      ]
+    );
+    assert_eq!(
+      validate_json("no json", &schema),
+      vec!["Invalid JSON: expected ident at line 1 column 2"]
+    );
+  }
+
+  #[test]
+  fn test_get_unsupported_keywords() {
+    let schema = json!({
+      "type": "object",
+      "description": "A person",
+      "properties": {
+        "name": { "type": "string", "pattern": "^[A-Z]" },
+        "age": { "type": "integer", "minimum": 0 },
+        "pet": { "$ref": "#/$defs/pet" },
+        "tags": { "type": "array", "items": { "enum": ["a"] } },
+      },
+      "$defs": { "pet": { "enum": ["cat", "dog"] } },
+    });
+    assert_eq!(
+      get_unsupported_keywords(&schema),
+      vec![
+        "\"$defs\" at $",
+        "\"minimum\" at $.properties.age",
+        "\"pattern\" at $.properties.name",
+        "\"$ref\" at $.properties.pet",
+      ]
+    );
+    assert!(get_unsupported_keywords(&json!({ "type": "object" })).is_empty());
+  }
+}
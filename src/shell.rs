@@ -0,0 +1,263 @@
+/// Programs which can delete, overwrite, or irreversibly modify data
+/// or the system. Checked for every word, so the detection errs
+/// on the side of caution.
+const DESTRUCTIVE_PROGRAMS: &[&str] = &[
+  "chmod", "chown", "cp", "crontab", "dd", "doas", "fdisk", "halt", "kill",
+  "killall", "ln", "mkfs", "mv", "parted", "pkill", "poweroff", "reboot", "rm",
+  "rmdir", "rsync", "shred", "shutdown", "su", "sudo", "tee", "truncate",
+  "unlink", "userdel", "wipefs",
+];
+
+/// Programs and flags which run arbitrary code or modify files in place.
+/// A word of the same simple command must start with the flag.
+const DESTRUCTIVE_FLAGS: &[(&str, &str)] = &[
+  ("find", "-delete"),
+  ("find", "-exec"),
+  ("node", "-e"),
+  ("perl", "-e"),
+  ("perl", "-i"),
+  ("php", "-r"),
+  ("python", "-c"),
+  ("python3", "-c"),
+  ("ruby", "-e"),
+  ("sed", "--in-place"),
+  ("sed", "-i"),
+];
+
+/// Shells whose `-c` argument is checked like a command of its own
+const SHELLS: &[&str] = &["bash", "dash", "fish", "ksh", "sh", "zsh"];
+
+/// Destructive argument combinations (matched case-insensitively)
+const DESTRUCTIVE_PATTERNS: &[&str] = &[
+  "reset --hard",
+  "git clean",
+  "push --force",
+  "push -f",
+  "branch -d",
+  "checkout --",
+  "-delete",
+  "drop table",
+  "drop database",
+  "| sh",
+  "| bash",
+  "| zsh",
+];
+
+/// Redirections which don't overwrite any files
+const HARMLESS_REDIRECTIONS: &[&str] = &["2>&1", "&>/dev/null", ">/dev/null"];
+
+/// Split the command into simple commands and those into words
+/// like a shell would: quotes and escapes are removed
+/// and `;|&()<>` and backticks separate the simple commands.
+fn split_simple_commands(command: &str) -> Vec<Vec<String>> {
+  let mut commands = vec![];
+  let mut words = vec![];
+  let mut word = String::new();
+  let mut quote: Option<char> = None;
+  let mut chars = command.chars();
+
+  while let Some(char) = chars.next() {
+    match (quote, char) {
+      (Some(open), _) if char == open => quote = None,
+      (Some('"'), '\\') | (None, '\\') => word.extend(chars.next()),
+      (Some(_), _) => word.push(char),
+      (None, '\'' | '"') => quote = Some(char),
+      (None, _) if char.is_whitespace() => {
+        if !word.is_empty() {
+          words.push(std::mem::take(&mut word));
+        }
+      }
+      (None, _) if ";|&()<>`".contains(char) => {
+        if !word.is_empty() {
+          words.push(std::mem::take(&mut word));
+        }
+        if !words.is_empty() {
+          commands.push(std::mem::take(&mut words));
+        }
+      }
+      (None, '$') => {}
+      (None, _) => word.push(char),
+    }
+  }
+  if !word.is_empty() {
+    words.push(word);
+  }
+  if !words.is_empty() {
+    commands.push(words);
+  }
+
+  // Also check the code passed to `sh -c` and `eval`
+  let mut nested_commands = vec![];
+  for words in &commands {
+    let program = get_program_name(&words[0]);
+    if program == "eval" {
+      nested_commands.extend(split_simple_commands(&words[1..].join(" ")));
+    }
+    if SHELLS.contains(&program) {
+      let code = words
+        .iter()
+        .position(|word| {
+          word.starts_with('-') && !word.starts_with("--") && word.contains('c')
+        })
+        .and_then(|index| words.get(index + 1));
+      if let Some(code) = code {
+        nested_commands.extend(split_simple_commands(code));
+      }
+    }
+  }
+  commands.extend(nested_commands);
+
+  commands
+}
+
+/// Get the name of the program without its directory (e.g. `/bin/rm` -> `rm`)
+fn get_program_name(word: &str) -> &str {
+  word.rsplit('/').next().unwrap_or(word)
+}
+
+/// Get the reason why the command might be destructive
+/// (`None` if it looks harmless)
+pub fn get_destructive_reason(command: &str) -> Option<String> {
+  let simple_commands = split_simple_commands(command);
+
+  let program = simple_commands
+    .iter()
+    .flatten()
+    .map(|word| get_program_name(word))
+    .find(|name| {
+      DESTRUCTIVE_PROGRAMS.contains(name) || name.starts_with("mkfs.")
+    });
+  if let Some(program) = program {
+    return Some(format!("uses `{program}`"));
+  }
+
+  for words in &simple_commands {
+    let program = get_program_name(&words[0]);
+    let flag = DESTRUCTIVE_FLAGS.iter().find(|(flag_program, flag)| {
+      *flag_program == program
+        && words[1..].iter().any(|word| word.starts_with(flag))
+    });
+    if let Some((program, flag)) = flag {
+      return Some(format!("uses `{program} {flag}`"));
+    }
+  }
+
+  // Check the original and the unquoted command to catch
+  // quoted patterns (e.g. `git 'reset' --hard`)
+  let lower_command =
+    command.to_lowercase().replace("> /dev/null", ">/dev/null");
+  let unquoted_commands: Vec<String> = simple_commands
+    .iter()
+    .map(|words| words.join(" ").to_lowercase())
+    .collect();
+  if let Some(pattern) = DESTRUCTIVE_PATTERNS.iter().find(|pattern| {
+    lower_command.contains(*pattern)
+      || unquoted_commands.iter().any(|cmd| cmd.contains(*pattern))
+  }) {
+    return Some(format!("contains `{pattern}`"));
+  }
+
+  let unredirected_command = HARMLESS_REDIRECTIONS
+    .iter()
+    .fold(lower_command, |cmd, redirection| {
+      cmd.replace(redirection, "")
+    });
+  unredirected_command
+    .contains('>')
+    .then(|| "writes to files via `>`".to_string())
+}
+
+/// Remove surrounding markdown code fences (e.g. ```sh) from the command
+pub fn strip_code_fence(text: &str) -> String {
+  let trimmed = text.trim();
+  match trimmed.strip_prefix("```") {
+    Some(rest) => rest
+      .split_once('\n')
+      .map_or("", |(_, code)| code)
+      .trim_end()
+      .trim_end_matches("```")
+      .trim()
+      .to_string(),
+    None => trimmed.trim_matches('`').to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_get_destructive_reason() {
+    assert_eq!(get_destructive_reason("ls -la | grep foo"), None);
+    assert_eq!(get_destructive_reason("find . -name '*.rs' 2>&1"), None);
+    assert_eq!(get_destructive_reason("cat x > /dev/null"), None);
+    assert_eq!(get_destructive_reason("bash -c 'echo hi'"), None);
+    assert_eq!(
+      get_destructive_reason("find . -name '*.tmp' -exec /bin/rm {} \\;"),
+      Some("uses `rm`".to_string())
+    );
+    assert_eq!(
+      get_destructive_reason("cd /tmp && sudo make install"),
+      Some("uses `sudo`".to_string())
+    );
+    assert_eq!(
+      get_destructive_reason("git reset --hard HEAD~1"),
+      Some("contains `reset --hard`".to_string())
+    );
+    assert_eq!(
+      get_destructive_reason("echo hi > notes.txt"),
+      Some("writes to files via `>`".to_string())
+    );
+    assert_eq!(
+      get_destructive_reason("cp new.txt old.txt"),
+      Some("uses `cp`".to_string())
+    );
+    assert_eq!(
+      get_destructive_reason("echo hi | tee notes.txt"),
+      Some("uses `tee`".to_string())
+    );
+    assert_eq!(
+      get_destructive_reason("sed -i.bak 's/a/b/' notes.txt"),
+      Some("uses `sed -i`".to_string())
+    );
+    assert_eq!(
+      get_destructive_reason("find . -name '*.tmp' '-delete'"),
+      Some("uses `find -delete`".to_string())
+    );
+    assert_eq!(
+      get_destructive_reason("python3 -c 'import os'"),
+      Some("uses `python3 -c`".to_string())
+    );
+  }
+
+  #[test]
+  fn test_get_destructive_reason_quoted() {
+    assert_eq!(
+      get_destructive_reason("bash -c 'rm -rf ~'"),
+      Some("uses `rm`".to_string())
+    );
+    assert_eq!(
+      get_destructive_reason("sh -ec \"cd /tmp; 'r'm -rf x\""),
+      Some("uses `rm`".to_string())
+    );
+    assert_eq!(
+      get_destructive_reason("eval \"\\rm notes.txt\""),
+      Some("uses `rm`".to_string())
+    );
+    assert_eq!(
+      get_destructive_reason("zsh -c \"sh -c 'sed -i s/a/b/ x'\""),
+      Some("uses `sed -i`".to_string())
+    );
+    assert_eq!(
+      get_destructive_reason("git \"reset\" --hard"),
+      Some("contains `reset --hard`".to_string())
+    );
+  }
+
+  #[test]
+  fn test_strip_code_fence() {
+    assert_eq!(strip_code_fence("```sh\nls -la\n```\n"), "ls -la");
+    assert_eq!(strip_code_fence("`ls -la`"), "ls -la");
+    assert_eq!(strip_code_fence("ls -la\n"), "ls -la");
+  }
+}
@@ -0,0 +1,200 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderName, HeaderValue};
+use sha2::{Digest, Sha256};
+
+use crate::AwsCredentials;
+
+fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+  let mut mac =
+    Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+  mac.update(data.as_bytes());
+  mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encode everything except the unreserved characters
+fn uri_encode(text: &str) -> String {
+  text
+    .bytes()
+    .map(|byte| {
+      if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+        (byte as char).to_string()
+      } else {
+        format!("%{byte:02X}")
+      }
+    })
+    .collect()
+}
+
+/// Path of the URL with each segment encoded again
+/// (required by all services except S3)
+fn get_canonical_uri(url: &reqwest::Url) -> String {
+  url
+    .path()
+    .split('/')
+    .map(uri_encode)
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+fn get_canonical_query(url: &reqwest::Url) -> String {
+  let mut pairs: Vec<(String, String)> = url
+    .query_pairs()
+    .map(|(key, value)| (uri_encode(&key), uri_encode(&value)))
+    .collect();
+  pairs.sort();
+  pairs
+    .iter()
+    .map(|(key, value)| format!("{key}={value}"))
+    .collect::<Vec<_>>()
+    .join("&")
+}
+
+/// Derive the key for the date, region, and service from the secret key
+fn get_signing_key(
+  secret_access_key: &str,
+  date: &str,
+  region: &str,
+  service: &str,
+) -> Vec<u8> {
+  let date_key =
+    hmac_sha256(format!("AWS4{secret_access_key}").as_bytes(), date);
+  let region_key = hmac_sha256(&date_key, region);
+  let service_key = hmac_sha256(&region_key, service);
+  hmac_sha256(&service_key, "aws4_request")
+}
+
+/// Sign the request with AWS Signature Version 4
+/// (https://docs.aws.amazon.com/IAM/latest/UserGuide/reference_sigv.html)
+/// by adding the `x-amz-date` and `authorization` headers
+pub fn sign_request(
+  request: &mut reqwest::Request,
+  credentials: &AwsCredentials,
+  service: &str,
+  time: DateTime<Utc>,
+) -> Result<(), String> {
+  let amz_date = time.format("%Y%m%dT%H%M%SZ").to_string();
+  let date = time.format("%Y%m%d").to_string();
+  let host = request
+    .url()
+    .host_str()
+    .ok_or("URL has no host")?
+    .to_string();
+
+  let mut headers = vec![
+    ("host".to_string(), host),
+    ("x-amz-date".to_string(), amz_date.clone()),
+  ];
+  if let Some(content_type) = request.headers().get("content-type") {
+    headers.push((
+      "content-type".to_string(),
+      content_type.to_str().unwrap_or_default().to_string(),
+    ));
+  }
+  if let Some(token) = &credentials.session_token {
+    headers.push(("x-amz-security-token".to_string(), token.clone()));
+  }
+  headers.sort();
+
+  let canonical_headers: String = headers
+    .iter()
+    .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+    .collect();
+  let signed_headers = headers
+    .iter()
+    .map(|(name, _)| name.as_str())
+    .collect::<Vec<_>>()
+    .join(";");
+  let body = request
+    .body()
+    .and_then(|body| body.as_bytes())
+    .unwrap_or_default();
+  let canonical_request = format!(
+    "{}\n{}\n{}\n{canonical_headers}\n{signed_headers}\n{}",
+    request.method(),
+    get_canonical_uri(request.url()),
+    get_canonical_query(request.url()),
+    to_hex(&Sha256::digest(body)),
+  );
+
+  let scope = format!("{date}/{}/{service}/aws4_request", credentials.region);
+  let string_to_sign = format!(
+    "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+    to_hex(&Sha256::digest(canonical_request.as_bytes()))
+  );
+  let signing_key = get_signing_key(
+    &credentials.secret_access_key,
+    &date,
+    &credentials.region,
+    service,
+  );
+  let signature = to_hex(&hmac_sha256(&signing_key, &string_to_sign));
+  let authorization = format!(
+    "AWS4-HMAC-SHA256 Credential={}/{scope}, \
+    SignedHeaders={signed_headers}, Signature={signature}",
+    credentials.access_key_id
+  );
+
+  for (name, value) in headers
+    .into_iter()
+    .filter(|(name, _)| name.starts_with("x-amz-"))
+    .chain([("authorization".to_string(), authorization)])
+  {
+    request.headers_mut().insert(
+      HeaderName::try_from(name).map_err(|err| err.to_string())?,
+      HeaderValue::try_from(value).map_err(|err| err.to_string())?,
+    );
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::TimeZone;
+
+  #[test]
+  fn test_sign_request() {
+    // Example from the AWS documentation
+    let credentials = AwsCredentials {
+      access_key_id: "AKIDEXAMPLE".to_string(),
+      secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+      session_token: None,
+      region: "us-east-1".to_string(),
+    };
+    let mut request = reqwest::Client::new()
+      .get("https://iam.amazonaws.com/?Action=ListUsers&Version=2010-05-08")
+      .header(
+        "content-type",
+        "application/x-www-form-urlencoded; charset=utf-8",
+      )
+      .build()
+      .unwrap();
+    let time = Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap();
+
+    assert_eq!(
+      to_hex(&get_signing_key(
+        &credentials.secret_access_key,
+        "20150830",
+        "us-east-1",
+        "iam"
+      )),
+      "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9"
+    );
+
+    sign_request(&mut request, &credentials, "iam", time).unwrap();
+    assert_eq!(request.headers()["x-amz-date"], "20150830T123600Z");
+    assert_eq!(
+      request.headers()["authorization"],
+      "AWS4-HMAC-SHA256 \
+      Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+      SignedHeaders=content-type;host;x-amz-date, \
+      Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+    );
+  }
+}
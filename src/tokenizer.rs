@@ -0,0 +1,150 @@
+use crate::Provider;
+
+/// Kind of a pre-tokenized piece of text
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum PieceKind {
+  Letters,
+  Digits,
+  Whitespace,
+  Other,
+}
+
+fn get_kind(char: char) -> PieceKind {
+  if char.is_alphabetic() {
+    PieceKind::Letters
+  } else if char.is_numeric() {
+    PieceKind::Digits
+  } else if char.is_whitespace() {
+    PieceKind::Whitespace
+  } else {
+    PieceKind::Other
+  }
+}
+
+/// Split text like the pre-tokenizer of OpenAI's BPE encodings:
+/// Runs of letters, digits, whitespace, and punctuation,
+/// where a single leading space is attached to the following word.
+fn split_pieces(text: &str) -> Vec<(PieceKind, String)> {
+  let mut pieces: Vec<(PieceKind, String)> = vec![];
+
+  for char in text.chars() {
+    let kind = get_kind(char);
+    match pieces.last_mut() {
+      Some((last_kind, piece)) if *last_kind == kind => piece.push(char),
+      Some((PieceKind::Whitespace, piece))
+        if piece.ends_with(' ') && kind != PieceKind::Whitespace =>
+      {
+        piece.pop();
+        if piece.is_empty() {
+          pieces.pop();
+        }
+        pieces.push((kind, format!(" {char}")));
+      }
+      _ => pieces.push((kind, char.to_string())),
+    }
+  }
+
+  pieces
+}
+
+/// Estimate the number of tokens of a single piece
+fn estimate_piece(kind: PieceKind, piece: &str) -> usize {
+  let char_count = piece.chars().count();
+  match kind {
+    // Common words are a single token, long ones are split
+    // into chunks of about 4 characters
+    PieceKind::Letters if piece.is_ascii() => {
+      if char_count <= 7 {
+        1
+      } else {
+        char_count.div_ceil(4)
+      }
+    }
+    // Non-ASCII scripts (e.g. CJK) need about one token per character
+    PieceKind::Letters => piece.trim_start().chars().count(),
+    // Numbers are split into groups of up to 3 digits
+    PieceKind::Digits => piece.trim_start().chars().count().div_ceil(3),
+    PieceKind::Whitespace => 1,
+    PieceKind::Other => char_count.div_ceil(2),
+  }
+}
+
+/// Estimate the number of tokens of a text without calling an API.
+/// OpenAI models use an approximation of their BPE tokenizer,
+/// other providers the common heuristic of 4 characters per token.
+pub fn estimate_tokens(provider: &Provider, text: &str) -> usize {
+  match provider {
+    Provider::OpenAI => split_pieces(text)
+      .iter()
+      .map(|(kind, piece)| estimate_piece(*kind, piece))
+      .sum(),
+    _ => text.chars().count().div_ceil(4),
+  }
+}
+
+/// Rough input price in USD per million tokens for well known models
+pub fn get_input_price(provider: &Provider, model: &str) -> Option<f64> {
+  match provider {
+    Provider::OpenAI => [
+      ("gpt-4o-mini", 0.15),
+      ("gpt-4o", 2.5),
+      ("gpt-4.1-nano", 0.1),
+      ("gpt-4.1-mini", 0.4),
+      ("gpt-4.1", 2.0),
+      ("o1-mini", 1.1),
+      ("o1", 15.0),
+      ("o3-mini", 1.1),
+      ("o3", 2.0),
+      ("o4-mini", 1.1),
+    ]
+    .iter()
+    .find(|(prefix, _)| model.starts_with(prefix))
+    .map(|(_, price)| *price),
+    Provider::Anthropic => {
+      if model.starts_with("claude-3-haiku") {
+        Some(0.25)
+      } else if model.contains("haiku") {
+        Some(1.0)
+      } else if model.contains("sonnet") {
+        Some(3.0)
+      } else if model.contains("opus") {
+        Some(15.0)
+      } else {
+        None
+      }
+    }
+    Provider::Groq
+    | Provider::Together
+    | Provider::Fireworks
+    | Provider::Bedrock => None,
+    Provider::Llamafile | Provider::Ollama => Some(0.0),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_split_pieces() {
+    assert_eq!(
+      split_pieces("Hello, world 2024!\n"),
+      vec![
+        (PieceKind::Letters, "Hello".to_string()),
+        (PieceKind::Other, ",".to_string()),
+        (PieceKind::Letters, " world".to_string()),
+        (PieceKind::Digits, " 2024".to_string()),
+        (PieceKind::Other, "!".to_string()),
+        (PieceKind::Whitespace, "\n".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_estimate_tokens() {
+    let text = "The quick brown fox jumps over the lazy dog.";
+    assert_eq!(estimate_tokens(&Provider::OpenAI, text), 10);
+    assert_eq!(estimate_tokens(&Provider::Anthropic, text), 11);
+    assert_eq!(estimate_tokens(&Provider::OpenAI, ""), 0);
+  }
+}
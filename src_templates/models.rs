@@ -113,3 +113,87 @@ macro_rules! anthropic_models_pretty {
     concat!($prefix, "\n", "{anthropic_models_pretty}")
   };
 }
+
+//////////////////////////////////////////////////
+//////////////////// TOGETHER ////////////////////
+
+// Pretty-printed string representation of the hashmap
+pub const TOGETHER_MODEL_MAPPING: &[(&str, &str)] = &[
+  // This will be replaced by build.rs:
+  // {together_model_hashmap}
+];
+
+fn get_together_model(model_id: &str) -> &str {
+  TOGETHER_MODEL_MAPPING
+    .iter()
+    .find(|(key, _)| key == &model_id)
+    .map_or(model_id, |(_, value)| *value)
+}
+
+pub const TOGETHER_MODELS_PRETTY: &str =
+  // This will be replaced by build.rs:
+  "{together_models_pretty}";
+
+#[macro_export]
+macro_rules! together_models_pretty {
+  ($prefix: expr) => {
+    // This will be replaced by build.rs
+    concat!($prefix, "\n", "{together_models_pretty}")
+  };
+}
+
+//////////////////////////////////////////////////
+/////////////////// FIREWORKS ////////////////////
+
+// Pretty-printed string representation of the hashmap
+pub const FIREWORKS_MODEL_MAPPING: &[(&str, &str)] = &[
+  // This will be replaced by build.rs:
+  // {fireworks_model_hashmap}
+];
+
+fn get_fireworks_model(model_id: &str) -> &str {
+  FIREWORKS_MODEL_MAPPING
+    .iter()
+    .find(|(key, _)| key == &model_id)
+    .map_or(model_id, |(_, value)| *value)
+}
+
+pub const FIREWORKS_MODELS_PRETTY: &str =
+  // This will be replaced by build.rs:
+  "{fireworks_models_pretty}";
+
+#[macro_export]
+macro_rules! fireworks_models_pretty {
+  ($prefix: expr) => {
+    // This will be replaced by build.rs
+    concat!($prefix, "\n", "{fireworks_models_pretty}")
+  };
+}
+
+//////////////////////////////////////////////////
+//////////////////// BEDROCK /////////////////////
+
+// Pretty-printed string representation of the hashmap
+pub const BEDROCK_MODEL_MAPPING: &[(&str, &str)] = &[
+  // This will be replaced by build.rs:
+  // {bedrock_model_hashmap}
+];
+
+fn get_bedrock_model(model_id: &str) -> &str {
+  BEDROCK_MODEL_MAPPING
+    .iter()
+    .find(|(key, _)| key == &model_id)
+    .map_or(model_id, |(_, value)| *value)
+}
+
+pub const BEDROCK_MODELS_PRETTY: &str =
+  // This will be replaced by build.rs:
+  "{bedrock_models_pretty}";
+
+#[macro_export]
+macro_rules! bedrock_models_pretty {
+  ($prefix: expr) => {
+    // This will be replaced by build.rs
+    concat!($prefix, "\n", "{bedrock_models_pretty}")
+  };
+}
@@ -8,11 +8,10 @@ mod tests {
     let mut cmd = Command::cargo_bin("cai").unwrap();
 
     cmd
-      .args(&["Which year did the Titanic sink?", "(Just the number)"])
+      .args(["Which year did the Titanic sink?", "(Just the number)"])
       .assert()
       .success()
-      .stderr("")
-      .stdout(predicate::str::contains("Groq llama3"))
+      .stderr(predicate::str::contains("Groq llama3"))
       .stdout(predicate::str::contains("1912"));
   }
   #[test]
@@ -20,35 +19,33 @@ mod tests {
     let mut cmd = Command::cargo_bin("cai").unwrap();
 
     cmd
-      .args(&[
+      .args([
         "ollama", "llama3", "Which", "year", "did", "the", "Titanic", "sink?",
         "(Just", "the", "number)",
       ])
       .assert()
       .success()
-      .stderr("")
-      .stdout(predicate::str::contains("Ollama"))
+      .stderr(predicate::str::contains("Ollama"))
       .stdout(predicate::str::contains("1912"));
   }
   #[test]
   fn test_ollama_shortcut_cai_execution() {
     let mut cmd = Command::cargo_bin("cai").unwrap();
     cmd
-      .args(&[
+      .args([
         "ol", "ll", "Which", "year", "did", "the", "Titanic", "sink?", "(Just",
         "the", "number)",
       ])
       .assert()
       .success()
-      .stderr("")
-      .stdout(predicate::str::contains("Ollama"))
+      .stderr(predicate::str::contains("Ollama"))
       .stdout(predicate::str::contains("1912"));
   }
   #[test]
   fn test_ollama_fails_cai_execution() {
     let mut cmd = Command::cargo_bin("cai").unwrap();
     cmd
-      .args(&["ollama", "xxx", "prompt"])
+      .args(["ollama", "xxx", "prompt"])
       .assert()
       .failure()
       .stderr(predicate::str::contains("Ollama"))